@@ -1,15 +1,26 @@
 use std::{
-    collections::HashSet,
-    env, fs,
-    io::{self, Write},
-    path::PathBuf,
+    collections::{HashMap, HashSet},
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
     process,
+    time::Duration,
 };
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
 
 mod ugit;
-use ugit::{base, data, diff, DEFAULT_REPO};
+use ugit::{
+    base::{self, MergeOutcome, ResetMode, WalkOptions, WalkOrder},
+    blame, config, data,
+    diff::{self, ConflictKind, DiffAlgorithm, DiffOptions, WordDiff},
+    ignore::Ignore,
+    index,
+    merge::{ConflictStyle, MergeFavor, MergeOptions},
+    Repository,
+};
 
 fn main() {
     const ABOUT_INIT: &str = "Create a new ugit repository";
@@ -18,18 +29,46 @@ fn main() {
     const ABOUT_WRITE_TREE: &str = "Write the current directory to the object store";
     const ABOUT_READ_TREE: &str =
         "Replace the contents of the current directory with the tree with the specified OID";
-    const ABOUT_COMMIT: &str = "Commit the current directory";
-    const ABOUT_LOG: &str = "Print the commit history, optionally beginning at the specified OID";
+    const ABOUT_ADD: &str = "Add the contents of the specified files to the index";
+    const ABOUT_RM: &str = "Remove the specified files from the index and the working directory";
+    const ABOUT_COMMIT: &str = "Commit the contents of the index";
+    const ABOUT_LOG: &str = "Print the history of the specified commits, by default HEAD";
     const ABOUT_CHECKOUT: &str =
         "Restore the working tree to that of the commit with the specified OID";
     const ABOUT_TAG: &str = "Create a reference with the specified name";
     const ABOUT_BRANCH: &str = "List the available branches, or create a new one";
-    const ABOUT_STATUS: &str = "Print the currently checked out branch";
+    const ABOUT_STATUS: &str =
+        "Print the currently checked out branch and the state of the index and working directory";
+    const ABOUT_RESET: &str =
+        "Move HEAD to the specified commit, or restore the index entries for the specified paths";
+    const ABOUT_CHECK_IGNORE: &str = "Print which of the specified paths are ignored";
+    const ABOUT_REFLOG: &str = "Print the changes made to a ref, newest first";
+    const ABOUT_REV_PARSE: &str = "Print the OIDs that the specified revisions resolve to";
+    const ABOUT_REV_LIST: &str =
+        "Print the OIDs of the commits selected by the specified revisions";
+    const ABOUT_BLAME: &str = "Print the commit that last changed each line of a file";
+    const ABOUT_GC: &str = "Delete objects that can't be reached from any ref or reflog";
 
     let matches = Command::new(clap::crate_name!())
         .about(clap::crate_description!())
         .author(clap::crate_authors!())
         .version(clap::crate_version!())
+        .arg(
+            Arg::new("git-dir")
+                .long("git-dir")
+                .global(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help(
+                    "Use the specified ugit directory instead of searching for one [env: UGIT_DIR]",
+                ),
+        )
+        .arg(
+            Arg::new("work-tree")
+                .long("work-tree")
+                .global(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Use the specified directory as the working tree [env: UGIT_WORK_TREE]"),
+        )
         .subcommand(Command::new("init").about(ABOUT_INIT))
         .subcommand(
             Command::new("hash-object")
@@ -45,26 +84,109 @@ fn main() {
         .subcommand(
             Command::new("read-tree")
                 .about(ABOUT_READ_TREE)
-                .arg(Arg::new("tree_oid").required(true)),
+                .arg(Arg::new("tree_oid").required(true))
+                .arg(force_arg()),
+        )
+        .subcommand(
+            Command::new("add")
+                .about(ABOUT_ADD)
+                .arg(Arg::new("paths").required(true).num_args(1..)),
+        )
+        .subcommand(
+            Command::new("rm")
+                .about(ABOUT_RM)
+                .arg(
+                    Arg::new("cached")
+                        .long("cached")
+                        .help("Only remove the files from the index")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(Arg::new("paths").required(true).num_args(1..)),
         )
         .subcommand(
             Command::new("commit").about(ABOUT_COMMIT).arg(
                 Arg::new("message")
                     .short('m')
                     .long("message")
-                    .required(true),
+                    .help("The commit message, which defaults to MERGE_MSG when merging"),
             ),
         )
         .subcommand(
             Command::new("log")
                 .about(ABOUT_LOG)
-                .arg(Arg::new("commit_oid").default_value("@")),
+                .arg(revisions_arg())
+                .args(walk_order_args())
+                .group(ArgGroup::new("order").args(["topo-order", "date-order", "author-date-order"]))
+                .arg(
+                    Arg::new("oneline")
+                        .long("oneline")
+                        .help("Show each commit on a single line")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("graph")
+                        .long("graph")
+                        .help("Draw the history as a graph alongside the commits")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("stat")
+                        .long("stat")
+                        .help("Summarise the files each commit changed")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("patch")
+                        .short('p')
+                        .long("patch")
+                        .help("Show the changes each commit made")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(diff_option_args())
+                .arg(
+                    Arg::new("grep")
+                        .long("grep")
+                        .value_name("text")
+                        .help("Only show commits whose message contains the text"),
+                )
+                .arg(
+                    Arg::new("author")
+                        .long("author")
+                        .value_name("text")
+                        .help("Only show commits whose author's name or email contains the text"),
+                )
+                .arg(
+                    Arg::new("max-count")
+                        .short('n')
+                        .long("max-count")
+                        .value_name("n")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Show at most n commits"),
+                )
+                .arg(
+                    Arg::new("follow")
+                        .long("follow")
+                        .help("Follow the history of a single path back through renames")
+                        .requires("paths")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("paths")
+                        .help("Only show commits that change these paths")
+                        .num_args(1..)
+                        .last(true),
+                ),
+        )
+        .subcommand(
+            Command::new("show")
+                .arg(Arg::new("commit_oid").default_value("@"))
+                .args(diff_option_args()),
         )
-        .subcommand(Command::new("show").arg(Arg::new("commit_oid").default_value("@")))
         .subcommand(
             Command::new("checkout")
                 .about(ABOUT_CHECKOUT)
-                .arg(Arg::new("commit").required(true)),
+                .arg(Arg::new("commit").required(true))
+                .arg(force_arg()),
         )
         .subcommand(
             Command::new("tag")
@@ -72,7 +194,12 @@ fn main() {
                 .arg(Arg::new("name").required(true))
                 .arg(Arg::new("oid").default_value("@")),
         )
-        .subcommand(Command::new("k"))
+        .subcommand(
+            Command::new("k")
+                .arg(revisions_arg())
+                .args(walk_order_args())
+                .group(ArgGroup::new("order").args(["topo-order", "date-order", "author-date-order"])),
+        )
         .subcommand(
             Command::new("branch")
                 .about(ABOUT_BRANCH)
@@ -80,14 +207,253 @@ fn main() {
                 .arg(Arg::new("start_point").default_value("@")),
         )
         .subcommand(Command::new("status").about(ABOUT_STATUS))
-        .subcommand(Command::new("reset").arg(Arg::new("oid").required(true)))
-        .subcommand(Command::new("diff").arg(Arg::new("commit").default_value("@")))
-        .subcommand(Command::new("merge").arg(Arg::new("commit").default_value("@")))
+        .subcommand(
+            Command::new("check-ignore")
+                .about(ABOUT_CHECK_IGNORE)
+                .arg(
+                    Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .help("Print the rule that matched each path")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("non-matching")
+                        .short('n')
+                        .long("non-matching")
+                        .help("With --verbose, also print paths that didn't match any rule")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(Arg::new("paths").required(true).num_args(1..)),
+        )
+        .subcommand(
+            Command::new("reset")
+                .about(ABOUT_RESET)
+                .arg(Arg::new("oid").default_value("@"))
+                .arg(
+                    Arg::new("soft")
+                        .long("soft")
+                        .help("Only move HEAD, leaving the index and working tree alone")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("mixed")
+                        .long("mixed")
+                        .help("Move HEAD and reset the index, leaving the working tree alone (the default)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("hard")
+                        .long("hard")
+                        .help("Move HEAD and reset the index and working tree")
+                        .action(ArgAction::SetTrue),
+                )
+                .group(ArgGroup::new("mode").args(["soft", "mixed", "hard"]))
+                .arg(force_arg().requires("hard"))
+                .arg(
+                    Arg::new("paths")
+                        .help("Only reset the index entries for these paths")
+                        .num_args(1..)
+                        .last(true)
+                        .conflicts_with_all(["soft", "hard"]),
+                ),
+        )
+        .subcommand(
+            Command::new("diff")
+                .arg(
+                    Arg::new("revisions")
+                        .num_args(0..=2)
+                        .help("The commits to compare, as A B or A..B, or one to compare with the working tree [default: HEAD]"),
+                )
+                .arg(
+                    Arg::new("cached")
+                        .long("cached")
+                        .visible_alias("staged")
+                        .help("Compare the index instead of the working tree")
+                        .action(ArgAction::SetTrue),
+                )
+                .args(diff_option_args())
+                .arg(
+                    Arg::new("stat")
+                        .long("stat")
+                        .help("Summarise the changes to each file")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("numstat")
+                        .long("numstat")
+                        .help("List the number of lines added and removed in each file")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("name-only")
+                        .long("name-only")
+                        .help("Only list the names of the changed files")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("name-status")
+                        .long("name-status")
+                        .help("List the names of the changed files and how each changed")
+                        .action(ArgAction::SetTrue),
+                )
+                .group(ArgGroup::new("format").args(["stat", "numstat", "name-only", "name-status"]))
+                .arg(
+                    Arg::new("exit-code")
+                        .long("exit-code")
+                        .help("Exit with a status of 1 if there are differences")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("paths")
+                        .help("Only compare these paths")
+                        .num_args(1..)
+                        .last(true),
+                ),
+        )
+        .subcommand(
+            Command::new("merge")
+                .arg(Arg::new("commit").default_value("@"))
+                .arg(
+                    Arg::new("abort")
+                        .long("abort")
+                        .help("Abandon the merge in progress and restore the paths it changed")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["commit", "continue"]),
+                )
+                .arg(
+                    Arg::new("continue")
+                        .long("continue")
+                        .help("Commit the merge in progress once its conflicts are resolved")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("commit"),
+                )
+                .arg(force_arg())
+                .arg(
+                    Arg::new("conflict")
+                        .long("conflict")
+                        .help("How to show conflicts, overriding merge.conflictStyle")
+                        .value_parser(["merge", "diff3", "zdiff3"]),
+                )
+                .arg(
+                    Arg::new("strategy-option")
+                        .short('X')
+                        .long("strategy-option")
+                        .help("Resolve conflicting changes in favour of one side, or keep both")
+                        .value_parser(["ours", "theirs", "union"]),
+                ),
+        )
         .subcommand(
             Command::new("merge-base")
                 .arg(Arg::new("commit1").required(true))
                 .arg(Arg::new("commit2").required(true)),
         )
+        .subcommand(
+            Command::new("reflog")
+                .about(ABOUT_REFLOG)
+                .arg(Arg::new("ref").default_value("HEAD")),
+        )
+        .subcommand(
+            Command::new("rev-parse")
+                .about(ABOUT_REV_PARSE)
+                .arg(revisions_arg())
+                .arg(
+                    Arg::new("abbrev-ref")
+                        .long("abbrev-ref")
+                        .help("Print the short name of the ref each revision refers to")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("short")
+                        .long("short")
+                        .value_name("length")
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("7")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Print the shortest unique abbreviation of each OID"),
+                )
+                .arg(
+                    Arg::new("verify")
+                        .long("verify")
+                        .help("Check that exactly one revision was given and names an object")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("show-toplevel")
+                        .long("show-toplevel")
+                        .help("Print the path of the root of the working tree")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("rev-list")
+                .about(ABOUT_REV_LIST)
+                .arg(revisions_arg().required(true))
+                .args(walk_order_args())
+                .group(ArgGroup::new("order").args(["topo-order", "date-order", "author-date-order"]))
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .help("Print the number of commits instead of listing them")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("max-count")
+                        .short('n')
+                        .long("max-count")
+                        .value_name("n")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("List at most n commits"),
+                )
+                .arg(
+                    Arg::new("first-parent")
+                        .long("first-parent")
+                        .help("Only follow the first parent of merge commits")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("reverse")
+                        .long("reverse")
+                        .help("List the commits oldest first")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("objects")
+                        .long("objects")
+                        .help("Also list the trees and blobs the commits reach")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("blame")
+                .about(ABOUT_BLAME)
+                .arg(Arg::new("path").required(true))
+                .arg(Arg::new("revision").default_value("HEAD"))
+                .arg(
+                    Arg::new("lines")
+                        .short('L')
+                        .value_name("start,end")
+                        .value_parser(parse_line_range)
+                        .help("Only blame the lines from start to end, or start,+count"),
+                )
+                .arg(
+                    Arg::new("porcelain")
+                        .long("porcelain")
+                        .help("Print the results in a format designed for scripts")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("gc").about(ABOUT_GC).arg(
+                Arg::new("prune")
+                    .long("prune")
+                    .value_name("days")
+                    .value_parser(clap::value_parser!(u64))
+                    .default_value("14")
+                    .help("Only delete unreachable objects older than this many days"),
+            ),
+        )
         .subcommand(Command::new("fetch").arg(Arg::new("remote").required(true)))
         .subcommand(
             Command::new("push")
@@ -97,106 +463,131 @@ fn main() {
         .arg_required_else_help(true)
         .get_matches();
 
+    if let Err(err) = run(&matches) {
+        let code = exit_code(&err);
+        let prefix = if code == 1 { "error" } else { "fatal" };
+        eprintln!("{}: {}", prefix, err);
+        process::exit(code);
+    }
+}
+
+fn run(matches: &ArgMatches) -> ugit::Result<()> {
+    let cwd = fs::canonicalize(env::current_dir()?)?;
     if matches.subcommand_matches("init").is_some() {
-        base::init();
-        process::exit(0);
+        init_repository(matches, &cwd)?;
+        return Ok(());
     }
 
     // All of the subsequent subcommands need to be run within an existing ugit repository, so exit
-    // with an error if the current working directory isn't one.
-    exit_if_not_repository();
-
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+    // with an error if one can't be found.
+    let repo = &open_repository(matches, &cwd)?;
+    // The location of the current directory within the working tree, used to interpret and display
+    // paths relative to it.
+    let prefix = repo.work_tree_relative_path(&cwd).unwrap_or_default();
 
     if let Some(matches) = matches.subcommand_matches("hash-object") {
         let filename = matches.get_one::<String>("filename").unwrap();
-        let contents = fs::read(filename).expect("Failed to read file contents");
-        let object_hash = data::hash_object(&contents, "blob");
-        io::stdout()
-            .write_all(object_hash.as_bytes())
-            .expect("Failed to output object ID");
-        io::stdout()
-            .write_all(b"\n")
-            .expect("Failed to output newline");
-        process::exit(0);
+        let contents = fs::read(filename)?;
+        let object_hash = data::hash_object(repo, &contents, "blob")?;
+        io::stdout().write_all(object_hash.as_bytes())?;
+        io::stdout().write_all(b"\n")?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("cat-file") {
-        if let Some(oid) = base::get_oid(matches.get_one::<String>("oid").unwrap()) {
-            let contents = data::get_object(default_repo, &oid, None);
-            io::stdout()
-                .write_all(&contents)
-                .expect("Failed to output file data");
-        }
-        process::exit(0);
+        let oid = base::get_oid(repo, matches.get_one::<String>("oid").unwrap())?;
+        let contents = data::get_object(repo, &oid, None)?;
+        io::stdout().write_all(&contents)?;
+        return Ok(());
     }
 
     if matches.subcommand_matches("write-tree").is_some() {
-        let cwd = env::current_dir().expect("Failed to get current working directory");
-        base::write_tree(cwd.as_path());
-        process::exit(0);
+        base::write_tree(repo, &repo.work_tree)?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("read-tree") {
-        if let Some(tree_oid) = base::get_oid(matches.get_one::<String>("tree_oid").unwrap()) {
-            base::read_tree(default_repo, &tree_oid);
-        }
-        process::exit(0);
+        let tree_oid = base::get_oid(repo, matches.get_one::<String>("tree_oid").unwrap())?;
+        base::read_tree(repo, &tree_oid, matches.get_flag("force"))?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("add") {
+        let paths = get_pathspecs(repo, &cwd, matches)?;
+        base::add(repo, &paths)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("rm") {
+        let paths = get_pathspecs(repo, &cwd, matches)?;
+        base::remove(repo, &paths, matches.get_flag("cached"))?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("commit") {
-        let message = matches.get_one::<String>("message").unwrap();
-        base::commit(message);
-        process::exit(0);
+        let message = match matches.get_one::<String>("message") {
+            Some(message) => message.to_owned(),
+            None => base::get_merge_message(repo)?.ok_or(ugit::Error::MissingCommitMessage)?,
+        };
+        base::commit(repo, &message)?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("log") {
-        if let Some(commit_oid) = base::get_oid(matches.get_one::<String>("commit_oid").unwrap()) {
-            log(&commit_oid);
+        let mut revisions = get_revisions(matches);
+        if revisions.is_empty() {
+            revisions.push("HEAD");
+        }
+        let (include, exclude) = base::parse_revisions(repo, &revisions)?;
+        let mut options = get_walk_options(matches);
+        options.paths = get_pathspecs(repo, &cwd, matches)?;
+        if matches.get_flag("follow") && options.paths.len() != 1 {
+            return Err(ugit::Error::NeedSinglePath);
         }
-        process::exit(0);
+        options.grep = matches.get_one::<String>("grep").cloned();
+        options.author = matches.get_one::<String>("author").cloned();
+        log(repo, &prefix, matches, &include, &exclude, &options)?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("show") {
-        let oid = base::get_oid(matches.get_one::<String>("commit_oid").unwrap());
-        show(oid.as_deref());
-        process::exit(0);
+        let oid = base::get_oid(repo, matches.get_one::<String>("commit_oid").unwrap())?;
+        show(repo, &oid, &get_diff_options(matches))?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("checkout") {
         let commit = matches.get_one::<String>("commit").unwrap();
-        base::checkout(&commit);
-        process::exit(0);
+        base::checkout(repo, commit, matches.get_flag("force"))?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("tag") {
         let name = matches.get_one::<String>("name").unwrap();
-        if let Some(oid) = base::get_oid(matches.get_one::<String>("oid").unwrap()) {
-            base::create_tag(name, &oid);
-        }
-        process::exit(0);
+        let oid = base::get_oid(repo, matches.get_one::<String>("oid").unwrap())?;
+        base::create_tag(repo, name, &oid)?;
+        return Ok(());
     }
 
-    if matches.subcommand_matches("k").is_some() {
-        k();
-        process::exit(0);
+    if let Some(matches) = matches.subcommand_matches("k") {
+        let revisions = get_revisions(matches);
+        k(repo, &revisions, &get_walk_options(matches))?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("branch") {
         if let Some(name) = matches.get_one::<String>("name") {
-            if let Some(start_point) =
-                base::get_oid(matches.get_one::<String>("start_point").unwrap())
-            {
-                base::create_branch(name, &start_point);
-                println!(
-                    "Branch {} created at {}",
-                    name,
-                    shorten_oid(start_point.as_str())
-                );
-            }
+            let start_point =
+                base::get_oid(repo, matches.get_one::<String>("start_point").unwrap())?;
+            base::create_branch(repo, name, &start_point)?;
+            println!(
+                "Branch {} created at {}",
+                name,
+                shorten_oid(start_point.as_str())
+            );
         } else {
-            let current = base::get_branch_name();
-            for branch in base::get_branch_names() {
+            let current = base::get_branch_name(repo)?;
+            for branch in base::get_branch_names(repo)? {
                 if let Some(current) = &current {
                     if branch == *current {
                         println!("* {}", branch);
@@ -206,129 +597,655 @@ fn main() {
                 println!("{}", branch);
             }
         }
-        process::exit(0);
+        return Ok(());
     }
 
     if matches.subcommand_matches("status").is_some() {
-        status();
-        process::exit(0);
+        status(repo, &prefix)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("check-ignore") {
+        let paths = get_pathspecs(repo, &cwd, matches)?;
+        let verbose = matches.get_flag("verbose");
+        let non_matching = verbose && matches.get_flag("non-matching");
+        // Like git, exit with a status of 1 when none of the paths are ignored.
+        if !check_ignore(repo, &prefix, &paths, verbose, non_matching)? {
+            process::exit(1);
+        }
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("reset") {
-        if let Some(oid) = base::get_oid(matches.get_one::<String>("oid").unwrap()) {
-            base::reset(&oid);
+        let oid = base::get_oid(repo, matches.get_one::<String>("oid").unwrap())?;
+        let paths = get_pathspecs(repo, &cwd, matches)?;
+        if !paths.is_empty() {
+            base::reset_paths(repo, &oid, &paths)?;
+        } else {
+            let mode = if matches.get_flag("soft") {
+                ResetMode::Soft
+            } else if matches.get_flag("hard") {
+                ResetMode::Hard
+            } else {
+                ResetMode::Mixed
+            };
+            base::reset(repo, &oid, mode, matches.get_flag("force"))?;
         }
-        process::exit(0);
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("diff") {
-        let oid =
-            base::get_oid(matches.get_one::<String>("commit").unwrap()).expect("Failed to get OID");
-        diff(&oid);
-        process::exit(0);
+        let paths = get_pathspecs(repo, &cwd, matches)?;
+        let changed = diff(repo, &prefix, matches, &paths)?;
+        if changed && matches.get_flag("exit-code") {
+            process::exit(1);
+        }
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("merge") {
-        let oid =
-            base::get_oid(matches.get_one::<String>("commit").unwrap()).expect("Failed to get OID");
-        merge(&oid);
-        process::exit(0);
+        if matches.get_flag("abort") {
+            base::merge_abort(repo)?;
+        } else if matches.get_flag("continue") {
+            base::merge_continue(repo)?;
+        } else {
+            let name = matches.get_one::<String>("commit").unwrap();
+            let options = get_merge_options(repo, matches)?;
+            merge(repo, name, &options, matches.get_flag("force"))?;
+        }
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("merge-base") {
-        let commit1 = base::get_oid(matches.get_one::<String>("commit1").unwrap())
-            .expect("Failed to get OID");
-        let commit2 = base::get_oid(matches.get_one::<String>("commit2").unwrap())
-            .expect("Failed to get OID");
+        let commit1 = base::get_oid(repo, matches.get_one::<String>("commit1").unwrap())?;
+        let commit2 = base::get_oid(repo, matches.get_one::<String>("commit2").unwrap())?;
 
-        let common_ancestor = base::get_merge_base(&commit1, &commit2);
+        let common_ancestor = base::get_merge_base(repo, &commit1, &commit2)?;
 
         println!("{}", common_ancestor.unwrap_or_else(|| "none".to_owned()));
 
-        process::exit(0);
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("reflog") {
+        let name = matches.get_one::<String>("ref").unwrap();
+        let entries = base::get_reflog(repo, name)?;
+        for (i, entry) in entries.iter().rev().enumerate() {
+            println!(
+                "{} {}@{{{}}}: {}",
+                shorten_oid(&entry.new),
+                name,
+                i,
+                entry.message
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("rev-parse") {
+        rev_parse(repo, matches)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("rev-list") {
+        rev_list(repo, matches)?;
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("blame") {
+        let path = matches.get_one::<String>("path").unwrap();
+        let path = repo.work_tree_relative_path(&cwd.join(path))?;
+        let oid = base::get_oid(repo, matches.get_one::<String>("revision").unwrap())?;
+        let range = matches.get_one::<(usize, usize)>("lines").copied();
+        let lines = blame::blame(repo, &path, &oid, range)?;
+        let start = range.map_or(1, |(start, _)| start);
+        if matches.get_flag("porcelain") {
+            print_blame_porcelain(repo, &path, &lines, start)?;
+        } else {
+            print_blame(repo, &lines, start)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("gc") {
+        let days = *matches.get_one::<u64>("prune").unwrap();
+        // A grace period too long to represent keeps everything, as one that long would.
+        base::gc(repo, Duration::from_secs(days.saturating_mul(24 * 60 * 60)))?;
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("fetch") {
         let remote = matches.get_one::<String>("remote").unwrap();
 
-        let mut remote_path = PathBuf::from(remote);
-        remote_path.push(DEFAULT_REPO);
-        ugit::remote::fetch(&remote_path);
+        let remote = Repository::open(&PathBuf::from(remote))?;
+        ugit::remote::fetch(repo, &remote)?;
 
-        process::exit(0);
+        return Ok(());
     }
 
     if let Some(matches) = matches.subcommand_matches("push") {
         let remote = matches.get_one::<String>("remote").unwrap();
         let branch = matches.get_one::<String>("branch").unwrap();
 
-        let mut remote_path = PathBuf::from(remote);
-        remote_path.push(DEFAULT_REPO);
+        let remote = Repository::open(&PathBuf::from(remote))?;
+
+        ugit::remote::push(repo, &remote, branch)?;
+
+        return Ok(());
+    }
+
+    Ok(())
+}
+
+/// The revisions selecting the commits to list, e.g. "A..B" or "^A B".
+fn revisions_arg() -> Arg {
+    Arg::new("revisions")
+        .num_args(0..)
+        .help("The commits to include, with ^ excluding a commit's history, e.g. A..B or ^A B")
+}
+
+/// The options controlling the order in which commits are listed.
+fn walk_order_args() -> [Arg; 3] {
+    [
+        Arg::new("topo-order")
+            .long("topo-order")
+            .help("Show each line of history together, children before parents")
+            .action(ArgAction::SetTrue),
+        Arg::new("date-order")
+            .long("date-order")
+            .help("Show newer commits first, children before parents (the default)")
+            .action(ArgAction::SetTrue),
+        Arg::new("author-date-order")
+            .long("author-date-order")
+            .help("Show commits by author date, children before parents")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn get_walk_options(matches: &ArgMatches) -> WalkOptions {
+    let mut options = WalkOptions::default();
+    if matches.get_flag("topo-order") {
+        options.order = WalkOrder::Topo;
+    } else if matches.get_flag("author-date-order") {
+        options.order = WalkOrder::AuthorDate;
+    }
+    // Only some subcommands can follow just the first parent.
+    if matches!(matches.try_get_one::<bool>("first-parent"), Ok(Some(true))) {
+        options.first_parent = true;
+    }
+    options
+}
+
+/// Return the revisions passed to a subcommand.
+fn get_revisions(matches: &ArgMatches) -> Vec<&str> {
+    matches
+        .get_many::<String>("revisions")
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect()
+}
 
-        ugit::remote::push(&remote_path, branch);
+/// The options shared by the subcommands that print diffs.
+fn diff_option_args() -> [Arg; 7] {
+    [
+        Arg::new("unified")
+            .short('U')
+            .long("unified")
+            .value_name("n")
+            .value_parser(clap::value_parser!(usize))
+            .help("Show n lines of context around each change [default: 3]"),
+        Arg::new("diff-algorithm")
+            .long("diff-algorithm")
+            .value_parser(["myers", "patience", "histogram"])
+            .help("The algorithm used to find the changed lines [default: myers]"),
+        Arg::new("find-renames")
+            .short('M')
+            .long("find-renames")
+            .value_name("n")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("50%")
+            .value_parser(parse_similarity)
+            .help("Show files at least n similar as renames, e.g. 75% or 0.75 [default: 50%]"),
+        Arg::new("find-copies")
+            .short('C')
+            .long("find-copies")
+            .help("Also show files copied from files changed alongside them")
+            .action(ArgAction::SetTrue),
+        Arg::new("color")
+            .long("color")
+            .value_name("when")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("always")
+            .value_parser(["auto", "always", "never"])
+            .help("Colour the output, with auto only colouring it for a terminal [default: auto]"),
+        Arg::new("word-diff")
+            .long("word-diff")
+            .value_name("mode")
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("plain")
+            .value_parser(["plain", "color", "porcelain", "none"])
+            .help("Show the changes within lines word by word"),
+        Arg::new("color-moved")
+            .long("color-moved")
+            .help("Colour blocks of lines moved within the diff differently")
+            .action(ArgAction::SetTrue),
+    ]
+}
 
-        process::exit(0);
+/// Parse a similarity threshold like git, either as a percentage ("75%") or as the digits of a
+/// fraction ("75" or "0.75" both mean 75%).
+fn parse_similarity(value: &str) -> Result<usize, String> {
+    let invalid = || format!("invalid similarity '{}'", value);
+    let similarity = match value.strip_suffix('%') {
+        Some(percentage) => percentage.parse::<f64>().map_err(|_| invalid())?,
+        None => {
+            let digits = value.strip_prefix("0.").unwrap_or(value);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            format!("0.{}", digits)
+                .parse::<f64>()
+                .map_err(|_| invalid())?
+                * 100.0
+        }
+    };
+    if !(0.0..=100.0).contains(&similarity) {
+        return Err(invalid());
+    }
+    Ok(similarity as usize)
+}
+
+fn force_arg() -> Arg {
+    Arg::new("force")
+        .short('f')
+        .long("force")
+        .help("Discard uncommitted changes that would otherwise prevent the operation")
+        .action(ArgAction::SetTrue)
+}
+
+fn get_diff_options(matches: &ArgMatches) -> DiffOptions {
+    let mut options = DiffOptions::default();
+    if let Some(context) = matches.get_one::<usize>("unified") {
+        options.context = *context;
+    }
+    match matches
+        .get_one::<String>("diff-algorithm")
+        .map(String::as_str)
+    {
+        Some("patience") => options.algorithm = DiffAlgorithm::Patience,
+        Some("histogram") => options.algorithm = DiffAlgorithm::Histogram,
+        _ => {}
+    }
+    options.renames = matches.get_one::<usize>("find-renames").copied();
+    // Like git, looking for copies implies looking for renames.
+    options.copies = matches.get_flag("find-copies");
+    if options.copies {
+        options
+            .renames
+            .get_or_insert(diff::DEFAULT_RENAME_THRESHOLD);
+    }
+    options.word_diff = match matches.get_one::<String>("word-diff").map(String::as_str) {
+        Some("plain") => Some(WordDiff::Plain),
+        Some("color") => Some(WordDiff::Color),
+        Some("porcelain") => Some(WordDiff::Porcelain),
+        _ => None,
+    };
+    // Colouring words is pointless without colour, so like git it turns colour on.
+    options.color = match matches.get_one::<String>("color").map(String::as_str) {
+        _ if options.word_diff == Some(WordDiff::Color) => true,
+        Some("always") => true,
+        Some("never") => false,
+        _ => io::stdout().is_terminal(),
+    };
+    options.color_moved = matches.get_flag("color-moved");
+    options
+}
+
+fn get_merge_options(repo: &Repository, matches: &ArgMatches) -> ugit::Result<MergeOptions> {
+    let mut options = MergeOptions::default();
+    let style = match matches.get_one::<String>("conflict") {
+        Some(style) => Some(style.to_owned()),
+        None => config::get(repo, "merge.conflictStyle")?,
+    };
+    match style.as_deref() {
+        Some("diff3") => options.style = ConflictStyle::Diff3,
+        Some("zdiff3") => options.style = ConflictStyle::Zdiff3,
+        _ => {}
+    }
+    options.favor = match matches
+        .get_one::<String>("strategy-option")
+        .map(String::as_str)
+    {
+        Some("ours") => Some(MergeFavor::Ours),
+        Some("theirs") => Some(MergeFavor::Theirs),
+        Some("union") => Some(MergeFavor::Union),
+        _ => None,
+    };
+    Ok(options)
+}
+
+/// Map an error to the exit code used to report it. Merge conflicts aren't fatal so they're
+/// distinguished from other errors in the same way as git.
+fn exit_code(err: &ugit::Error) -> i32 {
+    match err {
+        ugit::Error::MergeConflict(_) => 1,
+        _ => 128,
     }
 }
 
 fn print_commit(oid: &str, commit: &ugit::Commit, refs: Option<&Vec<String>>) {
-    let refs_str = match refs {
+    print!("{}", format_commit(oid, commit, refs));
+}
+
+/// Format a commit as log shows it by default, followed by a blank line.
+fn format_commit(oid: &str, commit: &ugit::Commit, refs: Option<&Vec<String>>) -> String {
+    let mut output = format!("commit {}{}\n", oid, format_refs(refs));
+    if commit.parents.len() > 1 {
+        let parents: Vec<String> = commit.parents.iter().map(|p| shorten_oid(p)).collect();
+        output.push_str(&format!("Merge: {}\n", parents.join(" ")));
+    }
+    if let Some(author) = &commit.author {
+        output.push_str(&format!("Author: {} <{}>\n", author.name, author.email));
+        output.push_str(&format!(
+            "Date:   {}\n",
+            format_date(author.time, author.offset)
+        ));
+    }
+    output.push('\n');
+    for line in commit.message.lines() {
+        output.push_str(&format!("    {}\n", line));
+    }
+    output.push('\n');
+    output
+}
+
+/// Format the names of the refs pointing at a commit as they follow its OID, e.g.
+/// " (refs/heads/master, HEAD)".
+fn format_refs(refs: Option<&Vec<String>>) -> String {
+    match refs {
         Some(refs) => format!(" ({})", refs.join(", ")),
         None => "".to_owned(),
-    };
-    println!("commit {}{}", oid, refs_str);
-    println!("{}\n", commit.message);
+    }
+}
+
+/// Format a timestamp in the style of git's default date format, e.g.
+/// "Tue Nov 14 22:13:20 2023 +0000", shown in the timezone it was recorded in.
+fn format_date(time: i64, offset: i32) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (year, month, day, seconds) = to_civil_time(time, offset);
+    let days = (time + i64::from(offset) * 60).div_euclid(86400);
+
+    format!(
+        "{} {} {} {:02}:{:02}:{:02} {} {}",
+        DAYS[days.rem_euclid(7) as usize],
+        MONTHS[(month - 1) as usize],
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        year,
+        base::format_offset(offset)
+    )
 }
 
-/// Beginning at the commit with the specified OID, print the commit message and repeatedly do the
-/// same for the parent commit, if it exists.
-fn log(oid: &str) {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+/// Format a timestamp as an ISO 8601-like date, e.g. "2005-04-07 15:13:13 -0700".
+fn format_iso_date(time: i64, offset: i32) -> String {
+    let (year, month, day, seconds) = to_civil_time(time, offset);
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        base::format_offset(offset)
+    )
+}
 
+/// Convert a timestamp to the year, month, day and second of the day in the specified time zone.
+fn to_civil_time(time: i64, offset: i32) -> (i64, i64, i64, i64) {
+    let local_time = time + i64::from(offset) * 60;
+    let days = local_time.div_euclid(86400);
+    let seconds = local_time.rem_euclid(86400);
+
+    // Convert days since the epoch to a civil date, using the algorithm from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, seconds)
+}
+
+/// Print the history of the specified commits, in the format chosen by the log subcommand's
+/// options.
+fn log(
+    repo: &Repository,
+    prefix: &Path,
+    matches: &ArgMatches,
+    include: &[String],
+    exclude: &[String],
+    options: &WalkOptions,
+) -> ugit::Result<()> {
     let mut oid_to_ref: std::collections::HashMap<String, Vec<String>> =
         std::collections::HashMap::new();
 
-    for (ref_name, ref_value) in data::get_refs(&PathBuf::from(DEFAULT_REPO), None, true) {
+    for (ref_name, ref_value) in data::get_refs(repo, None, true)? {
         if let Some(value) = ref_value.value {
-            let refs = oid_to_ref.entry(value).or_insert_with(Vec::new);
+            let refs = oid_to_ref.entry(value).or_default();
             refs.push(ref_name);
         }
     }
 
-    for oid in base::get_commits_and_parents(default_repo, vec![oid]) {
-        let commit = base::get_commit(default_repo, &oid);
-        let refs = oid_to_ref.get(&oid);
-        print_commit(&oid, &commit, refs);
+    let oneline = matches.get_flag("oneline");
+    let stat = matches.get_flag("stat");
+    let patch = matches.get_flag("patch");
+    let follow = matches.get_flag("follow");
+    let mut diff_options = get_diff_options(matches);
+    if follow {
+        diff_options
+            .renames
+            .get_or_insert(diff::DEFAULT_RENAME_THRESHOLD);
     }
+
+    // Each commit is listed with the paths to show its changes for.
+    let mut commits: Vec<base::FollowedCommit> = if follow {
+        // The followed path changes along the way, so the walk can't be limited to it.
+        let walk_options = WalkOptions {
+            paths: vec![],
+            ..options.clone()
+        };
+        let commits = base::walk_revisions(repo, include, exclude, &walk_options)?;
+        base::follow_path(repo, commits, &options.paths[0])?
+    } else {
+        base::walk_revisions(repo, include, exclude, options)?
+            .into_iter()
+            .map(|(oid, parents)| (oid, parents, options.paths.clone()))
+            .collect()
+    };
+    if let Some(max_count) = matches.get_one::<usize>("max-count") {
+        commits.truncate(*max_count);
+    }
+
+    // The commit each line of the graph is heading towards, if a graph is being drawn.
+    let mut graph_columns = matches.get_flag("graph").then(Vec::new);
+    for (oid, parents, paths) in &commits {
+        let commit = base::get_commit(repo, oid)?;
+        let refs = oid_to_ref.get(oid);
+        let mut text = if oneline {
+            let subject = commit.message.lines().next().unwrap_or_default();
+            format!("{}{} {}\n", shorten_oid(oid), format_refs(refs), subject)
+        } else {
+            format_commit(oid, &commit, refs)
+        };
+
+        if stat || patch {
+            let changes = get_commit_changes(repo, &commit, paths, &diff_options)?;
+            if !changes.is_empty() {
+                if stat {
+                    text.push_str(&diff::format_stat(&changes, prefix));
+                    if patch || !oneline {
+                        text.push('\n');
+                    }
+                }
+                if patch {
+                    text.push_str(&diff::format_file_diffs(&changes, prefix, &diff_options));
+                }
+            }
+        }
+
+        match &mut graph_columns {
+            Some(columns) => {
+                let (rows, padding) = draw_graph_rows(columns, oid, parents);
+                let lines: Vec<&str> = text.lines().collect();
+                let width = rows.iter().chain([&padding]).map(String::len).max();
+                for i in 0..rows.len().max(lines.len()) {
+                    let graph = rows.get(i).unwrap_or(&padding);
+                    let line = lines.get(i).copied().unwrap_or_default();
+                    let row = format!("{:<2$} {}", graph, line, width.unwrap_or_default());
+                    println!("{}", row.trim_end());
+                }
+            }
+            None => print!("{}", text),
+        }
+    }
+
+    Ok(())
 }
 
-fn show(oid: Option<&str>) {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+/// Find the changes a commit made to the specified paths, or to every path if there are none,
+/// relative to its parent. Like git, no changes are shown for merges.
+fn get_commit_changes(
+    repo: &Repository,
+    commit: &ugit::Commit,
+    paths: &[PathBuf],
+    options: &DiffOptions,
+) -> ugit::Result<Vec<diff::FileDiff>> {
+    if commit.parents.len() > 1 {
+        return Ok(vec![]);
+    }
+    let parent_tree = match commit.parents.first() {
+        Some(parent) => Some(base::get_commit(repo, parent)?.tree),
+        None => None,
+    };
+    let from = diff::filter_tree(base::get_tree(repo, parent_tree.as_deref(), None)?, paths);
+    let to = diff::filter_tree(base::get_tree(repo, Some(&commit.tree), None)?, paths);
+    diff::diff_tree_files(repo, &from, &to, options)
+}
 
-    if let Some(oid) = oid {
-        let commit = base::get_commit(default_repo, oid);
-        print_commit(oid, &commit, None);
+/// Draw the row of an ASCII history graph for the specified commit, followed by the rows that lead
+/// its lines into those of its parents. `columns` holds the commit that each line of the graph is
+/// heading towards, and is updated for the next commit. The returned padding continues the lines
+/// alongside the rest of the commit's description.
+fn draw_graph_rows(
+    columns: &mut Vec<String>,
+    oid: &str,
+    parents: &[String],
+) -> (Vec<String>, String) {
+    let index = match columns.iter().position(|column| column == oid) {
+        Some(index) => index,
+        None => {
+            columns.push(oid.to_owned());
+            columns.len() - 1
+        }
+    };
+    let commit_row: Vec<&str> = (0..columns.len())
+        .map(|i| if i == index { "*" } else { "|" })
+        .collect();
+    let mut rows = vec![commit_row.join(" ")];
 
-        let parent_tree = if let Some(parent_oid) = commit.parents.first() {
-            let parent_commit = base::get_commit(default_repo, parent_oid);
-            Some(parent_commit.tree)
+    // The commit's parents take its place, except those that another line is already heading
+    // towards, which the commit's lines join instead.
+    let mut next_columns: Vec<String> = vec![];
+    let mut lines: Vec<(usize, &str)> = vec![];
+    for (i, column) in columns.iter().enumerate() {
+        let targets = if i == index {
+            parents
         } else {
-            None
+            std::slice::from_ref(column)
         };
-        let result = diff::diff_trees(
-            &base::get_tree(default_repo, parent_tree.as_deref(), None),
-            &base::get_tree(default_repo, Some(commit.tree.as_str()), None),
-        );
-        println!("{}", result)
+        for target in targets {
+            if !next_columns.contains(target) {
+                next_columns.push(target.clone());
+            }
+            lines.push((i, target));
+        }
+    }
+
+    // Move each line at most one column per row until they all reach their new columns.
+    let mut positions: Vec<(usize, usize)> = lines
+        .iter()
+        .map(|(from, target)| {
+            let to = next_columns.iter().position(|column| column == target);
+            (*from, to.unwrap_or(*from))
+        })
+        .collect();
+    let width = 2 * columns.len().max(next_columns.len());
+    while positions.iter().any(|(position, to)| position != to) {
+        let mut row = vec![' '; width];
+        for (position, to) in &mut positions {
+            if *to > *position {
+                row[2 * *position + 1] = '\\';
+                *position += 1;
+            } else if *to < *position {
+                row[2 * *position - 1] = '/';
+                *position -= 1;
+            } else {
+                row[2 * *position] = '|';
+            }
+        }
+        rows.push(row.into_iter().collect::<String>().trim_end().to_owned());
     }
+
+    *columns = next_columns;
+    (rows, vec!["|"; columns.len()].join(" "))
 }
 
-fn k() {
+fn show(repo: &Repository, oid: &str, options: &DiffOptions) -> ugit::Result<()> {
+    let commit = base::get_commit(repo, oid)?;
+    print_commit(oid, &commit, None);
+
+    let parent_tree = if let Some(parent_oid) = commit.parents.first() {
+        let parent_commit = base::get_commit(repo, parent_oid)?;
+        Some(parent_commit.tree)
+    } else {
+        None
+    };
+    let result = diff::diff_trees(
+        repo,
+        &base::get_tree(repo, parent_tree.as_deref(), None)?,
+        &base::get_tree(repo, Some(commit.tree.as_str()), None)?,
+        Path::new(""),
+        options,
+    )?;
+    println!("{}", result);
+
+    Ok(())
+}
+
+/// Print a graph of the commits selected by the specified revisions, or of all the commits
+/// reachable from a ref if there are none, in the Graphviz dot format.
+fn k(repo: &Repository, revisions: &[&str], options: &WalkOptions) -> ugit::Result<()> {
     let mut dot = String::new();
     dot.push_str("digraph commits {\n");
 
     let mut ref_oids: HashSet<String> = HashSet::new();
-    for (refname, ref_value) in data::get_refs(&PathBuf::from(DEFAULT_REPO), None, false) {
+    for (refname, ref_value) in data::get_refs(repo, None, false)? {
         dot.push_str(format!("\"{}\" [shape=note]\n", refname).as_str());
         dot.push_str(
             format!(
@@ -345,12 +1262,13 @@ fn k() {
         }
     }
 
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
-
-    for oid in
-        base::get_commits_and_parents(default_repo, ref_oids.iter().map(String::as_str).collect())
-    {
-        let commit = base::get_commit(default_repo, &oid);
+    let (include, exclude) = if revisions.is_empty() {
+        (ref_oids.into_iter().collect(), vec![])
+    } else {
+        base::parse_revisions(repo, revisions)?
+    };
+    for (oid, _) in base::walk_revisions(repo, &include, &exclude, options)? {
+        let commit = base::get_commit(repo, &oid)?;
         dot.push_str(
             format!(
                 "\"{}\" [shape=box style=filled label=\"{}\"]\n",
@@ -367,53 +1285,535 @@ fn k() {
     dot.push('}');
 
     println!("{}", dot);
+
+    Ok(())
 }
 
-fn status() {
-    let head = base::get_oid("@").expect("Failed to get HEAD");
-    if let Some(branch_name) = base::get_branch_name() {
+fn status(repo: &Repository, prefix: &Path) -> ugit::Result<()> {
+    let head = data::get_ref(repo, "HEAD", true)?.value;
+    if let Some(branch_name) = base::get_branch_name(repo)? {
         println!("On branch {}", branch_name);
-    } else {
+    } else if let Some(head) = &head {
         println!("HEAD detached at {}", shorten_oid(head.as_str()));
     }
 
-    if let Some(merge_head) = data::get_ref(&PathBuf::from(DEFAULT_REPO), "MERGE_HEAD", true).value
-    {
+    if let Some(merge_head) = data::get_ref(repo, "MERGE_HEAD", true)?.value {
         println!("Merging with {}", shorten_oid(&merge_head));
     }
 
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+    let head_tree = match head {
+        Some(oid) => Some(base::get_commit(repo, &oid)?.tree),
+        None => None,
+    };
+    let head_tree = base::get_tree(repo, head_tree.as_deref(), None)?;
+    let index_tree = index::read(repo)?.to_tree();
+    let working_tree = base::get_working_tree(repo)?;
+
+    // Conflicted paths are listed separately until they're resolved.
+    let conflicts = base::get_merge_conflicts(repo)?;
+    let is_unmerged = |path: &std::ffi::OsString| conflicts.iter().any(|(p, _)| p == path);
+
+    let renames = diff::find_renames(
+        repo,
+        &head_tree,
+        &index_tree,
+        diff::DEFAULT_RENAME_THRESHOLD,
+        false,
+    )?;
+    let staged: Vec<_> = diff::get_changed_files(&head_tree, &index_tree, &renames)
+        .into_iter()
+        .filter(|(path, _, _)| !is_unmerged(path))
+        .collect();
+    if !staged.is_empty() {
+        println!("\nChanges to be committed:\n");
+        print_changed_files(prefix, staged);
+    }
+
+    if !conflicts.is_empty() {
+        println!("\nUnmerged paths:\n");
+        print_changed_files(
+            prefix,
+            conflicts
+                .iter()
+                .map(|(path, kind)| (path.clone().into_os_string(), kind.description(), None))
+                .collect(),
+        );
+    }
+
+    let (untracked, unstaged): (Vec<_>, Vec<_>) =
+        diff::get_changed_files(&index_tree, &working_tree, &[])
+            .into_iter()
+            .filter(|(path, _, _)| !is_unmerged(path))
+            .partition(|(_, action, _)| *action == "new file");
+    if !unstaged.is_empty() {
+        println!("\nChanges not staged for commit:\n");
+        print_changed_files(prefix, unstaged);
+    }
+    if !untracked.is_empty() {
+        println!("\nUntracked files:\n");
+        let mut untracked: Vec<_> = untracked.into_iter().map(|(path, _, _)| path).collect();
+        untracked.sort();
+        for path in untracked {
+            println!(
+                "    {}",
+                ugit::relative_to_prefix(prefix, Path::new(&path)).display()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the specified paths that are ignored, along with the rule responsible if `verbose` is set.
+/// Returns whether any of the paths are ignored.
+fn check_ignore(
+    repo: &Repository,
+    prefix: &Path,
+    paths: &[PathBuf],
+    verbose: bool,
+    non_matching: bool,
+) -> ugit::Result<bool> {
+    let mut ignore = Ignore::new(repo)?;
+    let mut any_ignored = false;
+
+    for path in paths {
+        let is_dir = repo.work_tree_path(path).is_dir();
+        let ignored = ignore.is_ignored(path, is_dir)?;
+        any_ignored |= ignored;
+
+        let display_path = ugit::relative_to_prefix(prefix, path);
+        if !verbose {
+            if ignored {
+                println!("{}", display_path.display());
+            }
+            continue;
+        }
 
-    println!("\nChanges to be committed:\n");
-    let head_tree = base::get_commit(default_repo, &head).tree;
-    for (path, action) in diff::get_changed_files(
-        &base::get_tree(default_repo, Some(&head_tree), None),
-        &base::get_working_tree(),
-    ) {
-        println!("{:>12}: {}", action, path.to_str().unwrap());
+        // Negated rules are shown too, as they explain why a path isn't ignored.
+        match ignore.matching_rule(path, is_dir)? {
+            Some(rule) if ignored || rule.negated => println!(
+                "{}:{}:{}\t{}",
+                ugit::relative_to_prefix(prefix, &rule.source).display(),
+                rule.line_number,
+                rule.pattern,
+                display_path.display()
+            ),
+            _ if non_matching => println!("::\t{}", display_path.display()),
+            _ => {}
+        }
     }
+
+    Ok(any_ignored)
 }
 
-fn diff(commit: &str) {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+/// Print each changed path with how it changed, showing renamed and copied files as "a -> b".
+fn print_changed_files(prefix: &Path, mut changed_files: Vec<(OsString, &str, Option<OsString>)>) {
+    changed_files.sort();
+    let width = changed_files
+        .iter()
+        .map(|(_, action, _)| action.len())
+        .max()
+        .unwrap_or(0)
+        .max(12);
+    for (path, action, origin) in changed_files {
+        let path = ugit::relative_to_prefix(prefix, Path::new(&path));
+        match origin {
+            Some(origin) => println!(
+                "{:>width$}: {} -> {}",
+                action,
+                ugit::relative_to_prefix(prefix, Path::new(&origin)).display(),
+                path.display()
+            ),
+            None => println!("{:>width$}: {}", action, path.display()),
+        }
+    }
+}
 
-    let tree_commit = base::get_commit(default_repo, commit);
-    let tree = base::get_tree(default_repo, Some(&tree_commit.tree), None);
-    let working_tree = base::get_working_tree();
-    let result = diff::diff_trees(&tree, &working_tree);
-    println!("{}", result);
+fn rev_parse(repo: &Repository, matches: &ArgMatches) -> ugit::Result<()> {
+    if matches.get_flag("show-toplevel") {
+        println!("{}", repo.work_tree.display());
+    }
+
+    let revisions = get_revisions(matches);
+    let verify = matches.get_flag("verify");
+    if verify && revisions.len() != 1 {
+        return Err(ugit::Error::NeedSingleRevision);
+    }
+    let print_oid = |prefix: &str, oid: &str| -> ugit::Result<()> {
+        match matches.get_one::<usize>("short") {
+            Some(length) => println!("{}{}", prefix, base::abbreviate_oid(repo, oid, *length)?),
+            None => println!("{}{}", prefix, oid),
+        }
+        Ok(())
+    };
+
+    for revision in revisions {
+        if matches.get_flag("abbrev-ref") {
+            println!("{}", base::get_abbrev_ref(repo, revision)?);
+        } else if verify {
+            let oid = base::get_oid(repo, revision)?;
+            // A full OID resolves to itself whether or not the object exists.
+            if !data::object_exists(repo, &oid) {
+                return Err(ugit::Error::ObjectNotFound(oid));
+            }
+            print_oid("", &oid)?;
+        } else if let Some((from, to, symmetric)) = base::split_range(revision) {
+            // Like git, print the commits a range includes and then those it excludes.
+            let (from, to) = (base::get_oid(repo, from)?, base::get_oid(repo, to)?);
+            if symmetric {
+                print_oid("", &from)?;
+                print_oid("", &to)?;
+                if let Some(merge_base) = base::get_merge_base(repo, &from, &to)? {
+                    print_oid("^", &merge_base)?;
+                }
+            } else {
+                print_oid("", &to)?;
+                print_oid("^", &from)?;
+            }
+        } else if let Some(revision) = revision.strip_prefix('^') {
+            print_oid("^", &base::get_oid(repo, revision)?)?;
+        } else {
+            print_oid("", &base::get_oid(repo, revision)?)?;
+        }
+    }
+    Ok(())
 }
 
-fn merge(commit: &str) {
-    base::merge(commit);
+/// Parse the argument to blame's -L option, either "start,end" or "start,+count".
+fn parse_line_range(value: &str) -> Result<(usize, usize), String> {
+    let parse = |n: &str| n.parse::<usize>().map_err(|err| err.to_string());
+    let (start, end) = value
+        .split_once(',')
+        .ok_or_else(|| "expected start,end".to_owned())?;
+    let start = parse(start)?;
+    let end = match end.strip_prefix('+') {
+        Some(count) => (start + parse(count)?).saturating_sub(1),
+        None => parse(end)?,
+    };
+    Ok((start, end))
 }
 
-fn exit_if_not_repository() {
-    let cwd = env::current_dir().expect("Failed to get current directory");
-    if !base::is_ugit_repository(&cwd) {
-        eprintln!("Not a ugit repository.");
-        process::exit(1);
+/// Print the commit that last changed each line, with its author and date, beside the line. The
+/// first line is numbered `start`.
+fn print_blame(repo: &Repository, lines: &[blame::BlameLine], start: usize) -> ugit::Result<()> {
+    let mut commits: HashMap<&str, (String, String)> = HashMap::new();
+    for (oid, _, _) in lines {
+        if !commits.contains_key(oid.as_str()) {
+            let commit = base::get_commit(repo, oid)?;
+            let details = match commit.author {
+                Some(author) => (author.name, format_iso_date(author.time, author.offset)),
+                None => ("unknown".to_owned(), format_iso_date(0, 0)),
+            };
+            commits.insert(oid, details);
+        }
+    }
+
+    let name_width = commits
+        .values()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    let number_width = (start + lines.len()).saturating_sub(1).to_string().len();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (i, (oid, _, content)) in lines.iter().enumerate() {
+        let (name, date) = &commits[oid.as_str()];
+        write!(
+            out,
+            "{} ({:<name_width$} {} {:>number_width$}) ",
+            shorten_oid(oid),
+            name,
+            date,
+            start + i,
+        )?;
+        out.write_all(content)?;
+        if !content.ends_with(b"\n") {
+            out.write_all(b"\n")?;
+        }
     }
+    Ok(())
+}
+
+/// Print blame results in git's porcelain format: a header for each group of consecutive lines
+/// from the same commit, the commit's details the first time it appears, then each line after a
+/// tab.
+fn print_blame_porcelain(
+    repo: &Repository,
+    path: &Path,
+    lines: &[blame::BlameLine],
+    start: usize,
+) -> ugit::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut seen: HashSet<&str> = HashSet::new();
+    for (i, (oid, line, content)) in lines.iter().enumerate() {
+        let starts_group = i == 0 || lines[i - 1].0 != *oid || lines[i - 1].1 + 1 != *line;
+        if starts_group {
+            let count = lines[i..]
+                .iter()
+                .zip(*line..)
+                .take_while(|((next_oid, next_line, _), expected)| {
+                    next_oid == oid && *next_line == *expected
+                })
+                .count();
+            writeln!(out, "{} {} {} {}", oid, line, start + i, count)?;
+        } else {
+            writeln!(out, "{} {} {}", oid, line, start + i)?;
+        }
+
+        if seen.insert(oid) {
+            let commit = base::get_commit(repo, oid)?;
+            for (role, signature) in [("author", &commit.author), ("committer", &commit.committer)]
+            {
+                if let Some(signature) = signature {
+                    writeln!(out, "{} {}", role, signature.name)?;
+                    writeln!(out, "{}-mail <{}>", role, signature.email)?;
+                    writeln!(out, "{}-time {}", role, signature.time)?;
+                    writeln!(out, "{}-tz {}", role, base::format_offset(signature.offset))?;
+                }
+            }
+            writeln!(
+                out,
+                "summary {}",
+                commit.message.lines().next().unwrap_or("")
+            )?;
+            if commit.parents.is_empty() {
+                writeln!(out, "boundary")?;
+            }
+            for parent in &commit.parents {
+                let tree = base::get_commit(repo, parent)?.tree;
+                if base::get_oid_at_path(repo, &tree, path)?.is_some() {
+                    writeln!(out, "previous {} {}", parent, path.display())?;
+                    break;
+                }
+            }
+            writeln!(out, "filename {}", path.display())?;
+        }
+
+        out.write_all(b"\t")?;
+        out.write_all(content)?;
+        if !content.ends_with(b"\n") {
+            out.write_all(b"\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn rev_list(repo: &Repository, matches: &ArgMatches) -> ugit::Result<()> {
+    let (include, exclude) = base::parse_revisions(repo, &get_revisions(matches))?;
+    let mut commits: Vec<String> =
+        base::walk_revisions(repo, &include, &exclude, &get_walk_options(matches))?
+            .into_iter()
+            .map(|(oid, _)| oid)
+            .collect();
+    if let Some(max_count) = matches.get_one::<usize>("max-count") {
+        commits.truncate(*max_count);
+    }
+    if matches.get_flag("reverse") {
+        commits.reverse();
+    }
+
+    if matches.get_flag("count") {
+        println!("{}", commits.len());
+        return Ok(());
+    }
+    for oid in &commits {
+        println!("{}", oid);
+    }
+
+    if matches.get_flag("objects") {
+        // List the trees and blobs reachable from the commits that aren't also reachable from the
+        // excluded ones.
+        let excluded =
+            base::get_objects_in_commits(repo, exclude.iter().map(String::as_str).collect())?;
+        let mut objects: HashSet<String> = HashSet::new();
+        for oid in &commits {
+            objects.extend(base::get_objects_in_commit_tree(repo, oid)?);
+        }
+        let mut objects: Vec<String> = objects
+            .into_iter()
+            .filter(|oid| !excluded.contains(oid))
+            .collect();
+        objects.sort();
+        for oid in objects {
+            println!("{}", oid);
+        }
+    }
+    Ok(())
+}
+
+/// Print the differences chosen by the diff subcommand's revisions and options: between two
+/// commits or the ends of a range, or between a commit (HEAD by default) and the working tree or,
+/// with --cached, the index. For "A...B", the differences are between B and where it diverged from
+/// A. Returns whether there were any differences.
+fn diff(
+    repo: &Repository,
+    prefix: &Path,
+    matches: &ArgMatches,
+    paths: &[PathBuf],
+) -> ugit::Result<bool> {
+    let get_commit_tree = |revision: &str| -> ugit::Result<ugit::Tree> {
+        let oid = base::get_oid(repo, revision)?;
+        base::get_tree(repo, Some(&base::get_commit(repo, &oid)?.tree), None)
+    };
+    let get_current_tree = || -> ugit::Result<ugit::Tree> {
+        if matches.get_flag("cached") {
+            Ok(index::read(repo)?.to_tree())
+        } else {
+            base::get_working_tree(repo)
+        }
+    };
+    let (from_tree, to_tree) = match get_revisions(matches).as_slice() {
+        [] => (base::get_head_tree(repo)?, get_current_tree()?),
+        [revision] => match base::split_range(revision) {
+            Some((from, to, symmetric)) => {
+                let to = base::get_oid(repo, to)?;
+                let mut from = base::get_oid(repo, from)?;
+                if symmetric {
+                    from = base::get_merge_base(repo, &from, &to)?
+                        .ok_or(ugit::Error::NoCommonAncestor(from))?;
+                }
+                (get_commit_tree(&from)?, get_commit_tree(&to)?)
+            }
+            None => (get_commit_tree(revision)?, get_current_tree()?),
+        },
+        [from, to, ..] => (get_commit_tree(from)?, get_commit_tree(to)?),
+    };
+    let from_tree = diff::filter_tree(from_tree, paths);
+    let to_tree = diff::filter_tree(to_tree, paths);
+
+    let options = get_diff_options(matches);
+    let file_diffs = diff::diff_tree_files(repo, &from_tree, &to_tree, &options)?;
+    if matches.get_flag("stat") {
+        if !file_diffs.is_empty() {
+            print!("{}", diff::format_stat(&file_diffs, prefix));
+        }
+    } else if matches.get_flag("numstat") {
+        print!("{}", diff::format_numstat(&file_diffs, prefix));
+    } else if matches.get_flag("name-only") {
+        for file_diff in &file_diffs {
+            let path = ugit::relative_to_prefix(prefix, Path::new(&file_diff.path));
+            println!("{}", path.display());
+        }
+    } else if matches.get_flag("name-status") {
+        print!("{}", diff::format_name_status(&file_diffs, prefix));
+    } else {
+        println!("{}", diff::format_file_diffs(&file_diffs, prefix, &options));
+    }
+
+    Ok(!file_diffs.is_empty())
+}
+
+/// Merge the specified commit into HEAD and report how it went. Conflicts are reported as an error
+/// so that, like git, the command exits with a status of 1.
+fn merge(repo: &Repository, name: &str, options: &MergeOptions, force: bool) -> ugit::Result<()> {
+    let conflicts = match base::merge(repo, name, options, force)? {
+        MergeOutcome::FastForward => {
+            println!("Fast-forward merge, no need to commit");
+            return Ok(());
+        }
+        MergeOutcome::Clean => {
+            println!("Merged in working tree\nPlease commit");
+            return Ok(());
+        }
+        MergeOutcome::Conflicts(conflicts) => conflicts,
+    };
+
+    for (path, kind) in &conflicts {
+        let path = path.display();
+        match kind {
+            ConflictKind::BothModified => {
+                println!("CONFLICT (content): Merge conflict in {}", path)
+            }
+            ConflictKind::BothAdded => {
+                println!("CONFLICT (add/add): Merge conflict in {}", path)
+            }
+            ConflictKind::DeletedByUs => println!(
+                "CONFLICT (modify/delete): {0} deleted in HEAD and modified in MERGE_HEAD. \
+                 Version MERGE_HEAD of {0} left in tree.",
+                path
+            ),
+            ConflictKind::DeletedByThem => println!(
+                "CONFLICT (modify/delete): {0} deleted in MERGE_HEAD and modified in HEAD. \
+                 Version HEAD of {0} left in tree.",
+                path
+            ),
+        }
+    }
+    Err(ugit::Error::MergeConflict(
+        conflicts.into_iter().map(|(path, _)| path).collect(),
+    ))
+}
+
+/// The ugit directory and working tree given by the `--git-dir` and `--work-tree` options, or failing
+/// those by the `UGIT_DIR` and `UGIT_WORK_TREE` environment variables.
+fn get_repository_options(matches: &ArgMatches) -> (Option<PathBuf>, Option<PathBuf>) {
+    let git_dir = matches
+        .get_one::<PathBuf>("git-dir")
+        .cloned()
+        .or_else(|| env::var_os("UGIT_DIR").map(PathBuf::from));
+    let work_tree = matches
+        .get_one::<PathBuf>("work-tree")
+        .cloned()
+        .or_else(|| env::var_os("UGIT_WORK_TREE").map(PathBuf::from));
+    (git_dir, work_tree)
+}
+
+/// Create a repository where the options ask for one. The working tree defaults to the current
+/// directory, and the ugit directory to one inside the working tree.
+fn init_repository(matches: &ArgMatches, cwd: &Path) -> ugit::Result<Repository> {
+    let (git_dir, work_tree) = get_repository_options(matches);
+    let work_tree = match work_tree {
+        Some(work_tree) => fs::canonicalize(cwd.join(work_tree))?,
+        None => cwd.to_owned(),
+    };
+    let git_dir = match git_dir {
+        Some(git_dir) => cwd.join(git_dir),
+        None => work_tree.join(ugit::DEFAULT_REPO),
+    };
+    base::init(&git_dir, &work_tree)
+}
+
+/// Locate the repository to operate on. The `--git-dir` and `--work-tree` options, or failing those
+/// the `UGIT_DIR` and `UGIT_WORK_TREE` environment variables, take precedence over searching
+/// upwards from the current directory.
+fn open_repository(matches: &ArgMatches, cwd: &Path) -> ugit::Result<Repository> {
+    let (git_dir, work_tree) = get_repository_options(matches);
+
+    let git_dir = match git_dir {
+        Some(git_dir) => fs::canonicalize(cwd.join(&git_dir))
+            .map_err(|_| ugit::Error::NotARepository(git_dir))?,
+        None => {
+            let repo = Repository::discover(cwd)?;
+            if work_tree.is_none() {
+                return Ok(repo);
+            }
+            repo.git_dir
+        }
+    };
+    // As with git, specifying the ugit directory without a working tree makes the current
+    // directory the working tree.
+    let work_tree = match work_tree {
+        Some(work_tree) => fs::canonicalize(cwd.join(work_tree))?,
+        None => cwd.to_owned(),
+    };
+
+    Ok(Repository::new(&git_dir, &work_tree))
+}
+
+/// Convert the paths passed to a subcommand from being relative to the current directory to being
+/// relative to the root of the working tree.
+fn get_pathspecs(
+    repo: &Repository,
+    cwd: &Path,
+    matches: &ArgMatches,
+) -> ugit::Result<Vec<PathBuf>> {
+    matches
+        .get_many::<String>("paths")
+        .into_iter()
+        .flatten()
+        .map(|path| repo.work_tree_relative_path(&cwd.join(path)))
+        .collect()
 }
 
 fn shorten_oid(oid: &str) -> String {
@@ -1,29 +1,582 @@
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet},
     env, ffi, fs,
     path::Component,
     path::{self, Path},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use path::PathBuf;
 
-use super::{data, diff, Commit, Tree, DEFAULT_REPO};
+/// The file recording the paths that the merge in progress left with conflicts.
+const MERGE_CONFLICTS: &str = "MERGE_CONFLICTS";
+/// The file holding the default message for the merge commit.
+const MERGE_MSG: &str = "MERGE_MSG";
+
+use super::{
+    bytes_to_os_string, config, data, diff,
+    diff::ConflictKind,
+    from_hex,
+    ignore::Ignore,
+    index,
+    merge::{self, MergeOptions},
+    os_str_to_bytes, to_hex, Commit, Error, Repository, Result, Signature, Tree, MODE_EXECUTABLE,
+    MODE_FILE, MODE_SYMLINK, MODE_TREE,
+};
 
-/// Initialise a new repository and create a master branch.
-pub fn init() {
-    data::init();
+/// Initialise a new repository in the specified ugit directory, for the specified working tree, and
+/// create a master branch.
+pub fn init(git_dir: &Path, work_tree: &Path) -> Result<Repository> {
+    let repo = Repository::new(git_dir, work_tree);
+    data::init(&repo)?;
     data::update_ref(
-        &PathBuf::from(DEFAULT_REPO),
+        &repo,
         "HEAD",
         &data::RefValue {
             symbolic: true,
             value: Some("refs/heads/master".to_owned()),
         },
         true,
-    )
+    )?;
+    Ok(repo)
+}
+
+/// Resolve a revision to the OID of the object it names. As well as a ref name, "@", or a full or
+/// unambiguous abbreviated OID, a revision may be followed by any number of "~n" (the nth
+/// first-parent ancestor), "^n" (the nth parent) and "^{type}" (the object peeled to a commit or
+/// tree) suffixes. "<rev>:<path>" names the object at a path in a revision's tree, and ":<path>"
+/// the blob staged in the index.
+pub fn get_oid(repo: &Repository, revision: &str) -> Result<String> {
+    let bad_revision = || Error::BadRef(revision.to_owned());
+
+    if let Some((name, path)) = revision.split_once(':') {
+        return if name.is_empty() {
+            let index = index::read(repo)?;
+            let entry = index
+                .get(Path::new(path))
+                .ok_or_else(|| Error::PathNotInRevision {
+                    path: path.to_owned(),
+                    revision: "the index".to_owned(),
+                })?;
+            Ok(entry.oid.clone())
+        } else {
+            let tree = peel(repo, &get_oid(repo, name)?, "tree")?;
+            get_oid_at_path(repo, &tree, Path::new(path))?.ok_or_else(|| Error::PathNotInRevision {
+                path: path.to_owned(),
+                revision: name.to_owned(),
+            })
+        };
+    }
+
+    // Ref names can't contain '~' or '^', so the first of either starts the suffixes.
+    let (name, suffixes) = revision.split_at(revision.find(['~', '^']).unwrap_or(revision.len()));
+    let suffixes = parse_suffixes(suffixes).ok_or_else(bad_revision)?;
+    let mut oid = resolve_name(repo, name)?;
+    for suffix in suffixes {
+        match suffix {
+            RevisionSuffix::Peel(object_type) => oid = peel(repo, &oid, object_type)?,
+            RevisionSuffix::Parent(0) => oid = peel(repo, &oid, "commit")?,
+            RevisionSuffix::Parent(n) => {
+                oid = get_commit(repo, &oid)?
+                    .parents
+                    .get(n - 1)
+                    .cloned()
+                    .ok_or_else(bad_revision)?
+            }
+            RevisionSuffix::Ancestor(n) => {
+                for _ in 0..n {
+                    oid = get_commit(repo, &oid)?
+                        .parents
+                        .first()
+                        .cloned()
+                        .ok_or_else(bad_revision)?;
+                }
+            }
+        }
+    }
+    Ok(oid)
+}
+
+/// A step from one object to another named by a revision suffix.
+#[derive(Debug, PartialEq, Eq)]
+enum RevisionSuffix<'a> {
+    /// "^n", the nth parent, where "^0" is the commit itself.
+    Parent(usize),
+    /// "~n", the nth first-parent ancestor.
+    Ancestor(usize),
+    /// "^{type}", the object peeled to the specified type.
+    Peel(&'a str),
+}
+
+/// Parse the suffixes following the name in a revision, or return None if they're malformed. A
+/// "^" or "~" without a number means 1.
+fn parse_suffixes(mut suffixes: &str) -> Option<Vec<RevisionSuffix<'_>>> {
+    let mut result = vec![];
+    while let Some(operator) = suffixes.chars().next() {
+        suffixes = &suffixes[operator.len_utf8()..];
+        if operator == '^' && suffixes.starts_with('{') {
+            let end = suffixes.find('}')?;
+            result.push(RevisionSuffix::Peel(&suffixes[1..end]));
+            suffixes = &suffixes[end + 1..];
+            continue;
+        }
+
+        let digits = suffixes
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(suffixes.len());
+        let n: usize = match &suffixes[..digits] {
+            "" => 1,
+            n => n.parse().ok()?,
+        };
+        suffixes = &suffixes[digits..];
+        result.push(match operator {
+            '^' => RevisionSuffix::Parent(n),
+            '~' => RevisionSuffix::Ancestor(n),
+            _ => return None,
+        });
+    }
+    Some(result)
+}
+
+/// Resolve a ref name, a reflog entry such as "master@{1}", or a full or abbreviated OID.
+fn resolve_name(repo: &Repository, name: &str) -> Result<String> {
+    // "<ref>@{n}" names the value the ref had n changes ago.
+    if let Some((reference, n)) = name
+        .strip_suffix('}')
+        .and_then(|selector| selector.rsplit_once("@{"))
+    {
+        let n: usize = n.parse().map_err(|_| Error::BadRef(name.to_owned()))?;
+        // Like git, "@{n}" means the current branch's reflog, which differs from HEAD's once other
+        // branches have been checked out. Only a detached HEAD uses its own.
+        let head = data::get_ref(repo, "HEAD", false)?;
+        let reference = match (reference.is_empty(), head.symbolic, &head.value) {
+            (true, true, Some(branch)) => branch.as_str(),
+            (true, _, _) => "HEAD",
+            (false, _, _) => reference,
+        };
+        let entries = get_reflog(repo, reference)?;
+        return entries
+            .iter()
+            .rev()
+            .nth(n)
+            .map(|entry| entry.new.clone())
+            .ok_or_else(|| Error::ReflogTooShort {
+                reference: reference.to_owned(),
+                entries: entries.len(),
+            });
+    }
+
+    if let Some(reference) = find_ref(repo, name)? {
+        return data::get_ref(repo, &reference, true)?
+            .value
+            .ok_or_else(|| Error::BadRef(name.to_owned()));
+    }
+
+    let is_hex = name.chars().all(|c| c.is_ascii_hexdigit());
+    if name.len() == 40 && is_hex {
+        // Name is an OID.
+        return Ok(name.to_owned());
+    }
+    // Like git, abbreviated OIDs must be at least four characters long.
+    if name.len() >= 4 && is_hex {
+        let mut oids = data::find_objects_with_prefix(repo, &name.to_lowercase())?;
+        if oids.len() > 1 {
+            return Err(Error::AmbiguousOid(name.to_owned()));
+        }
+        if let Some(oid) = oids.pop() {
+            return Ok(oid);
+        }
+    }
+
+    Err(Error::BadRef(name.to_owned()))
+}
+
+/// Follow an object to one of the specified type: a commit's tree for "tree", or the object itself
+/// if it's already of that type. An empty type or "object" accepts any object.
+fn peel(repo: &Repository, oid: &str, object_type: &str) -> Result<String> {
+    let actual = data::get_object_type(repo, oid)?;
+    match object_type {
+        "" | "object" => return Ok(oid.to_owned()),
+        "tree" if actual == "commit" => return Ok(get_commit(repo, oid)?.tree),
+        "commit" | "tree" | "blob" if actual == object_type => return Ok(oid.to_owned()),
+        "commit" | "tree" | "blob" => {}
+        _ => return Err(Error::BadRef(format!("{}^{{{}}}", oid, object_type))),
+    }
+    Err(Error::TypeMismatch {
+        oid: oid.to_owned(),
+        expected: object_type.to_owned(),
+        actual,
+    })
+}
+
+/// Return the OID of the object at the specified path within the tree with the specified OID.
+pub(crate) fn get_oid_at_path(
+    repo: &Repository,
+    tree: &str,
+    path: &Path,
+) -> Result<Option<String>> {
+    let mut oid = Some(tree.to_owned());
+    for component in normalise_path(path).components() {
+        // Only trees have entries, so anything else found part way along the path is a dead end.
+        let tree = match oid {
+            Some(tree) if data::get_object_type(repo, &tree)? == "tree" => tree,
+            _ => return Ok(None),
+        };
+        oid = get_tree_entries(repo, Some(&tree))?
+            .into_iter()
+            .find(|(_, _, name)| name == component.as_os_str())
+            .map(|(_, entry_oid, _)| entry_oid);
+    }
+    Ok(oid)
+}
+
+/// Split a range of the form "A..B" or "A...B" into its ends, with a flag set for the symmetric
+/// "..." form. An omitted end means HEAD, although ".." alone isn't a range.
+pub fn split_range(range: &str) -> Option<(&str, &str, bool)> {
+    // A path after a colon may contain dots, so only look before it.
+    let end = range.find(':').unwrap_or(range.len());
+    let (from, to, symmetric) = if let Some(start) = range[..end].find("...") {
+        (&range[..start], &range[start + 3..], true)
+    } else {
+        let start = range[..end].find("..")?;
+        (&range[..start], &range[start + 2..], false)
+    };
+    // Like git, ".." with nothing either side isn't a range.
+    if from.is_empty() && to.is_empty() && !symmetric {
+        return None;
+    }
+    let from = if from.is_empty() { "HEAD" } else { from };
+    let to = if to.is_empty() { "HEAD" } else { to };
+    Some((from, to, symmetric))
+}
+
+/// Resolve a list of revisions to the commits to include in a history and those whose history to
+/// exclude from it. "^A" excludes A, "A..B" includes B and excludes A, and "A...B" includes both A
+/// and B but excludes the history they share.
+pub fn parse_revisions(
+    repo: &Repository,
+    revisions: &[&str],
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut include = vec![];
+    let mut exclude = vec![];
+    for revision in revisions {
+        if let Some((from, to, symmetric)) = split_range(revision) {
+            let (from, to) = (get_oid(repo, from)?, get_oid(repo, to)?);
+            if symmetric {
+                let from_history = get_commits_and_parents(repo, vec![&from])?;
+                let mut shared: Vec<String> = get_commits_and_parents(repo, vec![&to])?
+                    .into_iter()
+                    .filter(|oid| from_history.contains(oid))
+                    .collect();
+                shared.sort();
+                exclude.extend(shared);
+                include.push(from);
+            } else {
+                exclude.push(from);
+            }
+            include.push(to);
+        } else if let Some(revision) = revision.strip_prefix('^') {
+            exclude.push(get_oid(repo, revision)?);
+        } else {
+            include.push(get_oid(repo, revision)?);
+        }
+    }
+    Ok((include, exclude))
+}
+
+/// The order in which a revision walk lists commits. Whatever the order, a commit is always listed
+/// before its parents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// Newest commit timestamp first.
+    Date,
+    /// Newest author timestamp first.
+    AuthorDate,
+    /// Each line of history together, rather than interleaving commits from parallel branches.
+    Topo,
+}
+
+/// Options controlling how the history of a set of commits is walked.
+#[derive(Debug, Clone)]
+pub struct WalkOptions {
+    pub order: WalkOrder,
+    /// Only follow the first parent of merge commits.
+    pub first_parent: bool,
+    /// Only list the commits that change these paths, following just the history that explains
+    /// their contents.
+    pub paths: Vec<PathBuf>,
+    /// Only list the commits whose message contains this text.
+    pub grep: Option<String>,
+    /// Only list the commits whose author's name or email contains this text.
+    pub author: Option<String>,
+}
+
+impl Default for WalkOptions {
+    fn default() -> WalkOptions {
+        WalkOptions {
+            order: WalkOrder::Date,
+            first_parent: false,
+            paths: vec![],
+            grep: None,
+            author: None,
+        }
+    }
 }
 
-pub fn get_oid(mut name: &str) -> Option<String> {
+/// Return the commits reachable from those in `include` but not from those in `exclude`, in the
+/// order specified by `options`. Each commit is listed with its parents among the listed commits:
+/// a parent that isn't listed is replaced by its own nearest listed ancestors.
+pub fn walk_revisions(
+    repo: &Repository,
+    include: &[String],
+    exclude: &[String],
+    options: &WalkOptions,
+) -> Result<Vec<(String, Vec<String>)>> {
+    let excluded = get_commits_and_parents(repo, exclude.iter().map(String::as_str).collect())?;
+
+    // The time of each commit in the walk, the parents the walk follows from it and whether it's
+    // listed.
+    let mut commits: HashMap<String, (i64, Vec<String>, bool)> = HashMap::new();
+    let mut path_oids: HashMap<String, Vec<Option<String>>> = HashMap::new();
+    let mut oids_to_visit: Vec<String> = include.to_vec();
+    while let Some(oid) = oids_to_visit.pop() {
+        if excluded.contains(&oid) || commits.contains_key(&oid) {
+            continue;
+        }
+        let commit = get_commit(repo, &oid)?;
+        let mut parents = commit.parents.clone();
+        if options.first_parent {
+            parents.truncate(1);
+        }
+
+        let mut listed = is_match(&commit, options);
+        if !options.paths.is_empty() {
+            // A commit that leaves the paths as one of its parents had them changed nothing of
+            // interest, and that parent's history alone explains their contents.
+            let ours = get_path_oids(repo, &commit.tree, &options.paths, &mut path_oids)?;
+            let mut same_parent = None;
+            for parent in &parents {
+                let tree = get_commit(repo, parent)?.tree;
+                if get_path_oids(repo, &tree, &options.paths, &mut path_oids)? == ours {
+                    same_parent = Some(parent.clone());
+                    break;
+                }
+            }
+            match same_parent {
+                Some(parent) => {
+                    parents = vec![parent];
+                    listed = false;
+                }
+                // A root commit matters only if it adds something at the paths.
+                None if parents.is_empty() => listed &= ours.iter().any(Option::is_some),
+                None => {}
+            }
+        }
+
+        let signature = match options.order {
+            WalkOrder::AuthorDate => commit.author,
+            WalkOrder::Date | WalkOrder::Topo => commit.committer,
+        };
+        let time = signature.map_or(0, |signature| signature.time);
+        oids_to_visit.extend(parents.iter().rev().cloned());
+        commits.insert(oid, (time, parents, listed));
+    }
+
+    // Count how many children each commit has in the walk, so that a commit is only ready to be
+    // listed once all of them have been.
+    let mut children: HashMap<&str, usize> = HashMap::new();
+    for (_, parents, _) in commits.values() {
+        for parent in parents {
+            if commits.contains_key(parent) {
+                *children.entry(parent).or_default() += 1;
+            }
+        }
+    }
+
+    // Commits that are ready to be listed, ordered by time and then by when they became ready. The
+    // topological order takes the most recent to become ready, so that it follows each line of
+    // history to its end before starting on another.
+    let mut ready: BTreeMap<(i64, usize), &str> = BTreeMap::new();
+    let time_of = |oid: &str| match options.order {
+        WalkOrder::Topo => 0,
+        WalkOrder::Date | WalkOrder::AuthorDate => commits[oid].0,
+    };
+    let mut sequence = 0;
+    let mut seen: HashSet<&str> = HashSet::new();
+    for oid in include {
+        if commits.contains_key(oid) && !children.contains_key(oid.as_str()) && seen.insert(oid) {
+            ready.insert((time_of(oid), sequence), oid);
+            sequence += 1;
+        }
+    }
+
+    let mut order = Vec::with_capacity(commits.len());
+    while let Some((_, oid)) = ready.pop_last() {
+        order.push(oid);
+        // Like git, the first parent becomes ready first, so that the topological order lists the
+        // history merged in before continuing along the first-parent line.
+        for parent in &commits[oid].1 {
+            if let Some(count) = children.get_mut(parent.as_str()) {
+                *count -= 1;
+                if *count == 0 {
+                    ready.insert((time_of(parent), sequence), parent);
+                    sequence += 1;
+                }
+            }
+        }
+    }
+
+    // Working from the oldest commit, find the nearest listed commits at or below each one, which
+    // replace the unlisted parents of the commits that are listed.
+    let mut nearest_listed: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut result = vec![];
+    for oid in order.into_iter().rev() {
+        let (_, parents, listed) = &commits[oid];
+        let mut listed_parents: Vec<String> = vec![];
+        for parent in parents {
+            for ancestor in nearest_listed.get(parent.as_str()).into_iter().flatten() {
+                if !listed_parents.contains(ancestor) {
+                    listed_parents.push(ancestor.clone());
+                }
+            }
+        }
+        if *listed {
+            nearest_listed.insert(oid, vec![oid.to_owned()]);
+            result.push((oid.to_owned(), listed_parents));
+        } else {
+            nearest_listed.insert(oid, listed_parents);
+        }
+    }
+    result.reverse();
+    Ok(result)
+}
+
+/// A commit listed while following a file, with its parents and the paths its changes should be
+/// shown for: the file's path in the commit, and the path it was renamed from if the commit renamed
+/// it.
+pub type FollowedCommit = (String, Vec<String>, Vec<PathBuf>);
+
+/// Narrow down commits listed children first, as returned by `walk_revisions`, to those that
+/// changed the file at `path`, following the file back through the commits that renamed it.
+pub fn follow_path(
+    repo: &Repository,
+    commits: Vec<(String, Vec<String>)>,
+    path: &Path,
+) -> Result<Vec<FollowedCommit>> {
+    let mut path = path.to_owned();
+    let mut result = vec![];
+    for (oid, parents) in commits {
+        let commit = get_commit(repo, &oid)?;
+        let blob = match get_oid_at_path(repo, &commit.tree, &path)? {
+            Some(blob) => blob,
+            None => continue,
+        };
+        let mut parent_blobs = vec![];
+        for parent in &commit.parents {
+            let tree = get_commit(repo, parent)?.tree;
+            parent_blobs.push(get_oid_at_path(repo, &tree, &path)?);
+        }
+        if parent_blobs.contains(&Some(blob)) {
+            continue;
+        }
+
+        // Where the file first appears, look for the file it was renamed from and follow that
+        // instead from here on.
+        let mut paths = vec![path.clone()];
+        if parent_blobs.iter().all(Option::is_none) {
+            let parent_tree = match commit.parents.first() {
+                Some(parent) => Some(get_commit(repo, parent)?.tree),
+                None => None,
+            };
+            let from = get_tree(repo, parent_tree.as_deref(), None)?;
+            let to = get_tree(repo, Some(&commit.tree), None)?;
+            let threshold = diff::DEFAULT_RENAME_THRESHOLD;
+            let renames = diff::find_renames(repo, &from, &to, threshold, false)?;
+            if let Some((from, _, _, _)) = renames.iter().find(|(_, to, _, _)| *to == *path) {
+                path = PathBuf::from(from);
+                paths.push(path.clone());
+            }
+        }
+        result.push((oid, parents, paths));
+    }
+    Ok(result)
+}
+
+/// Whether a commit passes the message and author filters of a revision walk.
+fn is_match(commit: &Commit, options: &WalkOptions) -> bool {
+    let message_matches = options
+        .grep
+        .as_ref()
+        .is_none_or(|pattern| commit.message.contains(pattern.as_str()));
+    let author_matches = options.author.as_ref().is_none_or(|pattern| {
+        commit.author.as_ref().is_some_and(|author| {
+            format!("{} <{}>", author.name, author.email).contains(pattern.as_str())
+        })
+    });
+    message_matches && author_matches
+}
+
+/// Return the OIDs of the objects at the specified paths in a tree, caching them by the tree's OID.
+fn get_path_oids(
+    repo: &Repository,
+    tree: &str,
+    paths: &[PathBuf],
+    cache: &mut HashMap<String, Vec<Option<String>>>,
+) -> Result<Vec<Option<String>>> {
+    if let Some(oids) = cache.get(tree) {
+        return Ok(oids.clone());
+    }
+    let oids = paths
+        .iter()
+        .map(|path| get_oid_at_path(repo, tree, path))
+        .collect::<Result<Vec<_>>>()?;
+    cache.insert(tree.to_owned(), oids.clone());
+    Ok(oids)
+}
+
+/// Return the short name of the ref that a revision refers to, following symbolic refs, e.g.
+/// "master" for HEAD when master is checked out. A detached HEAD stays "HEAD", and a revision that
+/// isn't a ref name is returned unchanged.
+pub fn get_abbrev_ref(repo: &Repository, revision: &str) -> Result<String> {
+    let reference = match find_ref(repo, revision)? {
+        Some(reference) => data::resolve_ref(repo, &reference)?.0,
+        None => {
+            get_oid(repo, revision)?;
+            return Ok(revision.to_owned());
+        }
+    };
+    let short_name = ["refs/heads/", "refs/tags/", "refs/remote/"]
+        .iter()
+        .find_map(|prefix| reference.strip_prefix(prefix))
+        .unwrap_or(&reference);
+    Ok(short_name.to_owned())
+}
+
+/// Return the shortest prefix of an OID that's at least `min_length` characters long and that no
+/// other object in the object store shares. Anything other than a full OID is refused.
+pub fn abbreviate_oid(repo: &Repository, oid: &str, min_length: usize) -> Result<String> {
+    if oid.len() != 40 || !oid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::BadRef(oid.to_owned()));
+    }
+    // Abbreviations shorter than four characters are never accepted as revisions.
+    let min_length = min_length.clamp(4, oid.len());
+    let others: Vec<String> = data::find_objects_with_prefix(repo, &oid[..min_length])?
+        .into_iter()
+        .filter(|other| other != oid)
+        .collect();
+    let length = (min_length..oid.len())
+        .find(|length| {
+            !others
+                .iter()
+                .any(|other| other.starts_with(&oid[..*length]))
+        })
+        .unwrap_or(oid.len());
+    Ok(oid[..length].to_owned())
+}
+
+/// Find the full name of the ref that the specified name refers to, e.g. "refs/heads/master" for
+/// "master".
+fn find_ref(repo: &Repository, mut name: &str) -> Result<Option<String>> {
     if name == "@" {
         name = "HEAD";
     }
@@ -36,398 +589,1361 @@ pub fn get_oid(mut name: &str) -> Option<String> {
     ];
 
     for reference in refs_to_try {
-        let ref_value = data::get_ref(&PathBuf::from(DEFAULT_REPO), &reference, false);
-        if ref_value.value.is_some() {
-            // Name is a ref.
-            return data::get_ref(&PathBuf::from(DEFAULT_REPO), &reference, true).value;
+        if data::get_ref(repo, &reference, false)?.value.is_some() {
+            return Ok(Some(reference));
         }
     }
+    Ok(None)
+}
 
-    let is_hex = name.chars().all(|c| c.is_ascii_hexdigit());
-    if name.len() == 40 && is_hex {
-        // Name is an OID.
-        return Some(name.to_owned());
-    }
-
-    panic!("Unknown name {}", name);
+/// Return the entries in the reflog of the ref with the specified name, oldest first.
+pub fn get_reflog(repo: &Repository, name: &str) -> Result<Vec<data::ReflogEntry>> {
+    let reference = find_ref(repo, name)?.ok_or_else(|| Error::BadRef(name.to_owned()))?;
+    data::get_reflog(repo, &reference)
 }
 
-/// Set HEAD to the specified OID. This differs from checkout in that it follows symbolic refs.
-pub fn reset(oid: &str) {
+/// Point the specified ref, following symbolic refs, at the specified commit. The change is
+/// recorded with the specified message in the ref's reflog, and in HEAD's if HEAD points to it.
+pub(crate) fn update_ref_logged(
+    repo: &Repository,
+    reference: &str,
+    oid: &str,
+    message: &str,
+) -> Result<()> {
+    let (name, old) = data::resolve_ref(repo, reference)?;
     data::update_ref(
-        &PathBuf::from(DEFAULT_REPO),
-        "HEAD",
+        repo,
+        reference,
         &data::RefValue {
             symbolic: false,
             value: Some(oid.to_owned()),
         },
         true,
-    )
+    )?;
+
+    let entry = data::ReflogEntry {
+        old: old.value,
+        new: oid.to_owned(),
+        identity: get_reflog_identity(repo)?,
+        message: message.to_owned(),
+    };
+    data::append_reflog(repo, &name, &entry)?;
+    if name != "HEAD" && data::resolve_ref(repo, "HEAD")?.0 == name {
+        data::append_reflog(repo, "HEAD", &entry)?;
+    }
+    Ok(())
 }
 
-/// Merge the trees referenced by the HEAD (ref) and `other` (OID) commits.
-pub fn merge(other: &str) {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+/// Return who to record as making a ref update. Unlike committing, updating a ref doesn't need a
+/// configured identity, so a placeholder is used without one.
+fn get_reflog_identity(repo: &Repository) -> Result<String> {
+    let signature = match get_signature(repo, "committer") {
+        Err(Error::IdentityUnknown(_)) => {
+            let (time, offset) = get_current_date();
+            Signature {
+                name: "unknown".to_owned(),
+                email: "unknown".to_owned(),
+                time,
+                offset,
+            }
+        }
+        result => result?,
+    };
+    Ok(format_signature(&signature))
+}
 
-    let head = data::get_ref(default_repo, "HEAD", true)
-        .value
-        .expect("Failed to get HEAD OID");
+/// Delete the objects that can't be reached from any ref, reflog entry or the index, and return
+/// how many were deleted. Objects modified more recently than `grace_period` ago are kept, since a
+/// command may have just written them and not yet updated a ref to point at them.
+pub fn gc(repo: &Repository, grace_period: Duration) -> Result<usize> {
+    let mut roots: Vec<String> = data::get_refs(repo, None, true)?
+        .into_iter()
+        .filter_map(|(_, value)| value.value)
+        .collect();
+    roots.extend(data::get_ref(repo, "ORIG_HEAD", false)?.value);
+    for reference in data::get_reflog_names(repo)? {
+        for entry in data::get_reflog(repo, &reference)? {
+            roots.extend(entry.old);
+            roots.push(entry.new);
+        }
+    }
 
-    let merge_base = get_merge_base(other, &head).expect("Failed to get merge base");
+    let mut reachable: HashSet<String> = HashSet::new();
+    let mut commits = vec![];
+    for oid in &roots {
+        // Tags can point straight at trees and blobs.
+        match get_commit(repo, oid) {
+            Ok(_) => commits.push(oid.as_str()),
+            Err(Error::TypeMismatch { actual, .. }) if actual == "tree" => {
+                reachable.extend(get_objects_in_tree(repo, oid)?)
+            }
+            Err(Error::TypeMismatch { .. }) => {
+                reachable.insert(oid.to_owned());
+            }
+            // Nothing can be done to protect an object that's already missing.
+            Err(Error::ObjectNotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    reachable.extend(get_objects_in_commits(repo, commits)?);
+    let index = index::read(repo)?;
+    reachable.extend(index.entries().map(|(_, entry)| entry.oid.clone()));
+
+    let now = SystemTime::now();
+    let mut deleted = 0;
+    for (oid, modified) in data::get_object_oids(repo)? {
+        let age = now.duration_since(modified).unwrap_or_default();
+        if !reachable.contains(&oid) && age >= grace_period {
+            data::delete_object(repo, &oid)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
 
-    let other_commit = get_commit(default_repo, other);
+/// How much of the repository's state `reset` updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Only move the branch that HEAD points to.
+    Soft,
+    /// Also make the index match the commit, leaving the working tree alone.
+    Mixed,
+    /// Also make the working tree match the commit, discarding uncommitted changes.
+    Hard,
+}
 
-    // Handle fast-forward merge.
-    if merge_base == head {
-        read_tree(default_repo, &other_commit.tree);
+/// Set HEAD to the specified OID. This differs from checkout in that it follows symbolic refs. The
+/// previous value of HEAD is saved as ORIG_HEAD so that the reset can be undone. A hard reset
+/// refuses to overwrite untracked files unless `force` is set.
+pub fn reset(repo: &Repository, oid: &str, mode: ResetMode, force: bool) -> Result<()> {
+    let target_tree = get_tree(repo, Some(&get_commit(repo, oid)?.tree), None)?;
+    match mode {
+        ResetMode::Soft => {}
+        ResetMode::Mixed => reset_index(repo, &target_tree, None)?,
+        ResetMode::Hard => {
+            let overwrite = if force {
+                Overwrite::Everything
+            } else {
+                Overwrite::TrackedChanges
+            };
+            update_work_tree(repo, &get_head_tree(repo)?, &target_tree, overwrite)?;
+        }
+    }
+
+    save_orig_head(repo)?;
+    update_ref_logged(repo, "HEAD", oid, &format!("reset: moving to {}", oid))?;
+    // Moving HEAD abandons any merge in progress.
+    clear_merge_state(repo)
+}
+
+/// Set the index entries for the specified paths to their versions in the specified commit,
+/// removing those the commit doesn't contain. HEAD and the working tree are left alone.
+pub fn reset_paths(repo: &Repository, oid: &str, paths: &[PathBuf]) -> Result<()> {
+    let target_tree = get_tree(repo, Some(&get_commit(repo, oid)?.tree), None)?;
+    let paths: Vec<PathBuf> = paths.iter().map(|path| normalise_path(path)).collect();
+    save_orig_head(repo)?;
+    reset_index(repo, &target_tree, Some(&paths))?;
+    resolve_merge_conflicts(repo, &paths)
+}
+
+/// Make the index match the specified tree, optionally only for the paths beneath those specified.
+/// Entries that are already up to date keep their cached file metadata.
+fn reset_index(repo: &Repository, tree: &Tree, paths: Option<&[PathBuf]>) -> Result<()> {
+    let old_index = index::read(repo)?;
+    let is_under = |path: &Path, paths: &[PathBuf]| {
+        paths
+            .iter()
+            .any(|p| p.as_os_str().is_empty() || path.starts_with(p))
+    };
+    let is_selected = |path: &Path| paths.is_none_or(|paths| is_under(path, paths));
+
+    // Like git, a path that's in neither the tree nor the index is an error.
+    for p in paths.unwrap_or_default() {
+        let in_tree = tree
+            .iter()
+            .any(|(_, path, _)| is_under(Path::new(path), std::slice::from_ref(p)));
+        if !in_tree && old_index.paths_under(p).is_empty() {
+            return Err(Error::PathspecNoMatch(p.clone()));
+        }
+    }
+
+    let mut index = index::Index::default();
+    for (path, entry) in old_index.entries() {
+        if !is_selected(Path::new(path)) {
+            index.insert(Path::new(path), entry.clone());
+        }
+    }
+    for (oid, path, mode) in tree {
+        let path = Path::new(path);
+        if !is_selected(path) {
+            continue;
+        }
+        let entry = match old_index.get(path) {
+            Some(entry) if entry.oid == *oid && entry.mode == *mode => entry.clone(),
+            _ => index::IndexEntry::from_tree(oid, *mode),
+        };
+        index.insert(path, entry);
+    }
+
+    index::write(repo, &index)
+}
+
+/// Record the current value of HEAD as ORIG_HEAD before it's moved.
+fn save_orig_head(repo: &Repository) -> Result<()> {
+    if let Some(head) = data::get_ref(repo, "HEAD", true)?.value {
         data::update_ref(
-            default_repo,
-            "HEAD",
+            repo,
+            "ORIG_HEAD",
             &data::RefValue {
                 symbolic: false,
-                value: Some(other.to_owned()),
+                value: Some(head),
             },
-            true,
-        );
-        println!("Fast-forward merge, no need to commit");
-        return;
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+/// How a merge turned out.
+#[derive(Debug)]
+pub enum MergeOutcome {
+    /// HEAD was an ancestor of the merged commit, so it was simply moved forward to it.
+    FastForward,
+    /// The changes were merged into the working tree without conflicts, ready to be committed.
+    Clean,
+    /// The merge left conflicts in the specified files, which need resolving before committing.
+    Conflicts(Vec<(PathBuf, ConflictKind)>),
+}
+
+/// Merge the trees referenced by the HEAD and `name` commits, returning whether it was a
+/// fast-forward, merged cleanly, or left conflicts in the working directory. Unless `force` is set,
+/// the merge is refused if it would overwrite uncommitted changes.
+pub fn merge(
+    repo: &Repository,
+    name: &str,
+    options: &MergeOptions,
+    force: bool,
+) -> Result<MergeOutcome> {
+    if data::get_ref(repo, "MERGE_HEAD", false)?.value.is_some() {
+        return Err(Error::MergeInProgress);
     }
 
+    let other = &get_oid(repo, name)?;
+    let head = data::get_ref(repo, "HEAD", true)?
+        .value
+        .ok_or_else(|| Error::BadRef("HEAD".to_owned()))?;
+
+    let merge_base = get_merge_base(repo, other, &head)?
+        .ok_or_else(|| Error::NoCommonAncestor(other.to_owned()))?;
+
+    let other_commit = get_commit(repo, other)?;
+
+    // Handle fast-forward merge.
+    if merge_base == head {
+        read_tree(repo, &other_commit.tree, force)?;
+        save_orig_head(repo)?;
+        update_ref_logged(
+            repo,
+            "HEAD",
+            other,
+            &format!("merge {}: Fast-forward", name),
+        )?;
+        return Ok(MergeOutcome::FastForward);
+    }
+
+    let base_commit = get_commit(repo, &merge_base)?;
+    let head_commit = get_commit(repo, &head)?;
+
+    let conflicts = read_tree_merged(
+        repo,
+        &base_commit.tree,
+        &head_commit.tree,
+        &other_commit.tree,
+        options,
+        force,
+    )?;
+    save_orig_head(repo)?;
+
     // Create a MERGE_HEAD ref for use when setting the parent commits of the merge commit.
     data::update_ref(
-        default_repo,
+        repo,
         "MERGE_HEAD",
         &data::RefValue {
             symbolic: false,
             value: Some(other.to_owned()),
         },
         true,
-    );
+    )?;
 
-    let base_commit = get_commit(default_repo, &merge_base);
-    let head_commit = get_commit(default_repo, &head);
+    let kind = if is_branch(repo, name)? {
+        "branch"
+    } else {
+        "commit"
+    };
+    fs::write(
+        repo.git_path(MERGE_MSG),
+        format!("Merge {} '{}'", kind, name),
+    )?;
 
-    read_tree_merged(&base_commit.tree, &head_commit.tree, &other_commit.tree);
-    println!("Merged in working tree\nPlease commit");
+    if !conflicts.is_empty() {
+        return Ok(MergeOutcome::Conflicts(conflicts));
+    }
+    Ok(MergeOutcome::Clean)
 }
 
-/// Find the common ancestor of the specified OIDs.
-pub fn get_merge_base(oid1: &str, oid2: &str) -> Option<String> {
-    use std::iter::FromIterator;
-
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+/// Abandon the merge in progress, restoring the paths that the merge changed to their versions in
+/// HEAD's tree. Like git's `reset --merge`, unstaged changes to other paths are kept, and the abort
+/// is refused if a path the merge changed cleanly has since been edited without being staged.
+pub fn merge_abort(repo: &Repository) -> Result<()> {
+    if data::get_ref(repo, "MERGE_HEAD", false)?.value.is_none() {
+        return Err(Error::NoMergeInProgress);
+    }
 
-    let parents1 = HashSet::<String>::from_iter(get_commits_and_parents(default_repo, vec![oid1]));
-    for oid in get_commits_and_parents(default_repo, vec![oid2]) {
-        if parents1.contains(&oid) {
-            return Some(oid);
+    // Edits made while resolving a conflict are discarded along with it, so stage them to let the
+    // conflicted paths be restored like any other path the merge changed.
+    let mut index = index::read(repo)?;
+    for (path, _) in get_merge_conflicts(repo)? {
+        if fs::symlink_metadata(repo.work_tree_path(&path)).is_ok_and(|m| !m.is_dir()) {
+            stage_file(repo, &mut index, &path)?;
+        } else {
+            index.remove(&path);
         }
     }
+    index::write(repo, &index)?;
+
+    // The merge refused to start with anything staged, so the paths where the index differs from
+    // HEAD are exactly those it changed.
+    update_work_tree(
+        repo,
+        &index.to_tree(),
+        &get_head_tree(repo)?,
+        Overwrite::Nothing,
+    )?;
+    clear_merge_state(repo)
+}
+
+/// Commit the merge in progress once its conflicts have been resolved, using the default merge
+/// message.
+pub fn merge_continue(repo: &Repository) -> Result<String> {
+    if data::get_ref(repo, "MERGE_HEAD", false)?.value.is_none() {
+        return Err(Error::NoMergeInProgress);
+    }
+    let message = get_merge_message(repo)?.ok_or(Error::MissingCommitMessage)?;
+    commit(repo, &message)
+}
+
+/// Return the default message for the merge in progress, if there is one.
+pub fn get_merge_message(repo: &Repository) -> Result<Option<String>> {
+    let path = repo.git_path(MERGE_MSG);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(path)?))
+}
+
+/// Forget the merge in progress, leaving the index and working tree as they are.
+fn clear_merge_state(repo: &Repository) -> Result<()> {
+    if data::get_ref(repo, "MERGE_HEAD", false)?.value.is_some() {
+        data::delete_ref(repo, "MERGE_HEAD", false)?;
+    }
+    write_merge_conflicts(repo, &[])?;
+    let message_path = repo.git_path(MERGE_MSG);
+    if message_path.is_file() {
+        fs::remove_file(message_path)?;
+    }
+    Ok(())
+}
 
-    // XXX: This should probably panic instead of returning an Option as
-    // every commit should have a common ancestory, even if it's the
-    // initial commit.
-    None
+/// Find the best common ancestor of the specified OIDs, one that no other common ancestor
+/// descends from.
+pub fn get_merge_base(repo: &Repository, oid1: &str, oid2: &str) -> Result<Option<String>> {
+    let parents1 = get_commits_and_parents(repo, vec![oid1])?;
+
+    // The walk lists children before their parents, so the first common ancestor it reaches has
+    // no other below it. Commits with unrelated histories have no common ancestor.
+    let history2 = walk_revisions(repo, &[oid2.to_owned()], &[], &WalkOptions::default())?;
+    Ok(history2
+        .into_iter()
+        .map(|(oid, _)| oid)
+        .find(|oid| parents1.contains(oid)))
 }
 
-pub fn is_ancestor_of(repo_path: &Path, commit: &str, maybe_ancestor: &str) -> bool {
-    return get_commits_and_parents(repo_path, vec![commit]).contains(&maybe_ancestor.to_owned());
+pub fn is_ancestor_of(repo: &Repository, commit: &str, maybe_ancestor: &str) -> Result<bool> {
+    Ok(get_commits_and_parents(repo, vec![commit])?.contains(maybe_ancestor))
 }
 
-pub fn create_tag(name: &str, oid: &str) {
+pub fn create_tag(repo: &Repository, name: &str, oid: &str) -> Result<()> {
     let ref_path = format!("refs/tags/{}", name);
     data::update_ref(
-        &PathBuf::from(DEFAULT_REPO),
+        repo,
         &ref_path,
         &data::RefValue {
             symbolic: false,
             value: Some(oid.to_owned()),
         },
         true,
-    );
+    )
 }
 
-pub fn create_branch(name: &str, oid: &str) {
+pub fn create_branch(repo: &Repository, name: &str, oid: &str) -> Result<()> {
     let ref_path = format!("refs/heads/{}", name);
-    data::update_ref(
-        &PathBuf::from(DEFAULT_REPO),
+    update_ref_logged(
+        repo,
         &ref_path,
-        &data::RefValue {
-            symbolic: false,
-            value: Some(oid.to_owned()),
-        },
-        true,
-    );
+        oid,
+        &format!("branch: Created from {}", oid),
+    )
 }
 
-pub fn get_branch_name() -> Option<String> {
-    let head = data::get_ref(&PathBuf::from(DEFAULT_REPO), "HEAD", false);
+pub fn get_branch_name(repo: &Repository) -> Result<Option<String>> {
+    let head = data::get_ref(repo, "HEAD", false)?;
     if !head.symbolic {
-        return None;
+        return Ok(None);
+    }
+    let head_ref = head.value.ok_or_else(|| Error::BadRef("HEAD".to_owned()))?;
+    if !head_ref.starts_with("refs/heads/") {
+        return Err(Error::BadRef(head_ref));
     }
-    let head_ref = head.value.expect("HEAD does not contain a symbolic ref");
-    assert!(head_ref.starts_with("refs/heads/"));
     // The original Python code uses os.path.relpath. Is there any problem with just doing a string
     // replacement?
-    Some(head_ref.replacen("refs/heads/", "", 1))
+    Ok(Some(head_ref.replacen("refs/heads/", "", 1)))
 }
 
-pub fn get_branch_names() -> Vec<String> {
+pub fn get_branch_names(repo: &Repository) -> Result<Vec<String>> {
     let mut branch_names = vec![];
-    for (ref_name, _ref_value) in
-        data::get_refs(&PathBuf::from(DEFAULT_REPO), Some("refs/heads/"), true)
-    {
+    for (ref_name, _ref_value) in data::get_refs(repo, Some("refs/heads/"), true)? {
         branch_names.push(ref_name.replace("refs/heads/", ""));
     }
-    branch_names
+    Ok(branch_names)
 }
 
-fn is_branch(branch: &str) -> bool {
-    data::get_ref(
-        &PathBuf::from(DEFAULT_REPO),
-        format!("refs/heads/{}", branch).as_str(),
-        true,
+fn is_branch(repo: &Repository, branch: &str) -> Result<bool> {
+    Ok(
+        data::get_ref(repo, format!("refs/heads/{}", branch).as_str(), true)?
+            .value
+            .is_some(),
     )
-    .value
-    .is_some()
 }
 
-/// Store the contents of the current directory to the object database, creates a commit object and
-/// updates the HEAD.
-pub fn commit(message: &str) -> Option<String> {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+/// Stage the current contents of the specified files, or of every file beneath the specified
+/// directories. Staged files that no longer exist are removed from the index.
+pub fn add(repo: &Repository, paths: &[PathBuf]) -> Result<()> {
+    let mut index = index::read(repo)?;
+    let mut ignore = Ignore::new(repo)?;
+    let mut staged = vec![];
+
+    for path in paths {
+        let path = normalise_path(path);
+        let walk_root = repo.work_tree_path(&path);
+
+        let mut matched = false;
+        if fs::symlink_metadata(&walk_root).is_ok() {
+            let mut entries = walkdir::WalkDir::new(walk_root)
+                .follow_root_links(false)
+                .into_iter();
+            while let Some(entry) = entries.next() {
+                let entry = entry?;
+                let is_dir = entry.file_type().is_dir();
+                let file_path = repo.work_tree_relative_path(entry.path())?;
+                if ignore.is_ignored(&file_path, is_dir)? {
+                    if is_dir {
+                        entries.skip_current_dir();
+                    }
+                    continue;
+                }
+                if is_dir {
+                    continue;
+                }
+                stage_file(repo, &mut index, &file_path)?;
+                staged.push(file_path);
+                matched = true;
+            }
+        }
 
-    let current_dir = std::env::current_dir().expect("Failed to get current directory");
-    let tree_oid = write_tree(&current_dir).expect("Failed to write tree");
+        for staged_path in index.paths_under(&path) {
+            if fs::symlink_metadata(repo.work_tree_path(&staged_path)).is_err() {
+                index.remove(&staged_path);
+                staged.push(staged_path);
+                matched = true;
+            }
+        }
 
-    let mut commit = String::new();
-    commit.push_str(format!("tree {}\n", tree_oid).as_str());
-    if let Some(head) = data::get_ref(default_repo, "HEAD", true).value {
-        commit.push_str(format!("parent {}\n", head).as_str());
+        if !matched {
+            return Err(Error::PathspecNoMatch(path));
+        }
     }
-    if let Some(merge_head) = data::get_ref(default_repo, "MERGE_HEAD", true).value {
-        commit.push_str(format!("parent {}\n", merge_head).as_str());
-        data::delete_ref("MERGE_HEAD", false);
+
+    index::write(repo, &index)?;
+    resolve_merge_conflicts(repo, &staged)
+}
+
+/// Remove the specified paths from the index. Unless `cached` is set, the files are also removed
+/// from the working directory.
+pub fn remove(repo: &Repository, paths: &[PathBuf], cached: bool) -> Result<()> {
+    let mut index = index::read(repo)?;
+    let mut removed = vec![];
+
+    for path in paths {
+        let path = normalise_path(path);
+        let staged_paths = index.paths_under(&path);
+        if staged_paths.is_empty() {
+            return Err(Error::PathspecNoMatch(path));
+        }
+
+        for staged_path in staged_paths {
+            index.remove(&staged_path);
+            let file_path = repo.work_tree_path(&staged_path);
+            if !cached && fs::symlink_metadata(&file_path).is_ok_and(|m| !m.is_dir()) {
+                fs::remove_file(file_path)?;
+            }
+            removed.push(staged_path);
+        }
     }
-    commit.push('\n');
-    commit.push_str(message);
 
-    let commit_oid = data::hash_object(&commit.as_bytes().to_vec(), "commit");
-    data::update_ref(
-        default_repo,
-        "HEAD",
-        &data::RefValue {
-            symbolic: false,
-            value: Some(commit_oid.to_owned()),
+    index::write(repo, &index)?;
+    resolve_merge_conflicts(repo, &removed)
+}
+
+/// Hash the specified file, relative to the working tree, into the object store and record it in
+/// the index.
+fn stage_file(repo: &Repository, index: &mut index::Index, path: &Path) -> Result<()> {
+    let (contents, metadata) = read_work_tree_file(&repo.work_tree_path(path))?;
+    let oid = data::hash_object(repo, &contents, "blob")?;
+    index.insert(path, index::IndexEntry::new(&oid, &metadata));
+    Ok(())
+}
+
+/// Read a file in the working tree as it's stored in a blob, along with its metadata. Symlinks
+/// aren't followed; their blob holds the path they point to.
+pub(crate) fn read_work_tree_file(file_path: &Path) -> Result<(Vec<u8>, fs::Metadata)> {
+    let metadata = fs::symlink_metadata(file_path)?;
+    let contents = if metadata.file_type().is_symlink() {
+        let target = fs::read_link(file_path)?;
+        os_str_to_bytes(target.as_os_str())
+            .ok_or(Error::InvalidPath(target.clone()))?
+            .to_vec()
+    } else {
+        fs::read(file_path)?
+    };
+    Ok((contents, metadata))
+}
+
+/// Write a blob to the working tree as a file with the specified mode, replacing any file already
+/// there, and return the metadata of the result.
+fn write_work_tree_file(file_path: &Path, contents: &[u8], mode: u32) -> Result<fs::Metadata> {
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::symlink_metadata(file_path).is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(file_path)?;
+    }
+
+    if mode == MODE_SYMLINK {
+        create_symlink(contents, file_path)?;
+    } else {
+        fs::write(file_path, contents)?;
+        set_executable(file_path, mode == MODE_EXECUTABLE)?;
+    }
+
+    Ok(fs::symlink_metadata(file_path)?)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &[u8], path: &Path) -> Result<()> {
+    let target = bytes_to_os_string(target).ok_or_else(|| Error::InvalidPath(path.to_owned()))?;
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+/// Platforms without symlinks get a plain file containing the link's target, as git does.
+#[cfg(not(unix))]
+fn create_symlink(target: &[u8], path: &Path) -> Result<()> {
+    fs::write(path, target)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+    // Allow execution by whoever is allowed to read the file.
+    permissions.set_mode(if executable {
+        mode | (mode & 0o444) >> 2
+    } else {
+        mode & !0o111
+    });
+    fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Store the contents of the index in the object database, creates a commit object and updates the
+/// HEAD.
+pub fn commit(repo: &Repository, message: &str) -> Result<String> {
+    let index = index::read(repo)?;
+    let head = data::get_ref(repo, "HEAD", true)?.value;
+    let merge_head = data::get_ref(repo, "MERGE_HEAD", true)?.value;
+    if merge_head.is_some() {
+        check_conflicts_resolved(repo, &index)?;
+    }
+    let tree_oid = write_tree_from_index(repo, &index)?;
+
+    let action = if merge_head.is_some() {
+        "commit (merge)"
+    } else if head.is_none() {
+        "commit (initial)"
+    } else {
+        "commit"
+    };
+    let parents = head.into_iter().chain(merge_head).collect();
+
+    let commit_oid = write_commit(
+        repo,
+        &Commit {
+            tree: tree_oid,
+            parents,
+            author: Some(get_signature(repo, "author")?),
+            committer: Some(get_signature(repo, "committer")?),
+            extra_headers: vec![],
+            message: message.to_owned(),
         },
-        true,
-    );
-    Some(commit_oid)
+    )?;
+    clear_merge_state(repo)?;
+    let subject = message.lines().next().unwrap_or_default();
+    update_ref_logged(
+        repo,
+        "HEAD",
+        &commit_oid,
+        &format!("{}: {}", action, subject),
+    )?;
+    Ok(commit_oid)
 }
 
-pub fn get_commit(repo_path: &Path, oid: &str) -> Commit {
-    let commit_data = data::get_object(repo_path, oid, Some("commit"));
-    let commit = String::from_utf8(commit_data).expect("Commit contains invalid data");
-    let mut commit_lines = commit.lines();
+/// Add a commit to the object store and return its OID.
+pub fn write_commit(repo: &Repository, commit: &Commit) -> Result<String> {
+    let mut data = String::new();
+    data.push_str(format!("tree {}\n", commit.tree).as_str());
+    for parent in &commit.parents {
+        data.push_str(format!("parent {}\n", parent).as_str());
+    }
+    if let Some(author) = &commit.author {
+        data.push_str(format!("author {}\n", format_signature(author)).as_str());
+    }
+    if let Some(committer) = &commit.committer {
+        data.push_str(format!("committer {}\n", format_signature(committer)).as_str());
+    }
+    for (key, value) in &commit.extra_headers {
+        // Multi-line values are continued on lines beginning with a space.
+        data.push_str(format!("{} {}\n", key, value.replace('\n', "\n ")).as_str());
+    }
+    data.push('\n');
+    data.push_str(&commit.message);
+
+    data::hash_object(repo, data.as_bytes(), "commit")
+}
+
+pub fn get_commit(repo: &Repository, oid: &str) -> Result<Commit> {
+    let commit_data = data::get_object(repo, oid, Some("commit"))?;
+    let commit = String::from_utf8(commit_data)
+        .map_err(|_| Error::corrupt_object(oid, "commit is not valid UTF-8"))?;
+    let (headers, message) = commit.split_once("\n\n").unwrap_or((&commit, ""));
 
     let mut tree_oid: Option<String> = None;
     let mut parent_oids: Vec<String> = Vec::new();
+    let mut author = None;
+    let mut committer = None;
+    let mut extra_headers: Vec<(String, String)> = Vec::new();
+
+    for line in headers.lines() {
+        // Lines beginning with a space continue the value of the previous header.
+        if let Some(continuation) = line.strip_prefix(' ') {
+            let (_, value) = extra_headers
+                .last_mut()
+                .ok_or_else(|| Error::corrupt_object(oid, "unexpected header continuation"))?;
+            value.push('\n');
+            value.push_str(continuation);
+            continue;
+        }
 
-    for line in commit_lines.by_ref().take_while(|l| !l.is_empty()) {
-        let mut split_line = line.split_whitespace();
-        let key = split_line
-            .next()
-            .expect("Failed to retrieve key from commit header");
-        let oid = split_line.next();
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
         match key {
-            "tree" => tree_oid = oid.map(ToOwned::to_owned),
-            "parent" => {
-                if let Some(oid) = oid {
-                    parent_oids.push(oid.to_string())
-                }
+            "" => return Err(Error::corrupt_object(oid, "empty commit header")),
+            "tree" => tree_oid = Some(value.to_owned()),
+            "parent" => parent_oids.push(value.to_owned()),
+            "author" => {
+                author = Some(
+                    parse_signature(value)
+                        .ok_or_else(|| Error::corrupt_object(oid, "malformed author header"))?,
+                )
+            }
+            "committer" => {
+                committer = Some(
+                    parse_signature(value)
+                        .ok_or_else(|| Error::corrupt_object(oid, "malformed committer header"))?,
+                )
             }
-            _ => panic!("Unrecognised commit header type"),
+            _ => extra_headers.push((key.to_owned(), value.to_owned())),
         }
     }
 
-    let message: String = commit_lines.collect();
-
     if let Some(tree_oid) = tree_oid {
-        Commit {
+        Ok(Commit {
             tree: tree_oid,
             parents: parent_oids,
-            message,
-        }
+            author,
+            committer,
+            extra_headers,
+            message: message.to_owned(),
+        })
     } else {
-        panic!("Commit did not contain tree header")
+        Err(Error::corrupt_object(oid, "missing tree header"))
     }
 }
 
-/// Traverses a directory hierarchy, adding any files or directories to the object store.
-pub fn write_tree(path: &Path) -> Option<String> {
-    if is_ignored(path) {
+/// Work out who is making a commit in the specified role ("author" or "committer") from the
+/// UGIT_<ROLE>_NAME, UGIT_<ROLE>_EMAIL and UGIT_<ROLE>_DATE environment variables, falling back on
+/// the user.name and user.email config variables and the current time.
+fn get_signature(repo: &Repository, role: &str) -> Result<Signature> {
+    let var = |field: &str| env::var(format!("UGIT_{}_{}", role.to_uppercase(), field)).ok();
+
+    let name = match var("NAME") {
+        Some(name) => Some(name),
+        None => config::get(repo, "user.name")?,
+    };
+    let email = match var("EMAIL") {
+        Some(email) => Some(email),
+        None => config::get(repo, "user.email")?,
+    };
+    let (name, email) = match (name, email) {
+        (Some(name), Some(email)) if !name.is_empty() => (name, email),
+        _ => return Err(Error::IdentityUnknown(role.to_owned())),
+    };
+
+    let (time, offset) = match var("DATE") {
+        Some(date) => parse_date(&date).ok_or(Error::InvalidDate(date))?,
+        None => get_current_date(),
+    };
+
+    Ok(Signature {
+        name,
+        email,
+        time,
+        offset,
+    })
+}
+
+/// Return the current time in seconds since the epoch and the local offset from UTC in minutes.
+fn get_current_date() -> (i64, i32) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64);
+    (time, get_local_offset(time))
+}
+
+/// Format a signature as it's stored in a commit header, e.g.
+/// "A U Thor <author@example.com> 1700000000 +0100".
+fn format_signature(signature: &Signature) -> String {
+    format!(
+        "{} <{}> {} {}",
+        signature.name,
+        signature.email,
+        signature.time,
+        format_offset(signature.offset)
+    )
+}
+
+/// Format an offset from UTC in minutes as e.g. "+0100".
+pub fn format_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset = offset.abs();
+    format!("{}{:02}{:02}", sign, offset / 60, offset % 60)
+}
+
+fn parse_signature(value: &str) -> Option<Signature> {
+    let (name, rest) = value.split_once('<')?;
+    let (email, date) = rest.split_once('>')?;
+    let (time, offset) = parse_date(date)?;
+    Some(Signature {
+        name: name.trim().to_owned(),
+        email: email.to_owned(),
+        time,
+        offset,
+    })
+}
+
+/// Parse a date of the form "<seconds since the epoch> <offset from UTC>", e.g.
+/// "1700000000 +0100", into a timestamp and an offset in minutes. A missing offset means UTC.
+fn parse_date(date: &str) -> Option<(i64, i32)> {
+    let mut parts = date.split_whitespace();
+    let time = parts.next()?.trim_start_matches('@').parse().ok()?;
+    let offset = match parts.next() {
+        Some(offset) => {
+            // Work on bytes, since a corrupt header may hold anything.
+            let (sign, digits) = match offset.as_bytes() {
+                [b'+', digits @ ..] => (1, digits),
+                [b'-', digits @ ..] => (-1, digits),
+                _ => return None,
+            };
+            if digits.len() != 4 || !digits.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+            let digit = |i: usize| i32::from(digits[i] - b'0');
+            sign * ((digit(0) * 10 + digit(1)) * 60 + digit(2) * 10 + digit(3))
+        }
+        None => 0,
+    };
+    if parts.next().is_some() {
         return None;
     }
+    Some((time, offset))
+}
 
-    let mut entries: Vec<(&str, String, ffi::OsString)> = vec![];
-    for dir_entry in fs::read_dir(&path).expect("Failed to read directory") {
-        let path = dir_entry.expect("Failed to read directory entry").path();
-        if path.is_file() {
-            let contents = std::fs::read(&path).expect("Failed to read file contents");
-            let oid = data::hash_object(&contents, "blob");
-            let file_name = path
-                .file_name()
-                .expect("Failed to get file name for path")
-                .to_owned();
-            entries.push(("blob", oid, file_name));
-        } else if path.is_dir() {
-            if let Some(oid) = write_tree(&path) {
-                let dir_name = path
-                    .file_name()
-                    .expect("Failed to get directory name for path")
-                    .to_owned();
-                entries.push(("tree", oid, dir_name));
+/// Return the local timezone's offset from UTC in minutes at the specified time.
+#[cfg(unix)]
+fn get_local_offset(time: i64) -> i32 {
+    let time = time as libc::time_t;
+    // SAFETY: localtime_r only writes to the tm struct we give it, and a zeroed tm is valid.
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return 0;
+        }
+        (tm.tm_gmtoff / 60) as i32
+    }
+}
+
+#[cfg(not(unix))]
+fn get_local_offset(_time: i64) -> i32 {
+    0
+}
+
+/// Traverses a directory hierarchy within the working tree, adding any files or directories that
+/// aren't ignored to the object store.
+pub fn write_tree(repo: &Repository, path: &Path) -> Result<Option<String>> {
+    write_tree_recursive(repo, &mut Ignore::new(repo)?, path)
+}
+
+fn write_tree_recursive(
+    repo: &Repository,
+    ignore: &mut Ignore,
+    path: &Path,
+) -> Result<Option<String>> {
+    if ignore.is_ignored(&repo.work_tree_relative_path(path)?, true)? {
+        return Ok(None);
+    }
+
+    let mut entries: Vec<(u32, String, ffi::OsString)> = vec![];
+    for dir_entry in fs::read_dir(path)? {
+        let path = dir_entry?.path();
+        let name = path
+            .file_name()
+            .ok_or_else(|| Error::InvalidPath(path.clone()))?
+            .to_owned();
+        if fs::symlink_metadata(&path)?.is_dir() {
+            if let Some(oid) = write_tree_recursive(repo, ignore, &path)? {
+                entries.push((MODE_TREE, oid, name));
+            }
+        } else if !ignore.is_ignored(&repo.work_tree_relative_path(&path)?, false)? {
+            let (contents, metadata) = read_work_tree_file(&path)?;
+            let oid = data::hash_object(repo, &contents, "blob")?;
+            entries.push((index::get_mode(&metadata), oid, name));
+        }
+    }
+
+    hash_tree(repo, entries).map(Some)
+}
+
+/// Write the tree objects needed to represent the contents of the index and return the OID of the
+/// root tree.
+pub fn write_tree_from_index(repo: &Repository, index: &index::Index) -> Result<String> {
+    let entries: Vec<(PathBuf, String, u32)> = index
+        .entries()
+        .map(|(path, entry)| (PathBuf::from(path), entry.oid.clone(), entry.mode))
+        .collect();
+    write_tree_from_entries(repo, &entries)
+}
+
+fn write_tree_from_entries(
+    repo: &Repository,
+    entries: &[(PathBuf, String, u32)],
+) -> Result<String> {
+    let mut tree_entries: Vec<(u32, String, ffi::OsString)> = vec![];
+    let mut subdirectories: BTreeMap<ffi::OsString, Vec<(PathBuf, String, u32)>> = BTreeMap::new();
+
+    for (path, oid, mode) in entries {
+        let mut components = path.components();
+        let name = components
+            .next()
+            .ok_or_else(|| Error::InvalidPath(path.clone()))?
+            .as_os_str()
+            .to_owned();
+        let rest = components.as_path();
+        if rest == Path::new("") {
+            tree_entries.push((*mode, oid.clone(), name));
+        } else {
+            subdirectories
+                .entry(name)
+                .or_default()
+                .push((rest.to_path_buf(), oid.clone(), *mode));
+        }
+    }
+
+    for (name, subdirectory_entries) in subdirectories {
+        let oid = write_tree_from_entries(repo, &subdirectory_entries)?;
+        tree_entries.push((MODE_TREE, oid, name));
+    }
+
+    hash_tree(repo, tree_entries)
+}
+
+/// Serialise the specified tree entries and add the resulting tree object to the object store.
+/// Entries are written in git's format, "mode name\0" followed by the raw OID, and in git's order,
+/// which sorts subtrees as though their names ended with a "/".
+fn hash_tree(repo: &Repository, entries: Vec<(u32, String, ffi::OsString)>) -> Result<String> {
+    let mut entries = entries
+        .into_iter()
+        .map(|(mode, oid, name)| {
+            let name = os_str_to_bytes(&name)
+                .ok_or_else(|| Error::InvalidPath(PathBuf::from(&name)))?
+                .to_vec();
+            Ok((mode, oid, name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by_cached_key(|(mode, _, name)| {
+        let mut key = name.clone();
+        if *mode == MODE_TREE {
+            key.push(b'/');
+        }
+        key
+    });
+
+    let mut tree = vec![];
+    for (mode, oid, name) in entries {
+        tree.extend_from_slice(format!("{:o} ", mode).as_bytes());
+        tree.extend_from_slice(&name);
+        tree.push(0);
+        tree.extend_from_slice(&from_hex(&oid)?);
+    }
+    data::hash_object(repo, &tree, "tree")
+}
+
+/// Retrieves the tree with the specified OID from the object store and writes it to the working
+/// tree and the index. Unless `force` is set, nothing is changed if that would overwrite
+/// uncommitted changes.
+pub fn read_tree(repo: &Repository, tree_oid: &str, force: bool) -> Result<()> {
+    let head_tree = get_head_tree(repo)?;
+    let tree = get_tree(repo, Some(tree_oid), None)?;
+    update_work_tree(repo, &head_tree, &tree, Overwrite::unless(force))
+}
+
+/// Write the result of merging the specified trees to the working tree and the index, returning the
+/// paths that were left with conflicts. Unless `force` is set, nothing is changed if there are
+/// staged changes or the merge would overwrite uncommitted changes.
+fn read_tree_merged(
+    repo: &Repository,
+    base: &str,
+    head: &str,
+    other: &str,
+    options: &MergeOptions,
+    force: bool,
+) -> Result<Vec<(PathBuf, ConflictKind)>> {
+    let base_tree = get_tree(repo, Some(base), None)?;
+    let head_tree = get_tree(repo, Some(head), None)?;
+    let other_tree = get_tree(repo, Some(other), None)?;
+
+    // The merge commit is made from the index, so anything already staged would end up in it.
+    if !force {
+        let staged = diff::get_changed_files(&head_tree, &index::read(repo)?.to_tree(), &[]);
+        if !staged.is_empty() {
+            return Err(Error::WouldOverwrite(
+                staged.into_iter().map(|(path, _, _)| path.into()).collect(),
+            ));
+        }
+    }
+
+    let mut merged_tree = vec![];
+    let mut conflicts = vec![];
+    for (path, (merged, conflict)) in
+        diff::merge_trees(repo, &base_tree, &head_tree, &other_tree, options)?
+    {
+        // Stage the merged result, conflicts and all, so that the merge commit picks it up.
+        if let Some((contents, mode)) = merged {
+            let oid = data::hash_object(repo, &contents, "blob")?;
+            merged_tree.push((oid, path.clone(), mode));
+        }
+        if let Some(kind) = conflict {
+            conflicts.push((PathBuf::from(path), kind));
+        }
+    }
+
+    update_work_tree(repo, &head_tree, &merged_tree, Overwrite::unless(force))?;
+    write_merge_conflicts(repo, &conflicts)?;
+
+    Ok(conflicts)
+}
+
+/// What uncommitted work `update_work_tree` may discard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Overwrite {
+    /// Refuse to change anything if uncommitted changes or untracked files are in the way.
+    Nothing,
+    /// Discard changes to tracked files, but not untracked files that are in the way.
+    TrackedChanges,
+    /// Discard anything that's in the way.
+    Everything,
+}
+
+impl Overwrite {
+    /// Discard nothing, unless forced to discard everything.
+    fn unless(force: bool) -> Overwrite {
+        if force {
+            Overwrite::Everything
+        } else {
+            Overwrite::Nothing
+        }
+    }
+}
+
+/// Move the index and working tree from the `current` tree to the `target` tree. Normally only the
+/// paths that differ between the trees are touched, keeping uncommitted changes to other paths,
+/// and nothing is changed if a path that differs has uncommitted changes, unless they already
+/// match `target`. If `overwrite` allows tracked changes to be discarded, every path is made to
+/// match `target` instead. Untracked files not in `target` are always left alone.
+fn update_work_tree(
+    repo: &Repository,
+    current: &Tree,
+    target: &Tree,
+    overwrite: Overwrite,
+) -> Result<()> {
+    let mut index = index::read(repo)?;
+    let index_tree = index.to_tree();
+    let working_tree = get_working_tree(repo)?;
+
+    let mut updates = vec![];
+    let mut overwritten = vec![];
+    for (path, objects) in diff::compare_trees(&[current, target, &index_tree, &working_tree]) {
+        let (current, target, staged, work) = (&objects[0], &objects[1], &objects[2], &objects[3]);
+        if (target.is_none() && staged.is_none()) || (staged == target && work == target) {
+            continue;
+        }
+        let untracked = current.is_none() && staged.is_none() && work.is_some();
+        match overwrite {
+            Overwrite::Nothing if current == target => continue,
+            // A file that already has the target's contents loses nothing by being updated, as
+            // long as nothing else is staged for it.
+            Overwrite::Nothing if work == target && (staged == current || staged == target) => {}
+            Overwrite::Nothing if staged != current || work != staged => {
+                overwritten.push(PathBuf::from(path));
+                continue;
             }
+            Overwrite::TrackedChanges if untracked => {
+                overwritten.push(PathBuf::from(path));
+                continue;
+            }
+            _ => {}
+        }
+        updates.push((PathBuf::from(path), target.clone()));
+    }
+
+    // A file being written can't share its name with a directory left in the working tree, nor
+    // be inside a directory whose name is taken by a file left there.
+    let removed: HashSet<&Path> = updates
+        .iter()
+        .filter(|(_, target)| target.is_none())
+        .map(|(path, _)| path.as_path())
+        .collect();
+    let remaining: HashSet<&Path> = working_tree
+        .iter()
+        .map(|(_, path, _)| Path::new(path))
+        .filter(|path| !removed.contains(path))
+        .collect();
+    let remaining_dirs: HashSet<&Path> = remaining
+        .iter()
+        .flat_map(|path| path.ancestors().skip(1))
+        .collect();
+    let mut blockers: Vec<PathBuf> = vec![];
+    for (path, _) in updates.iter().filter(|(_, target)| target.is_some()) {
+        if remaining_dirs.contains(path.as_path()) {
+            blockers.extend(
+                remaining
+                    .iter()
+                    .filter(|p| p.starts_with(path))
+                    .map(|p| p.to_path_buf()),
+            );
         }
+        blockers.extend(
+            path.ancestors()
+                .skip(1)
+                .filter(|p| remaining.contains(p))
+                .map(Path::to_path_buf),
+        );
+    }
+    if overwrite == Overwrite::Everything {
+        updates.extend(blockers.into_iter().map(|path| (path, None)));
+    } else {
+        overwritten.extend(blockers);
+    }
+
+    if !overwritten.is_empty() {
+        overwritten.sort();
+        overwritten.dedup();
+        return Err(Error::WouldOverwrite(overwritten));
     }
 
-    let mut tree = String::new();
-    for (object_type, oid, path) in entries {
-        let path_string = path.to_str().expect("Failed to convert path to string");
-        let tree_row = format!("{} {} {}\n", object_type, oid, path_string);
-        tree.push_str(tree_row.as_str());
+    // Remove files first, in case a file is being replaced by a directory of the same name.
+    updates.sort_by_key(|(_, target)| target.is_some());
+    for (path, target) in updates {
+        let file_path = repo.work_tree_path(&path);
+        match target {
+            Some((oid, mode)) => {
+                let contents = data::get_object(repo, &oid, Some("blob"))?;
+                let metadata = write_work_tree_file(&file_path, &contents, mode)?;
+                index.insert(&path, index::IndexEntry::new(&oid, &metadata));
+            }
+            None => {
+                index.remove(&path);
+                remove_work_tree_file(repo, &file_path)?;
+            }
+        }
     }
-    let oid = data::hash_object(&tree.into_bytes(), "tree");
 
-    Some(oid)
+    index::write(repo, &index)
 }
 
-/// Retrieves the tree with the specified OID from the object store and writes it to the current
-/// directory.
-pub fn read_tree(repo_path: &Path, tree_oid: &str) {
-    let current_dir = env::current_dir().expect("Failed to get current directory");
-    empty_directory(&current_dir);
+/// Delete a file from the working tree, along with any directories left empty.
+fn remove_work_tree_file(repo: &Repository, file_path: &Path) -> Result<()> {
+    if fs::symlink_metadata(file_path).is_ok_and(|m| !m.is_dir()) {
+        fs::remove_file(file_path)?;
+    }
+    for dir in file_path.ancestors().skip(1) {
+        if dir == repo.work_tree || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Return the tree of the commit that HEAD points to, which is empty before the first commit.
+pub fn get_head_tree(repo: &Repository) -> Result<Tree> {
+    let tree = match data::get_ref(repo, "HEAD", true)?.value {
+        Some(oid) => Some(get_commit(repo, &oid)?.tree),
+        None => None,
+    };
+    get_tree(repo, tree.as_deref(), None)
+}
 
-    let tree = get_tree(repo_path, Some(tree_oid), None);
-    for (oid, path) in tree {
-        let directories = Path::new(&path)
-            .parent()
-            .expect("Failed to get parent directories from path");
-        // Check whether the file is contained in a subdirectory.
-        // XXX(sjm): Is there a nicer way of doing this?
-        if directories != Path::new("") {
-            std::fs::create_dir_all(directories).expect("Failed to create parent directories");
+/// Refuse to commit a merge while paths remain unresolved, either because they haven't been staged
+/// since the merge or because they were staged with conflict markers still in them.
+fn check_conflicts_resolved(repo: &Repository, index: &index::Index) -> Result<()> {
+    let mut unresolved: Vec<PathBuf> = get_merge_conflicts(repo)?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+
+    let head_tree = get_head_tree(repo)?;
+    let index_tree = index.to_tree();
+    for (path, _, _) in diff::get_changed_files(&head_tree, &index_tree, &[]) {
+        let oid = match index_tree.iter().find(|(_, p, _)| *p == path) {
+            Some((oid, _, _)) => oid,
+            None => continue,
+        };
+        if merge::has_conflict_markers(&data::get_object(repo, oid, Some("blob"))?) {
+            unresolved.push(PathBuf::from(path));
         }
+    }
 
-        let contents = data::get_object(repo_path, oid.as_str(), None);
-        std::fs::write(path, contents).expect("Failed to write file contents");
+    if unresolved.is_empty() {
+        return Ok(());
     }
+    unresolved.sort();
+    unresolved.dedup();
+    Err(Error::MergeConflict(unresolved))
 }
 
-fn read_tree_merged(base: &str, head: &str, other: &str) {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
-    let current_dir = env::current_dir().expect("Failed to get current directory");
+/// Mark the specified paths as resolved, now that they've been staged or removed.
+fn resolve_merge_conflicts(repo: &Repository, paths: &[PathBuf]) -> Result<()> {
+    let mut conflicts = get_merge_conflicts(repo)?;
+    let count = conflicts.len();
+    conflicts.retain(|(path, _)| !paths.contains(path));
+    if conflicts.len() != count {
+        write_merge_conflicts(repo, &conflicts)?;
+    }
+    Ok(())
+}
 
-    empty_directory(&current_dir);
+/// Return the paths that the merge in progress left with conflicts, along with the kind of
+/// conflict in each.
+pub fn get_merge_conflicts(repo: &Repository) -> Result<Vec<(PathBuf, ConflictKind)>> {
+    let path = repo.git_path(MERGE_CONFLICTS);
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
 
-    let base_tree = get_tree(default_repo, Some(base), None);
-    let head_tree = get_tree(default_repo, Some(head), None);
-    let other_tree = get_tree(default_repo, Some(other), None);
-    for (path, blob) in diff::merge_trees(&base_tree, &head_tree, &other_tree) {
-        let path = path::PathBuf::from(path);
-        fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directory");
-        fs::write(path, blob).expect("Failed to write blob");
+    let mut conflicts = vec![];
+    for entry in fs::read(&path)?
+        .split(|b| *b == 0)
+        .filter(|e| !e.is_empty())
+    {
+        let malformed = || {
+            let reason = format!("malformed {} entry", MERGE_CONFLICTS);
+            Error::CorruptMergeState(reason)
+        };
+        let (code, path) = match entry.iter().position(|b| *b == b' ') {
+            Some(space) => (&entry[..space], &entry[space + 1..]),
+            None => return Err(malformed()),
+        };
+        let kind = std::str::from_utf8(code)
+            .ok()
+            .and_then(diff::ConflictKind::from_code)
+            .ok_or_else(malformed)?;
+        let path = bytes_to_os_string(path)
+            .filter(|path| !path.is_empty())
+            .ok_or_else(malformed)?;
+        conflicts.push((PathBuf::from(path), kind));
     }
+    Ok(conflicts)
 }
 
-fn get_tree_entries(repo_path: &Path, oid: Option<&str>) -> Vec<(String, String, String)> {
-    let mut tree_entries = vec![];
+/// Record the paths left with conflicts, one "<code> <path>" entry per path, each terminated by a
+/// null byte so that any path can be stored.
+fn write_merge_conflicts(repo: &Repository, conflicts: &[(PathBuf, ConflictKind)]) -> Result<()> {
+    let path = repo.git_path(MERGE_CONFLICTS);
+    if conflicts.is_empty() {
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
 
-    if let Some(oid) = oid {
-        let tree_object = data::get_object(repo_path, oid, Some("tree"));
-        let tree = std::str::from_utf8(&tree_object).expect("Tree is not valid UTF-8");
-        for line in tree.lines() {
-            let split: Vec<&str> = line.split_whitespace().collect();
+    let mut contents = vec![];
+    for (conflict_path, kind) in conflicts {
+        let path_bytes = os_str_to_bytes(conflict_path.as_os_str())
+            .ok_or_else(|| Error::InvalidPath(conflict_path.to_owned()))?;
+        contents.extend_from_slice(kind.code().as_bytes());
+        contents.push(b' ');
+        contents.extend_from_slice(path_bytes);
+        contents.push(0);
+    }
+    fs::write(path, contents)?;
+    Ok(())
+}
 
-            let object_type = split
-                .get(0)
-                .expect("Failed to get object type from tree object")
-                .to_string();
-            let oid = split
-                .get(1)
-                .expect("Failed to get OID from tree object")
-                .to_string();
-            let path = split
-                .get(2)
-                .expect("Failed to get path from tree object")
-                .to_string();
+/// Parse the tree with the specified OID into a list of the modes, OIDs and names of its entries.
+fn get_tree_entries(
+    repo: &Repository,
+    oid: Option<&str>,
+) -> Result<Vec<(u32, String, ffi::OsString)>> {
+    let mut tree_entries = vec![];
 
-            tree_entries.push((object_type, oid, path));
+    if let Some(oid) = oid {
+        let tree_object = data::get_object(repo, oid, Some("tree"))?;
+        let mut rest = tree_object.as_slice();
+        while !rest.is_empty() {
+            let malformed = || Error::corrupt_object(oid, "malformed tree entry");
+
+            let space = rest.iter().position(|b| *b == b' ').ok_or_else(malformed)?;
+            let mode = std::str::from_utf8(&rest[..space])
+                .ok()
+                .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+                .ok_or_else(malformed)?;
+            rest = &rest[space + 1..];
+
+            let null = rest.iter().position(|b| *b == 0).ok_or_else(malformed)?;
+            let name = bytes_to_os_string(&rest[..null]).ok_or_else(|| {
+                Error::corrupt_object(oid, "tree entry name can't be represented")
+            })?;
+            rest = &rest[null + 1..];
+
+            let entry_oid = rest.get(..20).ok_or_else(malformed)?;
+            tree_entries.push((mode, to_hex(entry_oid), name));
+            rest = &rest[20..];
         }
     }
 
-    tree_entries
+    Ok(tree_entries)
 }
 
-/// Recursively traverses the tree with the specified OID and returns a flattened list of file OIDs
-/// and their paths.
-pub fn get_tree(repo_path: &Path, oid: Option<&str>, base_path: Option<&str>) -> Tree {
-    let base_path = base_path.unwrap_or("");
+/// Recursively traverses the tree with the specified OID and returns a flattened list of file OIDs,
+/// paths and modes.
+pub fn get_tree(repo: &Repository, oid: Option<&str>, base_path: Option<&Path>) -> Result<Tree> {
+    let base_path = base_path.unwrap_or_else(|| Path::new(""));
 
     let mut result: Tree = vec![];
 
-    for (object_type, oid, relative_path) in get_tree_entries(repo_path, oid) {
-        let mut path = path::PathBuf::new();
-        path.push(base_path);
-        path.push(relative_path);
+    for (mode, entry_oid, name) in get_tree_entries(repo, oid)? {
+        let path = base_path.join(&name);
 
-        assert!(!is_illegal(&path));
+        if is_illegal(&path) || Path::new(&name).components().count() != 1 {
+            return Err(Error::InvalidPath(path));
+        }
 
-        match object_type.as_ref() {
-            "blob" => {
-                result.push((oid.to_string(), path.into_os_string()));
+        match mode {
+            MODE_TREE => {
+                let subtree = get_tree(repo, Some(&entry_oid), Some(&path))?;
+                result.extend(subtree);
             }
-            "tree" => {
-                let subtree = get_tree(repo_path, Some(&oid), path.to_str());
-                for subtree_object in subtree {
-                    result.push(subtree_object);
-                }
+            MODE_FILE | MODE_EXECUTABLE | MODE_SYMLINK => {
+                result.push((entry_oid, path.into_os_string(), mode));
+            }
+            _ => {
+                return Err(Error::corrupt_object(
+                    oid.unwrap_or_default(),
+                    "unrecognised tree entry mode",
+                ))
             }
-            _ => panic!("Unrecognised object type: {}", object_type),
         }
     }
 
-    result
+    Ok(result)
 }
 
-/// Store the contents of the current directory in the object store and return a corresponding Tree.
-pub fn get_working_tree() -> Tree {
+/// Return a Tree describing the contents of the working tree, other than ignored files. Nothing is
+/// written to the object store, and files whose metadata matches the index aren't read at all.
+pub fn get_working_tree(repo: &Repository) -> Result<Tree> {
+    let index = index::read(repo)?;
+    let mut ignore = Ignore::new(repo)?;
     let mut result = vec![];
-    for entry in walkdir::WalkDir::new(".") {
-        let entry = entry.expect("Failed to read directory entry");
-        let path = entry
-            .into_path()
-            .components()
-            .skip(1) // Skip the "." part of the path.
-            .collect::<path::PathBuf>();
-        if is_ignored(&path) || !path.is_file() {
+    let mut entries = walkdir::WalkDir::new(&repo.work_tree).into_iter();
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let is_dir = entry.file_type().is_dir();
+        let path = repo.work_tree_relative_path(entry.path())?;
+        if ignore.is_ignored(&path, is_dir)? {
+            if is_dir {
+                entries.skip_current_dir();
+            }
             continue;
         }
-        let contents = std::fs::read(&path).expect("Failed to read file contents");
-        let oid = data::hash_object(&contents, "blob");
-        result.push((oid.to_string(), path.into_os_string()));
+        if is_dir {
+            continue;
+        }
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let oid = match index.get_unchanged_oid(&path, &metadata) {
+            Some(oid) => oid.to_owned(),
+            None => data::compute_oid(&read_work_tree_file(entry.path())?.0, "blob"),
+        };
+        result.push((oid, path.into_os_string(), index::get_mode(&metadata)));
     }
-    result
+    Ok(result)
 }
 
-pub fn checkout(name: &str) {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
-
-    let oid = get_oid(name).unwrap();
-    let commit = get_commit(default_repo, &oid);
-    read_tree(default_repo, &commit.tree);
-
-    let head = if is_branch(name) {
+/// Switch to the specified branch or commit, updating the index and working tree to match it.
+/// Unless `force` is set, this is refused if it would overwrite uncommitted changes.
+pub fn checkout(repo: &Repository, name: &str, force: bool) -> Result<()> {
+    let oid = get_oid(repo, name)?;
+    let commit = get_commit(repo, &oid)?;
+    read_tree(repo, &commit.tree, force)?;
+
+    let old = data::get_ref(repo, "HEAD", true)?.value;
+    let from = get_branch_name(repo)?
+        .or_else(|| old.clone())
+        .unwrap_or_default();
+    let head = if is_branch(repo, name)? {
         data::RefValue {
             symbolic: true,
             value: Some(format!("refs/heads/{}", name)),
@@ -435,51 +1951,51 @@ pub fn checkout(name: &str) {
     } else {
         data::RefValue {
             symbolic: false,
-            value: Some(oid),
+            value: Some(oid.clone()),
         }
     };
-    data::update_ref(default_repo, "HEAD", &head, false);
+    data::update_ref(repo, "HEAD", &head, false)?;
+    // Switching branches only changes HEAD, so only its reflog records the checkout.
+    data::append_reflog(
+        repo,
+        "HEAD",
+        &data::ReflogEntry {
+            old,
+            new: oid,
+            identity: get_reflog_identity(repo)?,
+            message: format!("checkout: moving from {} to {}", from, name),
+        },
+    )
 }
 
 /// Retrieve the OIDs of all the commits that are reachable from the commits with the specified
-/// OIDs.
-pub fn get_commits_and_parents(repo_path: &Path, root_oids: Vec<&str>) -> Vec<String> {
-    let mut oids_to_visit: VecDeque<String> = VecDeque::new();
+/// OIDs. They're returned as a set, since the order they're found in means nothing; use
+/// `walk_revisions` to list history in order.
+pub fn get_commits_and_parents(repo: &Repository, root_oids: Vec<&str>) -> Result<HashSet<String>> {
+    let mut oids_to_visit: Vec<String> = root_oids.into_iter().map(str::to_owned).collect();
     let mut visited_oids: HashSet<String> = HashSet::new();
 
-    for oid in root_oids {
-        oids_to_visit.push_back(oid.to_owned());
-    }
-
-    let mut oids: Vec<String> = vec![];
-    while let Some(oid) = oids_to_visit.pop_back() {
+    while let Some(oid) = oids_to_visit.pop() {
         if visited_oids.contains(&oid) {
             continue;
         }
-
-        let commit: Commit = get_commit(repo_path, &oid);
-
-        visited_oids.insert(oid.clone());
-
-        oids.push(oid.clone());
-
-        for parent_oid in commit.parents {
-            oids_to_visit.push_back(parent_oid);
-        }
+        let commit: Commit = get_commit(repo, &oid)?;
+        visited_oids.insert(oid);
+        oids_to_visit.extend(commit.parents);
     }
 
-    oids
+    Ok(visited_oids)
 }
 
-fn get_objects_in_tree(repo_path: &Path, oid: &str) -> HashSet<String> {
+fn get_objects_in_tree(repo: &Repository, oid: &str) -> Result<HashSet<String>> {
     let mut visited: HashSet<String> = HashSet::new();
 
     visited.insert(oid.to_owned());
 
-    for (object_type, oid, _) in get_tree_entries(repo_path, Some(oid)) {
+    for (mode, oid, _) in get_tree_entries(repo, Some(oid))? {
         if !visited.contains(&oid) {
-            if object_type == "tree" {
-                let subtree_oids = get_objects_in_tree(repo_path, &oid);
+            if mode == MODE_TREE {
+                let subtree_oids = get_objects_in_tree(repo, &oid)?;
                 visited.extend(subtree_oids);
             } else {
                 visited.insert(oid);
@@ -487,34 +2003,32 @@ fn get_objects_in_tree(repo_path: &Path, oid: &str) -> HashSet<String> {
         }
     }
 
-    visited
+    Ok(visited)
+}
+
+/// Retrieve the OIDs of the trees and blobs in the tree of the commit with the specified OID.
+pub fn get_objects_in_commit_tree(repo: &Repository, oid: &str) -> Result<HashSet<String>> {
+    get_objects_in_tree(repo, &get_commit(repo, oid)?.tree)
 }
 
-pub fn get_objects_in_commits(repo_path: &Path, oids: Vec<&str>) -> HashSet<String> {
+pub fn get_objects_in_commits(repo: &Repository, oids: Vec<&str>) -> Result<HashSet<String>> {
     let mut oids_in_commits: HashSet<String> = HashSet::new();
-    for oid in get_commits_and_parents(repo_path, oids) {
-        let commit = get_commit(repo_path, &oid);
+    for oid in get_commits_and_parents(repo, oids)? {
+        let commit = get_commit(repo, &oid)?;
         oids_in_commits.insert(oid.clone());
         if !oids_in_commits.contains(&commit.tree) {
-            let oids_in_tree = get_objects_in_tree(repo_path, &commit.tree);
+            let oids_in_tree = get_objects_in_tree(repo, &commit.tree)?;
             oids_in_commits.extend(oids_in_tree);
         }
     }
-    oids_in_commits
+    Ok(oids_in_commits)
 }
 
-/// Whether the specified path is a ugit repository. This is overly simplistic and should really
-/// check whether the .ugit directory at least contains an objects sub-directory.
-pub fn is_ugit_repository(path: &Path) -> bool {
-    let mut ugit_data_dir = path.to_owned();
-    ugit_data_dir.push(DEFAULT_REPO);
-    ugit_data_dir.is_dir()
-}
-
-/// Whether or not the specified path should not be added to the object store.
-fn is_ignored(path: &Path) -> bool {
+/// Strip any leading "./" components from a path so that it matches the paths stored in the index.
+fn normalise_path(path: &Path) -> PathBuf {
     path.components()
-        .any(|c| c == Component::Normal(DEFAULT_REPO.as_ref()))
+        .filter(|c| *c != Component::CurDir)
+        .collect()
 }
 
 /// Whether a path contains illegal components.
@@ -524,17 +2038,673 @@ fn is_illegal(path: &Path) -> bool {
         .any(|c| illegal_path_components.contains(&c))
 }
 
-/// Empty the specified directory of its contents, ignoring the ugit directory.
-fn empty_directory(dir_path: &Path) {
-    let dir = fs::read_dir(dir_path).expect("Failed to read directory");
-    for entry in dir {
-        let path = entry.expect("Failed to read directory entry").path();
-        if path.is_dir() {
-            if !is_ignored(&path) {
-                fs::remove_dir_all(path).expect("Failed to remove directory");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ugit::testing::{
+        commit_files, init_repo, make_commit, make_commit_with_times, read_file, write_files,
+    };
+
+    /// Commit changes to "a.txt" on master and on a branch called "other" that conflict when
+    /// merged, leaving master checked out.
+    fn make_conflicting_branches(repo: &Repository) {
+        commit_files(repo, &[("a.txt", "base\n"), ("b.txt", "b\n")], "base");
+        create_branch(repo, "other", &get_oid(repo, "HEAD").unwrap()).unwrap();
+        commit_files(repo, &[("a.txt", "ours\n")], "ours");
+        checkout(repo, "other", false).unwrap();
+        commit_files(repo, &[("a.txt", "theirs\n")], "theirs");
+        checkout(repo, "master", false).unwrap();
+    }
+
+    /// Whether the index matches HEAD's tree.
+    fn nothing_staged(repo: &Repository) -> bool {
+        let index_tree = index::read(repo).unwrap().to_tree();
+        diff::get_changed_files(&get_head_tree(repo).unwrap(), &index_tree, &[]).is_empty()
+    }
+
+    #[test]
+    fn merge_abort_keeps_unrelated_changes() {
+        let (_dir, repo) = init_repo();
+        make_conflicting_branches(&repo);
+        write_files(&repo, &[("b.txt", "unstaged\n")]);
+        let outcome = merge(&repo, "other", &MergeOptions::default(), false).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Conflicts(_)));
+        // Half-finished work on the conflict is thrown away with it.
+        write_files(&repo, &[("a.txt", "resolving\n")]);
+
+        merge_abort(&repo).unwrap();
+        assert_eq!(read_file(&repo, "a.txt").as_deref(), Some("ours\n"));
+        assert_eq!(read_file(&repo, "b.txt").as_deref(), Some("unstaged\n"));
+        assert!(nothing_staged(&repo));
+    }
+
+    #[test]
+    fn merges_can_be_undone_with_orig_head() {
+        let (_dir, repo) = init_repo();
+        let base = make_diverging_branch(&repo);
+        let outcome = merge(&repo, "other", &MergeOptions::default(), false).unwrap();
+        assert!(matches!(outcome, MergeOutcome::FastForward));
+        assert_eq!(get_oid(&repo, "ORIG_HEAD").unwrap(), base);
+
+        let orig_head = get_oid(&repo, "ORIG_HEAD").unwrap();
+        reset(&repo, &orig_head, ResetMode::Hard, false).unwrap();
+        assert_eq!(get_oid(&repo, "HEAD").unwrap(), base);
+        assert_eq!(read_file(&repo, "a.txt").as_deref(), Some("a\n"));
+        assert_eq!(read_file(&repo, "new.txt"), None);
+
+        // A merge that isn't a fast-forward records it too, before the merge commit is made.
+        commit_files(&repo, &[("b.txt", "ours\n")], "ours");
+        let head = get_oid(&repo, "HEAD").unwrap();
+        let outcome = merge(&repo, "other", &MergeOptions::default(), false).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Clean));
+        assert_eq!(get_oid(&repo, "ORIG_HEAD").unwrap(), head);
+    }
+
+    #[test]
+    fn merge_conflicts_round_trip() {
+        let (_dir, repo) = init_repo();
+        assert!(get_merge_conflicts(&repo).unwrap().is_empty());
+
+        let conflicts = vec![
+            (PathBuf::from("a.txt"), ConflictKind::BothModified),
+            (PathBuf::from("dir/with space"), ConflictKind::BothAdded),
+            (PathBuf::from("deleted"), ConflictKind::DeletedByUs),
+            (PathBuf::from("kept"), ConflictKind::DeletedByThem),
+        ];
+        write_merge_conflicts(&repo, &conflicts).unwrap();
+        assert_eq!(get_merge_conflicts(&repo).unwrap(), conflicts);
+
+        resolve_merge_conflicts(&repo, &[PathBuf::from("deleted")]).unwrap();
+        let mut remaining = conflicts.clone();
+        remaining.remove(2);
+        assert_eq!(get_merge_conflicts(&repo).unwrap(), remaining);
+
+        write_merge_conflicts(&repo, &[]).unwrap();
+        assert!(!repo.git_path(MERGE_CONFLICTS).exists());
+    }
+
+    #[test]
+    fn malformed_merge_conflicts() {
+        let (_dir, repo) = init_repo();
+        for contents in [&b"XX a.txt\0"[..], b"UU\0", b"UUa.txt\0", b"UU \0"] {
+            fs::write(repo.git_path(MERGE_CONFLICTS), contents).unwrap();
+            let result = get_merge_conflicts(&repo);
+            assert!(matches!(result, Err(Error::CorruptMergeState(_))));
+        }
+    }
+
+    #[test]
+    fn merge_abort_restores_pre_merge_state() {
+        let (_dir, repo) = init_repo();
+        let base = commit_files(&repo, &[("a.txt", "a\n"), ("b.txt", "b\n")], "base");
+        create_branch(&repo, "other", &base).unwrap();
+        checkout(&repo, "other", false).unwrap();
+        commit_files(
+            &repo,
+            &[("b.txt", "changed\n"), ("new.txt", "new\n")],
+            "theirs",
+        );
+        checkout(&repo, "master", false).unwrap();
+        let head = commit_files(&repo, &[("a.txt", "ours\n")], "ours");
+
+        let outcome = merge(&repo, "other", &MergeOptions::default(), false).unwrap();
+        assert!(matches!(outcome, MergeOutcome::Clean));
+        assert_eq!(read_file(&repo, "new.txt").as_deref(), Some("new\n"));
+
+        merge_abort(&repo).unwrap();
+        assert_eq!(get_oid(&repo, "HEAD").unwrap(), head);
+        assert_eq!(read_file(&repo, "b.txt").as_deref(), Some("b\n"));
+        assert_eq!(read_file(&repo, "new.txt"), None);
+        assert!(nothing_staged(&repo));
+        assert!(data::get_ref(&repo, "MERGE_HEAD", false)
+            .unwrap()
+            .value
+            .is_none());
+        assert!(get_merge_message(&repo).unwrap().is_none());
+        assert!(matches!(merge_abort(&repo), Err(Error::NoMergeInProgress)));
+    }
+
+    #[test]
+    fn merge_commit_waits_for_conflicts_to_be_resolved() {
+        let (_dir, repo) = init_repo();
+        make_conflicting_branches(&repo);
+        merge(&repo, "other", &MergeOptions::default(), false).unwrap();
+        let unresolved = |result: Result<String>| matches!(result, Err(Error::MergeConflict(paths)) if paths == [PathBuf::from("a.txt")]);
+
+        // Neither an unstaged conflict nor one staged with its markers still in it is resolved.
+        assert!(unresolved(commit(&repo, "merge")));
+        add(&repo, &[PathBuf::from("a.txt")]).unwrap();
+        assert!(get_merge_conflicts(&repo).unwrap().is_empty());
+        assert!(unresolved(merge_continue(&repo)));
+
+        write_files(&repo, &[("a.txt", "both\n")]);
+        add(&repo, &[PathBuf::from("a.txt")]).unwrap();
+        let oid = merge_continue(&repo).unwrap();
+        let commit = get_commit(&repo, &oid).unwrap();
+        assert_eq!(commit.parents.len(), 2);
+        assert_eq!(commit.message, "Merge branch 'other'");
+        assert!(matches!(
+            merge_continue(&repo),
+            Err(Error::NoMergeInProgress)
+        ));
+    }
+
+    #[test]
+    fn parse_dates() {
+        let cases: &[(&str, Option<(i64, i32)>)] = &[
+            ("1700000000 +0100", Some((1700000000, 60))),
+            ("1700000000 -0530", Some((1700000000, -330))),
+            ("@1700000000 +0000", Some((1700000000, 0))),
+            ("1700000000", Some((1700000000, 0))),
+            ("  1700000000   +1400  ", Some((1700000000, 840))),
+            ("", None),
+            ("yesterday +0100", None),
+            ("1700000000 0100", None),
+            ("1700000000 +010", None),
+            ("1700000000 +01000", None),
+            ("1700000000 +-100", None),
+            ("1700000000 +\u{e9}00", None),
+            ("1700000000 \u{e9}0100", None),
+            ("1700000000 +0100 +0100", None),
+        ];
+        for (date, expected) in cases {
+            assert_eq!(parse_date(date), *expected, "{:?}", date);
+        }
+    }
+
+    #[test]
+    fn format_offsets() {
+        assert_eq!(format_offset(0), "+0000");
+        assert_eq!(format_offset(60), "+0100");
+        assert_eq!(format_offset(-330), "-0530");
+        assert_eq!(format_offset(840), "+1400");
+    }
+
+    #[test]
+    fn parse_signatures() {
+        let signature =
+            parse_signature("Zo\u{eb} Thor <zoe@example.com> 1700000000 -0130").unwrap();
+        assert_eq!(signature.name, "Zo\u{eb} Thor");
+        assert_eq!(signature.email, "zoe@example.com");
+        assert_eq!((signature.time, signature.offset), (1700000000, -90));
+        assert_eq!(
+            format_signature(&signature),
+            "Zo\u{eb} Thor <zoe@example.com> 1700000000 -0130"
+        );
+
+        assert!(parse_signature("A U Thor author@example.com 1700000000 +0000").is_none());
+        assert!(parse_signature("A U Thor <author@example.com 1700000000 +0000").is_none());
+        assert!(parse_signature("A U Thor <author@example.com>").is_none());
+        assert!(parse_signature("A U Thor <author@example.com> 1700000000 +\u{e9}00").is_none());
+    }
+
+    #[test]
+    fn malformed_signature_in_commit() {
+        let (_dir, repo) = init_repo();
+        let tree = write_tree_from_index(&repo, &index::Index::default()).unwrap();
+        let commit = format!(
+            "tree {}\nauthor A <a@example.com> 0 +\u{e9}00\n\nmessage\n",
+            tree
+        );
+        let oid = data::hash_object(&repo, commit.as_bytes(), "commit").unwrap();
+        assert!(matches!(
+            get_commit(&repo, &oid),
+            Err(Error::CorruptObject { .. })
+        ));
+    }
+
+    /// Commit "a.txt" and "b.txt" on master, then change "a.txt" and add "new.txt" on a branch
+    /// called "other", leaving master checked out.
+    fn make_diverging_branch(repo: &Repository) -> String {
+        let base = commit_files(repo, &[("a.txt", "a\n"), ("b.txt", "b\n")], "base");
+        create_branch(repo, "other", &base).unwrap();
+        checkout(repo, "other", false).unwrap();
+        commit_files(repo, &[("a.txt", "other\n"), ("new.txt", "new\n")], "other");
+        checkout(repo, "master", false).unwrap();
+        base
+    }
+
+    fn would_overwrite(result: Result<()>, expected: &[&str]) -> bool {
+        let expected: Vec<PathBuf> = expected.iter().map(PathBuf::from).collect();
+        matches!(result, Err(Error::WouldOverwrite(paths)) if paths == expected)
+    }
+
+    #[test]
+    fn checkout_refuses_to_overwrite_changes() {
+        let (_dir, repo) = init_repo();
+        make_diverging_branch(&repo);
+
+        write_files(&repo, &[("a.txt", "edited\n")]);
+        assert!(would_overwrite(checkout(&repo, "other", false), &["a.txt"]));
+        assert_eq!(get_branch_name(&repo).unwrap().as_deref(), Some("master"));
+        assert_eq!(read_file(&repo, "a.txt").as_deref(), Some("edited\n"));
+
+        // Staged changes are protected just the same.
+        add(&repo, &[PathBuf::from("a.txt")]).unwrap();
+        assert!(would_overwrite(checkout(&repo, "other", false), &["a.txt"]));
+    }
+
+    #[test]
+    fn checkout_keeps_changes_to_paths_it_leaves_alone() {
+        let (_dir, repo) = init_repo();
+        make_diverging_branch(&repo);
+
+        write_files(&repo, &[("b.txt", "edited\n"), ("untracked", "u\n")]);
+        checkout(&repo, "other", false).unwrap();
+        assert_eq!(read_file(&repo, "a.txt").as_deref(), Some("other\n"));
+        assert_eq!(read_file(&repo, "b.txt").as_deref(), Some("edited\n"));
+        assert_eq!(read_file(&repo, "untracked").as_deref(), Some("u\n"));
+    }
+
+    #[test]
+    fn checkout_onto_identical_changes() {
+        let (_dir, repo) = init_repo();
+        make_diverging_branch(&repo);
+
+        // Neither an edit nor an untracked file stands in the way if it matches the target.
+        write_files(&repo, &[("a.txt", "other\n"), ("new.txt", "new\n")]);
+        checkout(&repo, "other", false).unwrap();
+        assert!(nothing_staged(&repo));
+    }
+
+    #[test]
+    fn checkout_refuses_to_overwrite_untracked_files() {
+        let (_dir, repo) = init_repo();
+        make_diverging_branch(&repo);
+
+        write_files(&repo, &[("new.txt", "mine\n")]);
+        assert!(would_overwrite(
+            checkout(&repo, "other", false),
+            &["new.txt"]
+        ));
+
+        // A file can't be written where an untracked directory is.
+        fs::remove_file(repo.work_tree_path("new.txt")).unwrap();
+        write_files(&repo, &[("new.txt/inside", "mine\n")]);
+        assert!(would_overwrite(
+            checkout(&repo, "other", false),
+            &["new.txt/inside"]
+        ));
+        assert_eq!(
+            read_file(&repo, "new.txt/inside").as_deref(),
+            Some("mine\n")
+        );
+    }
+
+    #[test]
+    fn forced_checkout_discards_everything_in_the_way() {
+        let (_dir, repo) = init_repo();
+        make_diverging_branch(&repo);
+
+        write_files(
+            &repo,
+            &[("a.txt", "edited\n"), ("new.txt/inside", "mine\n")],
+        );
+        checkout(&repo, "other", true).unwrap();
+        assert_eq!(read_file(&repo, "a.txt").as_deref(), Some("other\n"));
+        assert_eq!(read_file(&repo, "new.txt").as_deref(), Some("new\n"));
+        assert!(nothing_staged(&repo));
+    }
+
+    #[test]
+    fn hard_reset_discards_tracked_changes_only() {
+        let (_dir, repo) = init_repo();
+        make_diverging_branch(&repo);
+        let other = get_oid(&repo, "other").unwrap();
+
+        write_files(&repo, &[("a.txt", "edited\n"), ("untracked", "u\n")]);
+        reset(&repo, &other, ResetMode::Hard, false).unwrap();
+        assert_eq!(read_file(&repo, "a.txt").as_deref(), Some("other\n"));
+        assert_eq!(read_file(&repo, "untracked").as_deref(), Some("u\n"));
+        assert!(nothing_staged(&repo));
+
+        // Going back would delete new.txt, which is fine, but not replace an untracked file.
+        let base = get_oid(&repo, "HEAD~").unwrap();
+        reset(&repo, &base, ResetMode::Hard, false).unwrap();
+        assert_eq!(read_file(&repo, "new.txt"), None);
+        write_files(&repo, &[("new.txt", "mine\n")]);
+        assert!(would_overwrite(
+            reset(&repo, &other, ResetMode::Hard, false),
+            &["new.txt"]
+        ));
+        reset(&repo, &other, ResetMode::Hard, true).unwrap();
+        assert_eq!(read_file(&repo, "new.txt").as_deref(), Some("new\n"));
+    }
+
+    #[test]
+    fn reflog_selectors() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("a.txt", "1\n")], "first");
+        let second = commit_files(&repo, &[("a.txt", "2\n")], "second");
+        let third = commit_files(&repo, &[("a.txt", "3\n")], "third");
+
+        assert_eq!(get_oid(&repo, "master@{0}").unwrap(), third);
+        assert_eq!(get_oid(&repo, "master@{2}").unwrap(), first);
+        assert_eq!(get_oid(&repo, "@{1}").unwrap(), second);
+        assert_eq!(get_oid(&repo, "master@{1}~").unwrap(), first);
+        assert!(matches!(
+            get_oid(&repo, "master@{3}"),
+            Err(Error::ReflogTooShort { entries: 3, .. })
+        ));
+        assert!(matches!(
+            get_oid(&repo, "master@{x}"),
+            Err(Error::BadRef(_))
+        ));
+
+        // "@{n}" follows the branch that's checked out, while HEAD's reflog also records the
+        // checkout.
+        create_branch(&repo, "other", &first).unwrap();
+        checkout(&repo, "other", false).unwrap();
+        let other = commit_files(&repo, &[("b.txt", "b\n")], "other");
+        assert_eq!(get_oid(&repo, "@{0}").unwrap(), other);
+        assert_eq!(get_oid(&repo, "@{1}").unwrap(), first);
+        assert_eq!(get_oid(&repo, "HEAD@{1}").unwrap(), first);
+        assert_eq!(get_oid(&repo, "HEAD@{2}").unwrap(), third);
+
+        // A detached HEAD has only its own reflog.
+        checkout(&repo, &second, false).unwrap();
+        assert_eq!(get_oid(&repo, "@{0}").unwrap(), second);
+        assert_eq!(get_oid(&repo, "@{1}").unwrap(), other);
+    }
+
+    #[test]
+    fn split_ranges() {
+        let cases = [
+            ("A..B", Some(("A", "B", false))),
+            ("A...B", Some(("A", "B", true))),
+            ("..B", Some(("HEAD", "B", false))),
+            ("A..", Some(("A", "HEAD", false))),
+            ("...", Some(("HEAD", "HEAD", true))),
+            ("..", None),
+            ("A", None),
+            (
+                "HEAD~2..HEAD^{tree}",
+                Some(("HEAD~2", "HEAD^{tree}", false)),
+            ),
+            ("HEAD:dir/a..b", None),
+            ("A..B:dir/a..b", Some(("A", "B:dir/a..b", false))),
+        ];
+        for (range, expected) in cases {
+            assert_eq!(split_range(range), expected, "{:?}", range);
+        }
+    }
+
+    #[test]
+    fn parse_revision_suffixes() {
+        use RevisionSuffix::*;
+        let cases: &[(&str, Option<Vec<RevisionSuffix>>)] = &[
+            ("", Some(vec![])),
+            ("^", Some(vec![Parent(1)])),
+            ("^2", Some(vec![Parent(2)])),
+            ("^0", Some(vec![Parent(0)])),
+            ("~", Some(vec![Ancestor(1)])),
+            ("~12", Some(vec![Ancestor(12)])),
+            (
+                "^^~3^2",
+                Some(vec![Parent(1), Parent(1), Ancestor(3), Parent(2)]),
+            ),
+            ("^{tree}", Some(vec![Peel("tree")])),
+            ("~2^{}", Some(vec![Ancestor(2), Peel("")])),
+            ("^{tree", None),
+            ("~x", None),
+            ("~\u{e9}", None),
+            ("~99999999999999999999999", None),
+        ];
+        for (suffixes, expected) in cases {
+            assert_eq!(parse_suffixes(suffixes), *expected, "{:?}", suffixes);
+        }
+    }
+
+    /// Build the history below, tagging "m" and "s", and return the OIDs of c1, c2, c3, s and m.
+    ///
+    ///     c1 - c2 - c3 - m
+    ///            \      /
+    ///             s ----
+    fn make_merge_history(repo: &Repository) -> [String; 5] {
+        let c1 = make_commit(repo, &[("a.txt", "1\n")], &[], 1);
+        let c2 = make_commit(repo, &[("a.txt", "2\n")], &[&c1], 2);
+        let c3 = make_commit(repo, &[("a.txt", "3\n")], &[&c2], 3);
+        let s = make_commit(repo, &[("a.txt", "2\n"), ("s.txt", "s\n")], &[&c2], 4);
+        let m = make_commit(repo, &[("a.txt", "3\n"), ("s.txt", "s\n")], &[&c3, &s], 5);
+        create_tag(repo, "m", &m).unwrap();
+        create_tag(repo, "s", &s).unwrap();
+        [c1, c2, c3, s, m]
+    }
+
+    #[test]
+    fn revision_suffixes() {
+        let (_dir, repo) = init_repo();
+        let [c1, c2, c3, s, m] = make_merge_history(&repo);
+        let oid = |revision: &str| get_oid(&repo, revision);
+
+        assert_eq!(oid("m^").unwrap(), c3);
+        assert_eq!(oid("m^1").unwrap(), c3);
+        assert_eq!(oid("m^2").unwrap(), s);
+        assert_eq!(oid("m^0").unwrap(), m);
+        assert_eq!(oid("m~2").unwrap(), c2);
+        assert_eq!(oid("m^^^").unwrap(), c1);
+        assert_eq!(oid("m^2~").unwrap(), c2);
+        assert_eq!(oid("s~1^{commit}").unwrap(), c2);
+        assert_eq!(
+            oid("m^{tree}").unwrap(),
+            get_commit(&repo, &m).unwrap().tree
+        );
+        assert_eq!(oid("m^{}").unwrap(), m);
+
+        assert!(matches!(oid("m^3"), Err(Error::BadRef(_))));
+        assert!(matches!(oid("c3^2"), Err(Error::BadRef(_))));
+        assert!(matches!(oid("m~4"), Err(Error::BadRef(_))));
+        assert!(matches!(oid("m^{bogus}"), Err(Error::BadRef(_))));
+        assert!(matches!(oid("m^{tree"), Err(Error::BadRef(_))));
+        assert!(matches!(oid("m^{blob}"), Err(Error::TypeMismatch { .. })));
+        assert!(matches!(oid("m^{tree}^"), Err(Error::TypeMismatch { .. })));
+        assert!(matches!(oid("nonexistent~1"), Err(Error::BadRef(_))));
+    }
+
+    #[test]
+    fn revision_paths() {
+        let (_dir, repo) = init_repo();
+        let [_, _, _, s, _] = make_merge_history(&repo);
+        let blob = data::compute_oid(b"s\n", "blob");
+
+        assert_eq!(get_oid(&repo, "m:s.txt").unwrap(), blob);
+        assert_eq!(get_oid(&repo, &format!("{}:./s.txt", s)).unwrap(), blob);
+        assert!(matches!(
+            get_oid(&repo, "m~:s.txt"),
+            Err(Error::PathNotInRevision { .. })
+        ));
+        // Nothing has been staged.
+        assert!(matches!(
+            get_oid(&repo, ":s.txt"),
+            Err(Error::PathNotInRevision { revision, .. }) if revision == "the index"
+        ));
+    }
+
+    #[test]
+    fn abbreviated_oids() {
+        let (_dir, repo) = init_repo();
+        let [.., m] = make_merge_history(&repo);
+
+        assert_eq!(get_oid(&repo, &m[..7]).unwrap(), m);
+        assert_eq!(get_oid(&repo, &m[..4].to_uppercase()).unwrap(), m);
+        assert!(matches!(get_oid(&repo, &m[..3]), Err(Error::BadRef(_))));
+        assert_eq!(abbreviate_oid(&repo, &m, 7).unwrap(), m[..7]);
+
+        // Store two blobs whose OIDs share their first four characters.
+        let mut seen: HashMap<String, String> = HashMap::new();
+        let (first, second) = (0..)
+            .find_map(|i| {
+                let contents = i.to_string();
+                let oid = data::compute_oid(contents.as_bytes(), "blob");
+                let other = seen.insert(oid[..4].to_owned(), contents.clone())?;
+                Some((other, contents))
+            })
+            .unwrap();
+        let first = data::hash_object(&repo, first.as_bytes(), "blob").unwrap();
+        let second = data::hash_object(&repo, second.as_bytes(), "blob").unwrap();
+        assert!(matches!(
+            get_oid(&repo, &first[..4]),
+            Err(Error::AmbiguousOid(prefix)) if prefix == first[..4]
+        ));
+        let length = abbreviate_oid(&repo, &first, 4).unwrap().len();
+        assert!(length > 4 && first[..length] != second[..length]);
+        assert_eq!(get_oid(&repo, &first[..length]).unwrap(), first);
+    }
+
+    #[test]
+    fn revision_ranges() {
+        let (_dir, repo) = init_repo();
+        let [c1, c2, c3, s, m] = make_merge_history(&repo);
+        let parse = |revisions: &[&str]| parse_revisions(&repo, revisions).unwrap();
+
+        let range = format!("{}..m", c1);
+        assert_eq!(parse(&[&range]), (vec![m.clone()], vec![c1.clone()]));
+        assert_eq!(parse(&["m", "^s"]), (vec![m.clone()], vec![s.clone()]));
+        let (include, exclude) = parse(&["s...m~"]);
+        assert_eq!(include, [s.clone(), c3]);
+        assert!(exclude.contains(&c2) && exclude.contains(&c1) && !exclude.contains(&s));
+        assert!(matches!(
+            parse_revisions(&repo, &[".."]),
+            Err(Error::BadRef(_))
+        ));
+        assert!(matches!(
+            parse_revisions(&repo, &["m..nonexistent"]),
+            Err(Error::BadRef(_))
+        ));
+    }
+
+    /// Build two lines of history from c1 that a merge brings together, as below, with commit
+    /// times in the order the commits are named and author times that put the "a" line after the
+    /// "b" line. Returns a map from each commit's name to its OID, and the reverse.
+    ///
+    ///     c1 - a1 - a2 - m
+    ///        \          /
+    ///         b1 - b2 -
+    fn make_parallel_history(repo: &Repository) -> (HashMap<&str, String>, HashMap<String, &str>) {
+        let mut oids: HashMap<&str, String> = HashMap::new();
+        let commits = [
+            ("c1", vec![], 1, 1),
+            ("a1", vec!["c1"], 5, 2),
+            ("b1", vec!["c1"], 3, 3),
+            ("a2", vec!["a1"], 6, 4),
+            ("b2", vec!["b1"], 4, 5),
+            ("m", vec!["a2", "b2"], 7, 6),
+        ];
+        for (name, parents, author_time, committer_time) in commits {
+            let parents: Vec<&str> = parents.iter().map(|parent| oids[parent].as_str()).collect();
+            let oid = make_commit_with_times(repo, &[], &parents, author_time, committer_time);
+            oids.insert(name, oid);
+        }
+        let names = oids
+            .iter()
+            .map(|(name, oid)| (oid.clone(), *name))
+            .collect();
+        (oids, names)
+    }
+
+    /// Walk the history of "m" from `make_parallel_history`, excluding the specified commits, and
+    /// return the names of the commits listed, checking that none is listed before a child.
+    fn walk(repo: &Repository, exclude: &[&str], options: &WalkOptions) -> Vec<String> {
+        let (oids, names) = make_parallel_history(repo);
+        let exclude: Vec<String> = exclude.iter().map(|name| oids[name].clone()).collect();
+        let walk = walk_revisions(repo, &[oids["m"].clone()], &exclude, options).unwrap();
+        for (i, (_, parents)) in walk.iter().enumerate() {
+            for parent in parents {
+                assert!(walk[i + 1..].iter().any(|(oid, _)| oid == parent));
             }
-        } else if path.is_file() {
-            fs::remove_file(path).expect("Failed to remove file");
         }
+        walk.iter().map(|(oid, _)| names[oid].to_owned()).collect()
+    }
+
+    fn walk_in_order(order: WalkOrder) -> Vec<String> {
+        let (_dir, repo) = init_repo();
+        let options = WalkOptions {
+            order,
+            ..WalkOptions::default()
+        };
+        walk(&repo, &[], &options)
+    }
+
+    #[test]
+    fn walk_in_date_order() {
+        assert_eq!(
+            walk_in_order(WalkOrder::Date),
+            ["m", "b2", "a2", "b1", "a1", "c1"]
+        );
+    }
+
+    #[test]
+    fn walk_in_author_date_order() {
+        assert_eq!(
+            walk_in_order(WalkOrder::AuthorDate),
+            ["m", "a2", "a1", "b2", "b1", "c1"]
+        );
+    }
+
+    #[test]
+    fn walk_in_topological_order() {
+        assert_eq!(
+            walk_in_order(WalkOrder::Topo),
+            ["m", "b2", "b1", "a2", "a1", "c1"]
+        );
+    }
+
+    #[test]
+    fn walk_first_parents() {
+        let (_dir, repo) = init_repo();
+        let options = WalkOptions {
+            first_parent: true,
+            ..WalkOptions::default()
+        };
+        assert_eq!(walk(&repo, &[], &options), ["m", "a2", "a1", "c1"]);
+    }
+
+    #[test]
+    fn walk_excluding_history() {
+        let (_dir, repo) = init_repo();
+        assert_eq!(
+            walk(&repo, &["b1"], &WalkOptions::default()),
+            ["m", "b2", "a2", "a1"]
+        );
+    }
+
+    #[test]
+    fn children_are_listed_before_parents_despite_clock_skew() {
+        let (_dir, repo) = init_repo();
+        // The merge and one of its parents claim to be older than their ancestors.
+        let root = make_commit(&repo, &[], &[], 10);
+        let left = make_commit(&repo, &[], &[&root], 1);
+        let right = make_commit(&repo, &[], &[&root], 20);
+        let merge = make_commit(&repo, &[], &[&left, &right], 2);
+        for order in [WalkOrder::Date, WalkOrder::AuthorDate, WalkOrder::Topo] {
+            let options = WalkOptions {
+                order,
+                ..WalkOptions::default()
+            };
+            let walk = walk_revisions(&repo, std::slice::from_ref(&merge), &[], &options).unwrap();
+            let listed: Vec<&str> = walk.iter().map(|(oid, _)| oid.as_str()).collect();
+            assert_eq!(listed[0], merge);
+            assert_eq!(listed[3], root);
+        }
+    }
+
+    #[test]
+    fn merge_base_is_the_nearest_common_ancestor() {
+        let (_dir, repo) = init_repo();
+        let c1 = make_commit(&repo, &[], &[], 1);
+        let c2 = make_commit(&repo, &[], &[&c1], 2);
+        let c3 = make_commit(&repo, &[], &[&c2], 3);
+        let side = make_commit(&repo, &[], &[&c1], 4);
+        // Searching the merge's parents depth first from the last would find c1 through the side
+        // branch before c2.
+        let merge = make_commit(&repo, &[], &[&c2, &side], 5);
+        assert_eq!(
+            get_merge_base(&repo, &c3, &merge).unwrap(),
+            Some(c2.clone())
+        );
+        assert_eq!(get_merge_base(&repo, &merge, &c3).unwrap(), Some(c2));
+
+        let unrelated = make_commit(&repo, &[], &[], 6);
+        assert_eq!(get_merge_base(&repo, &c3, &unrelated).unwrap(), None);
     }
 }
@@ -0,0 +1,197 @@
+use std::{collections::BTreeMap, path::Path};
+
+use super::{
+    base, data,
+    diff::{find_changed_lines, intern_lines, split_lines, DiffAlgorithm},
+    Error, Repository, Result,
+};
+
+/// The commit that last changed a line, the line's 1-based number in that commit's version of the
+/// file, and the line itself.
+pub type BlameLine = (String, usize, Vec<u8>);
+
+/// The blob of the file in a commit, and the lines of the blamed file that might have come from the
+/// commit, as their index in the blamed file and in that blob.
+type Suspect = (String, Vec<(usize, usize)>);
+
+/// Find the commit that last changed each line of the file at `path` in the commit with the
+/// specified OID, optionally only for the lines from `start` to `end` (1-based and inclusive).
+/// History is followed through every parent of a merge, so that each line is blamed on the commit
+/// that introduced it on whichever side of the merge it came from.
+pub fn blame(
+    repo: &Repository,
+    path: &Path,
+    oid: &str,
+    range: Option<(usize, usize)>,
+) -> Result<Vec<BlameLine>> {
+    let blob = get_blob_oid(repo, oid, path)?.ok_or_else(|| Error::PathNotInRevision {
+        path: path.display().to_string(),
+        revision: oid.to_owned(),
+    })?;
+    let contents = data::get_object(repo, &blob, Some("blob"))?;
+    let lines = split_lines(&contents);
+    let (start, end) = match range {
+        Some((start, end)) if start == 0 || start > end || end > lines.len() => {
+            return Err(Error::InvalidLineRange {
+                path: path.display().to_string(),
+                lines: lines.len(),
+            })
+        }
+        Some((start, end)) => (start - 1, end),
+        None => (0, lines.len()),
+    };
+
+    // The commits still to be examined, newest first, with the blob of the file each has and the
+    // lines that might have come from it.
+    let mut suspects: BTreeMap<(i64, String), Suspect> = BTreeMap::new();
+    suspects.insert(
+        (get_commit_time(repo, oid)?, oid.to_owned()),
+        (blob, (start..end).map(|i| (i, i)).collect()),
+    );
+    let mut blamed: Vec<Option<(String, usize)>> = vec![None; lines.len()];
+    while let Some(((_, oid), (blob, mut pending))) = suspects.pop_last() {
+        // Pass each line that a parent already had on to it, trying the parents in order.
+        for parent in base::get_commit(repo, &oid)?.parents {
+            if pending.is_empty() {
+                break;
+            }
+            let parent_blob = match get_blob_oid(repo, &parent, path)? {
+                Some(parent_blob) => parent_blob,
+                None => continue,
+            };
+            let passed: Vec<(usize, usize)> = if parent_blob == blob {
+                std::mem::take(&mut pending)
+            } else {
+                let origins = find_line_origins(repo, &parent_blob, &blob)?;
+                let (passed, kept): (Vec<_>, Vec<_>) = pending
+                    .into_iter()
+                    .partition(|(_, line)| origins[*line].is_some());
+                pending = kept;
+                passed
+                    .into_iter()
+                    .filter_map(|(i, line)| Some((i, origins[line]?)))
+                    .collect()
+            };
+            if !passed.is_empty() {
+                let key = (get_commit_time(repo, &parent)?, parent);
+                let suspect = suspects.entry(key).or_insert_with(|| (parent_blob, vec![]));
+                suspect.1.extend(passed);
+            }
+        }
+
+        // Whatever no parent had, this commit introduced.
+        for (i, line) in pending {
+            blamed[i] = Some((oid.clone(), line + 1));
+        }
+    }
+
+    // Every line should be blamed on some commit, if only the root commit that added it, so one
+    // that isn't means the history couldn't be followed.
+    (start..end)
+        .map(|i| match blamed[i].take() {
+            Some((blamed_oid, line)) => Ok((blamed_oid, line, lines[i].to_vec())),
+            None => Err(Error::corrupt_object(
+                oid,
+                &format!("no commit in its history added line {}", i + 1),
+            )),
+        })
+        .collect()
+}
+
+/// Return the OID of the blob at the specified path in a commit's tree, if there is one.
+fn get_blob_oid(repo: &Repository, oid: &str, path: &Path) -> Result<Option<String>> {
+    let tree = base::get_commit(repo, oid)?.tree;
+    base::get_oid_at_path(repo, &tree, path)
+}
+
+fn get_commit_time(repo: &Repository, oid: &str) -> Result<i64> {
+    let committer = base::get_commit(repo, oid)?.committer;
+    Ok(committer.map_or(0, |committer| committer.time))
+}
+
+/// For each line of the blob `new`, find the line of the blob `old` it was unchanged from, if any.
+fn find_line_origins(repo: &Repository, old: &str, new: &str) -> Result<Vec<Option<usize>>> {
+    let old_data = data::get_object(repo, old, Some("blob"))?;
+    let new_data = data::get_object(repo, new, Some("blob"))?;
+    let ids = intern_lines(&[&split_lines(&old_data), &split_lines(&new_data)]);
+    let (removed, added) = find_changed_lines(&ids[0], &ids[1], DiffAlgorithm::Myers);
+    let mut unchanged = (0..ids[0].len()).filter(|i| !removed[*i]);
+    Ok(added
+        .iter()
+        .map(|added| if *added { None } else { unchanged.next() })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ugit::testing::{init_repo, make_commit};
+
+    fn blame_line(oid: &str, line: usize, text: &str) -> BlameLine {
+        (oid.to_owned(), line, text.as_bytes().to_vec())
+    }
+
+    /// A root commit, a line added on each side of a branch, and a merge of the two that adds a
+    /// line of its own. Returns the OIDs of the root, the two sides and the merge.
+    fn make_merged_file(repo: &Repository) -> [String; 4] {
+        let root = make_commit(repo, &[("f.txt", "a\nb\nc\n")], &[], 1);
+        let side = make_commit(repo, &[("f.txt", "a\nb\nc\nside\n")], &[&root], 2);
+        let main = make_commit(repo, &[("f.txt", "main\na\nb\nc\n")], &[&root], 3);
+        let merge = make_commit(
+            repo,
+            &[("f.txt", "main\na\nb\nc\nside\nmerge\n")],
+            &[&main, &side],
+            4,
+        );
+        [root, main, side, merge]
+    }
+
+    #[test]
+    fn lines_are_blamed_through_both_parents_of_a_merge() {
+        let (_dir, repo) = init_repo();
+        let [root, main, side, merge] = make_merged_file(&repo);
+        assert_eq!(
+            blame(&repo, Path::new("f.txt"), &merge, None).unwrap(),
+            [
+                blame_line(&main, 1, "main\n"),
+                blame_line(&root, 1, "a\n"),
+                blame_line(&root, 2, "b\n"),
+                blame_line(&root, 3, "c\n"),
+                blame_line(&side, 4, "side\n"),
+                blame_line(&merge, 6, "merge\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn line_ranges() {
+        let (_dir, repo) = init_repo();
+        let [root, _, side, merge] = make_merged_file(&repo);
+        let path = Path::new("f.txt");
+        assert_eq!(
+            blame(&repo, path, &merge, Some((4, 5))).unwrap(),
+            [blame_line(&root, 3, "c\n"), blame_line(&side, 4, "side\n")]
+        );
+        assert_eq!(
+            blame(&repo, path, &merge, Some((6, 6))).unwrap(),
+            [blame_line(&merge, 6, "merge\n")]
+        );
+
+        for range in [(0, 1), (3, 2), (1, 7)] {
+            let result = blame(&repo, path, &merge, Some(range));
+            assert!(
+                matches!(result, Err(Error::InvalidLineRange { lines: 6, .. })),
+                "{:?}",
+                range
+            );
+        }
+    }
+
+    #[test]
+    fn missing_path() {
+        let (_dir, repo) = init_repo();
+        let [root, ..] = make_merged_file(&repo);
+        let result = blame(&repo, Path::new("missing.txt"), &root, None);
+        assert!(matches!(result, Err(Error::PathNotInRevision { .. })));
+    }
+}
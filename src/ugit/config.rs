@@ -0,0 +1,56 @@
+use std::{env, fs, io, path::Path};
+
+use super::{Repository, Result};
+
+/// Look up the value of a configuration variable such as "user.name". The repository's config file
+/// takes precedence over the user's global ~/.ugitconfig.
+pub fn get(repo: &Repository, name: &str) -> Result<Option<String>> {
+    if let Some(value) = get_from_file(&repo.git_path("config"), name)? {
+        return Ok(Some(value));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        return get_from_file(&Path::new(&home).join(".ugitconfig"), name);
+    }
+    Ok(None)
+}
+
+/// Look up the value of a configuration variable in a git-style config file. The last assignment to
+/// the variable wins, as it does in git.
+fn get_from_file(path: &Path, name: &str) -> Result<Option<String>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let name = name.to_lowercase();
+    let mut section = String::new();
+    let mut value = None;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = match header.split_once(' ') {
+                Some((name, subsection)) => {
+                    format!("{}.{}", name, subsection.trim().trim_matches('"'))
+                }
+                None => header.to_owned(),
+            };
+            continue;
+        }
+
+        let (key, key_value) = match line.split_once('=') {
+            Some((key, key_value)) => (key.trim(), key_value.trim()),
+            // A key without a value is a boolean set to true.
+            None => (line, "true"),
+        };
+        if format!("{}.{}", section, key).to_lowercase() == name {
+            value = Some(key_value.trim_matches('"').to_owned());
+        }
+    }
+
+    Ok(value)
+}
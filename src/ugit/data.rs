@@ -1,9 +1,17 @@
-use std::{fmt::Write as _, fs, io::Write as _, path::Path, path::PathBuf};
-
+use std::{
+    fmt::Write as _,
+    fs, io,
+    io::{Read as _, Write as _},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
 use sha1::{Digest, Sha1};
+use tempfile::NamedTempFile;
 use walkdir::WalkDir;
 
-use super::DEFAULT_REPO;
+use super::{Error, Repository, Result};
 
 #[derive(Debug)]
 pub struct RefValue {
@@ -11,114 +19,271 @@ pub struct RefValue {
     pub value: Option<String>,
 }
 
-/// Create a new ugit repository.
-pub fn init() {
-    let mut path = PathBuf::from(DEFAULT_REPO);
-    fs::create_dir(&path).expect("Failed to create .ugit directory");
+/// A change to a ref, as recorded in its reflog.
+#[derive(Debug)]
+pub struct ReflogEntry {
+    /// The OID the ref pointed to before the change, or None if it didn't exist.
+    pub old: Option<String>,
+    pub new: String,
+    /// Who made the change and when, formatted as in a commit header.
+    pub identity: String,
+    /// Why the ref changed, e.g. "commit: Fix typo".
+    pub message: String,
+}
+
+/// The OID written in a reflog for a ref that didn't exist.
+const NULL_OID: &str = "0000000000000000000000000000000000000000";
+
+/// Create the data directory for a new ugit repository.
+pub fn init(repo: &Repository) -> Result<()> {
+    fs::create_dir(&repo.git_dir)?;
+    fs::create_dir(repo.git_path("objects"))?;
+    // git won't recognise the directory as a repository without a refs directory.
+    fs::create_dir(repo.git_path("refs"))?;
 
-    path.push("objects");
-    fs::create_dir(&path).expect("Unable to create objects directory");
+    Ok(())
 }
 
-/// Adds a new object to the object store and return it's OID.
-pub fn hash_object(data: &[u8], object_type: &str) -> String {
-    let oid = generate_oid(data);
+/// Adds a new object to the object store and return it's OID. Objects are stored the same way git
+/// stores loose objects: a "type size\0" header followed by the data, compressed with zlib.
+pub fn hash_object(repo: &Repository, data: &[u8], object_type: &str) -> Result<String> {
+    let object = serialise_object(data, object_type);
+    let oid = generate_oid(&object);
 
-    // Write the data to a file, using the OID as the filename.
-    let path: PathBuf = get_object_path(&PathBuf::from(DEFAULT_REPO), &oid);
-    let mut file = fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(&path)
-        .expect("Failed to open object file for writing");
-    file.write_all(object_type.as_bytes())
-        .expect("Failed to write object type");
-    file.write_all(b"\x00").expect("Failed to write null byte");
-    file.write_all(data).expect("Failed to write file data");
+    let path: PathBuf = get_object_path(repo, &oid);
+    if path.is_file() {
+        // Objects are immutable, so there's nothing to do if it's already stored.
+        return Ok(oid);
+    }
 
-    oid
+    // Write to a temporary file first so that a partially written object is never visible.
+    let parent = path.parent().unwrap_or(&repo.git_dir);
+    fs::create_dir_all(parent)?;
+    let mut encoder = ZlibEncoder::new(NamedTempFile::new_in(parent)?, Compression::default());
+    encoder.write_all(&object)?;
+    encoder
+        .finish()?
+        .persist(&path)
+        .map_err(|err| Error::Io(err.error))?;
+
+    Ok(oid)
+}
+
+/// Return the OID that the specified data would have in the object store, without storing it.
+pub fn compute_oid(data: &[u8], object_type: &str) -> String {
+    generate_oid(&serialise_object(data, object_type))
+}
+
+fn serialise_object(data: &[u8], object_type: &str) -> Vec<u8> {
+    let mut object = format!("{} {}\0", object_type, data.len()).into_bytes();
+    object.extend_from_slice(data);
+    object
 }
 
 /// Retrieves the object with the specified OID from the object store.
-pub fn get_object(repo_path: &Path, oid: &str, expected_type: Option<&str>) -> Vec<u8> {
-    let path: PathBuf = get_object_path(repo_path, oid);
-    let contents = fs::read(path).expect("Failed to read file data");
+pub fn get_object(repo: &Repository, oid: &str, expected_type: Option<&str>) -> Result<Vec<u8>> {
+    let (object_type, data) = read_object(repo, oid)?;
+
+    if let Some(expected_type) = expected_type {
+        // Check whether the object type stored in the header is the expected type.
+        if expected_type != object_type {
+            return Err(Error::TypeMismatch {
+                oid: oid.to_owned(),
+                expected: expected_type.to_owned(),
+                actual: object_type,
+            });
+        }
+    }
+
+    Ok(data)
+}
 
-    // Find the index of the null byte that separates the object type from the data.
+/// Return the type of the object with the specified OID, e.g. "commit".
+pub fn get_object_type(repo: &Repository, oid: &str) -> Result<String> {
+    Ok(read_object(repo, oid)?.0)
+}
+
+/// Read an object from the object store, returning its type and data.
+fn read_object(repo: &Repository, oid: &str) -> Result<(String, Vec<u8>)> {
+    let path: PathBuf = get_object_path(repo, oid);
+    let file = fs::File::open(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => Error::ObjectNotFound(oid.to_owned()),
+        _ => Error::Io(err),
+    })?;
+    let mut contents = vec![];
+    ZlibDecoder::new(file)
+        .read_to_end(&mut contents)
+        .map_err(|_| Error::corrupt_object(oid, "failed to decompress object"))?;
+
+    // Find the index of the null byte that separates the header from the data.
     let index = contents
         .iter()
         .position(|b| *b == 0)
-        .expect("Failed to find null byte object type separator");
-    // Split the data on the null byte.
-    let object_type = &contents[0..index];
+        .ok_or_else(|| Error::corrupt_object(oid, "missing object header separator"))?;
+    let header = std::str::from_utf8(&contents[0..index])
+        .map_err(|_| Error::corrupt_object(oid, "object header is not valid UTF-8"))?;
     let data = &contents[index + 1..];
 
-    if let Some(expected_type) = expected_type {
-        // Check whether the object type stored in the data is the expected type.
-        assert!(expected_type.as_bytes() == object_type);
+    let (object_type, size) = header
+        .split_once(' ')
+        .ok_or_else(|| Error::corrupt_object(oid, "malformed object header"))?;
+    if size.parse() != Ok(data.len()) {
+        return Err(Error::corrupt_object(
+            oid,
+            "object size doesn't match header",
+        ));
     }
 
-    data.to_vec()
+    Ok((object_type.to_owned(), data.to_vec()))
 }
 
 /// Map the specified reference to the specified value.
-pub fn update_ref(repo_path: &Path, reference: &str, value: &RefValue, deref: bool) {
-    let reference = get_ref_internal(repo_path, reference, deref).0;
+pub fn update_ref(repo: &Repository, reference: &str, value: &RefValue, deref: bool) -> Result<()> {
+    let reference = get_ref_internal(repo, reference, deref)?.0;
 
-    assert!(value.value.is_some());
+    let target = value
+        .value
+        .as_ref()
+        .ok_or_else(|| Error::BadRef(reference.clone()))?;
     let new_ref_value = if value.symbolic {
-        format!("ref: {}", value.value.as_ref().unwrap())
+        format!("ref: {}", target)
     } else {
-        value.value.to_owned().unwrap()
+        target.to_owned()
     };
 
-    let mut ref_path = PathBuf::from(repo_path);
-    ref_path.push(reference);
-    fs::create_dir_all(ref_path.parent().unwrap())
-        .expect("Failed to create reference directory structure");
-    fs::write(ref_path, new_ref_value).expect("Failed to update reference");
+    let ref_path = repo.git_path(reference);
+    if let Some(parent) = ref_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(ref_path, new_ref_value)?;
+
+    Ok(())
 }
 
 /// Retrieves the OID that the specified reference is mapped to.
-pub fn get_ref(repo_path: &Path, reference: &str, deref: bool) -> RefValue {
-    let ref_value = get_ref_internal(repo_path, reference, deref);
-    ref_value.1
+pub fn get_ref(repo: &Repository, reference: &str, deref: bool) -> Result<RefValue> {
+    let ref_value = get_ref_internal(repo, reference, deref)?;
+    Ok(ref_value.1)
 }
 
-fn get_ref_internal(repo_path: &Path, reference: &str, deref: bool) -> (String, RefValue) {
-    let mut ref_path = PathBuf::from(repo_path);
-    ref_path.push(reference);
+fn get_ref_internal(repo: &Repository, reference: &str, deref: bool) -> Result<(String, RefValue)> {
+    let ref_path = repo.git_path(reference);
 
     let mut value: Option<String> = None;
 
     let reference: String = reference.to_owned();
     if ref_path.is_file() {
-        let ref_string = fs::read_to_string(ref_path).expect("Failed to read reference");
+        let ref_string = fs::read_to_string(ref_path)?;
         value = Some(ref_string);
     }
 
-    let symbolic = value.as_ref().map_or(false, |s| s.starts_with("ref:"));
+    let symbolic = value.as_ref().is_some_and(|s| s.starts_with("ref:"));
     if symbolic {
         value = value.as_ref().map(|s| s.replacen("ref: ", "", 1));
         if deref {
-            return get_ref_internal(repo_path, &value.unwrap(), true);
+            if let Some(value) = value {
+                return get_ref_internal(repo, &value, true);
+            }
         }
     }
-    return (reference, RefValue { symbolic, value });
+    Ok((reference, RefValue { symbolic, value }))
+}
+
+/// Return the name of the ref that the specified reference resolves to after following any
+/// symbolic refs, along with its value.
+pub fn resolve_ref(repo: &Repository, reference: &str) -> Result<(String, RefValue)> {
+    get_ref_internal(repo, reference, true)
+}
+
+/// Add an entry to the end of the reflog of the specified ref. Entries are stored one per line in
+/// the same format as git: "<old oid> <new oid> <identity>\t<message>".
+pub fn append_reflog(repo: &Repository, reference: &str, entry: &ReflogEntry) -> Result<()> {
+    let log_path = repo.git_path("logs").join(reference);
+    if let Some(parent) = log_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // The message is a single line, so any line breaks in it are flattened.
+    let message = entry
+        .message
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+    writeln!(
+        log,
+        "{} {} {}\t{}",
+        entry.old.as_deref().unwrap_or(NULL_OID),
+        entry.new,
+        entry.identity,
+        message
+    )?;
+    Ok(())
+}
+
+/// Return the entries in the reflog of the specified ref, oldest first. A ref without a reflog
+/// has no entries.
+pub fn get_reflog(repo: &Repository, reference: &str) -> Result<Vec<ReflogEntry>> {
+    let log_path = repo.git_path("logs").join(reference);
+    if !log_path.is_file() {
+        return Ok(vec![]);
+    }
+
+    let mut entries = vec![];
+    for line in fs::read_to_string(log_path)?.lines() {
+        let malformed = || Error::CorruptReflog(reference.to_owned());
+        let (header, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut parts = header.splitn(3, ' ');
+        let is_oid = |oid: &str| oid.len() == 40 && oid.chars().all(|c| c.is_ascii_hexdigit());
+        let (old, new, identity) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(old), Some(new), Some(identity)) if is_oid(old) && is_oid(new) => {
+                (old, new, identity)
+            }
+            _ => return Err(malformed()),
+        };
+        entries.push(ReflogEntry {
+            old: Some(old.to_owned()).filter(|old| old != NULL_OID),
+            new: new.to_owned(),
+            identity: identity.to_owned(),
+            message: message.to_owned(),
+        });
+    }
+    Ok(entries)
 }
 
-pub fn delete_ref(reference: &str, deref: bool) {
-    let reference = get_ref_internal(&PathBuf::from(DEFAULT_REPO), reference, deref).0;
-    let mut ref_path = PathBuf::from(DEFAULT_REPO);
-    ref_path.push(reference);
-    fs::remove_file(ref_path).expect("Failed to delete ref");
+/// Return the names of all the refs that have a reflog.
+pub fn get_reflog_names(repo: &Repository) -> Result<Vec<String>> {
+    let logs_path = repo.git_path("logs");
+    let mut names = vec![];
+    for entry in WalkDir::new(&logs_path).into_iter().flatten() {
+        if entry.path().is_file() {
+            let name = entry
+                .path()
+                .strip_prefix(&logs_path)
+                .unwrap_or(entry.path());
+            let name = name
+                .to_str()
+                .ok_or_else(|| Error::InvalidPath(name.to_owned()))?;
+            names.push(name.to_owned());
+        }
+    }
+    Ok(names)
 }
 
-pub fn get_refs(repo_path: &Path, prefix: Option<&str>, deref: bool) -> Vec<(String, RefValue)> {
-    let mut refs_path = PathBuf::from(repo_path);
-    refs_path.push("refs");
+pub fn delete_ref(repo: &Repository, reference: &str, deref: bool) -> Result<()> {
+    let reference = get_ref_internal(repo, reference, deref)?.0;
+    fs::remove_file(repo.git_path(reference))?;
+    Ok(())
+}
 
-    let mut ref_names = find_ref_names(&refs_path);
+pub fn get_refs(
+    repo: &Repository,
+    prefix: Option<&str>,
+    deref: bool,
+) -> Result<Vec<(String, RefValue)>> {
+    let mut ref_names = find_ref_names(&repo.git_path("refs"))?;
     ref_names.push("HEAD".to_string());
     ref_names.push("MERGE_HEAD".to_string());
 
@@ -129,7 +294,7 @@ pub fn get_refs(repo_path: &Path, prefix: Option<&str>, deref: bool) -> Vec<(Str
                 continue;
             }
         }
-        let ref_value = get_ref(repo_path, &ref_name, deref);
+        let ref_value = get_ref(repo, &ref_name, deref)?;
         if ref_value.value.is_some() {
             // This is mainly to handle MERGE_HEAD which will only exist if we're in the middle of a
             // merge.
@@ -137,22 +302,26 @@ pub fn get_refs(repo_path: &Path, prefix: Option<&str>, deref: bool) -> Vec<(Str
         }
     }
 
-    refs_to_values
+    Ok(refs_to_values)
 }
 
-fn find_ref_names(path: &Path) -> Vec<String> {
+fn find_ref_names(path: &Path) -> Result<Vec<String>> {
     let mut ref_names: Vec<String> = vec![];
 
-    for entry in WalkDir::new(path) {
-        if let Ok(entry) = entry {
-            if entry.path().is_file() {
-                let ref_name = entry.path().strip_prefix(path.parent().unwrap()).unwrap();
-                ref_names.push(ref_name.as_os_str().to_str().unwrap().to_owned());
-            }
+    for entry in WalkDir::new(path).into_iter().flatten() {
+        if entry.path().is_file() {
+            let ref_name = entry
+                .path()
+                .strip_prefix(path.parent().unwrap_or(path))
+                .unwrap_or(entry.path());
+            let ref_name = ref_name
+                .to_str()
+                .ok_or_else(|| Error::InvalidPath(ref_name.to_owned()))?;
+            ref_names.push(ref_name.to_owned());
         }
     }
 
-    ref_names
+    Ok(ref_names)
 }
 
 /// Generates an OID from a byte vector.
@@ -162,59 +331,192 @@ fn generate_oid(bytes: &[u8]) -> String {
     let result = hasher.finalize();
     let mut oid = String::new();
     for byte in result {
-        write!(&mut oid, "{:02x}", byte).expect("Unable to construct object filename");
+        // Writing to a String can't fail.
+        let _ = write!(&mut oid, "{:02x}", byte);
     }
     oid
 }
 
-/// Return the path to an object in the object database.
-fn get_object_path(repo_path: &Path, oid: &str) -> PathBuf {
-    let mut path = PathBuf::from(repo_path);
-    path.push("objects");
-    path.push(oid);
+/// Return the path to an object in the object database. Objects are fanned out into subdirectories
+/// named after the first two characters of their OID to keep directories small.
+fn get_object_path(repo: &Repository, oid: &str) -> PathBuf {
+    let mut path = repo.git_path("objects");
+    if oid.len() > 2 && oid.is_char_boundary(2) {
+        path.push(&oid[..2]);
+        path.push(&oid[2..]);
+    } else {
+        path.push(oid);
+    }
     path
 }
 
-pub fn fetch_object_if_missing(remote_path: &Path, oid: &str) {
-    let mut to = PathBuf::new();
-    to.push(DEFAULT_REPO);
-    to.push("objects");
-    to.push(oid);
+/// Copy the object with the specified OID from the remote repository, if it isn't already in the
+/// local object store.
+pub fn fetch_object_if_missing(repo: &Repository, remote: &Repository, oid: &str) -> Result<()> {
+    let to = get_object_path(repo, oid);
 
     if to.exists() {
         // Object already exists so don't copy.
-        return;
+        return Ok(());
     }
 
-    let mut from = remote_path.to_path_buf();
-    from.push("objects");
-    from.push(oid);
+    let from = get_object_path(remote, oid);
 
-    fs::copy(from, to).expect(&format!("Failed to copy remote object with OID {}", oid));
+    if !from.exists() {
+        return Err(Error::ObjectNotFound(oid.to_owned()));
+    }
+    copy_object(&from, &to)
 }
 
-pub fn push_object(remote_path: &Path, oid: &str) {
-    let mut remote_object_path = PathBuf::from(remote_path);
-    remote_object_path.push("objects");
-    remote_object_path.push(oid);
+/// Copy the object with the specified OID to the remote repository, if it doesn't already have it.
+pub fn push_object(repo: &Repository, remote: &Repository, oid: &str) -> Result<()> {
+    let remote_object_path = get_object_path(remote, oid);
 
     if remote_object_path.exists() {
         // Object already exists so don't copy.
-        return;
+        return Ok(());
+    }
+
+    let local_object_path = get_object_path(repo, oid);
+
+    if !local_object_path.exists() {
+        return Err(Error::ObjectNotFound(oid.to_owned()));
     }
+    copy_object(&local_object_path, &remote_object_path)
+}
 
-    let mut local_object_path = PathBuf::from(DEFAULT_REPO);
-    local_object_path.push("objects");
-    local_object_path.push(oid);
+fn copy_object(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(from, to)?;
+    Ok(())
+}
 
-    fs::copy(local_object_path, remote_object_path)
-        .expect(&format!("Failed to copy local object with OID {}", oid));
+/// Return the OIDs of all the objects in the object store, along with when each was last
+/// modified.
+pub fn get_object_oids(repo: &Repository) -> Result<Vec<(String, SystemTime)>> {
+    let objects_path = repo.git_path("objects");
+    let mut oids = vec![];
+    for entry in WalkDir::new(&objects_path).min_depth(2).max_depth(2) {
+        let entry = entry?;
+        let (dir, file) = match (entry.path().parent(), entry.path().file_name()) {
+            (Some(dir), Some(file)) => (dir, file),
+            _ => continue,
+        };
+        let oid = format!(
+            "{}{}",
+            dir.file_name().unwrap_or_default().to_string_lossy(),
+            file.to_string_lossy()
+        );
+        // Skip anything that isn't an object, such as a temporary file left by a failed write.
+        if oid.len() == 40 && oid.chars().all(|c| c.is_ascii_hexdigit()) {
+            oids.push((oid, entry.metadata()?.modified()?));
+        }
+    }
+    Ok(oids)
 }
 
-pub fn object_exists(repo_path: &Path, oid: &str) -> bool {
-    let mut object_path = PathBuf::from(repo_path);
-    object_path.push("objects");
-    object_path.push(oid);
+/// Return the OIDs of the objects in the object store that begin with the specified prefix.
+pub fn find_objects_with_prefix(repo: &Repository, prefix: &str) -> Result<Vec<String>> {
+    if prefix.len() < 2 || !prefix.is_char_boundary(2) {
+        return Ok(vec![]);
+    }
+    let dir = repo.git_path("objects").join(&prefix[..2]);
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
 
-    return object_path.is_file();
+    let mut oids = vec![];
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(rest) = name.to_str() {
+            let oid = format!("{}{}", &prefix[..2], rest);
+            if oid.len() == 40 && oid.starts_with(prefix) {
+                oids.push(oid);
+            }
+        }
+    }
+    oids.sort();
+    Ok(oids)
+}
+
+/// Remove the object with the specified OID from the object store.
+pub fn delete_object(repo: &Repository, oid: &str) -> Result<()> {
+    let path = get_object_path(repo, oid);
+    fs::remove_file(&path)?;
+    // Leave no empty fan-out directories behind.
+    if let Some(parent) = path.parent() {
+        if fs::read_dir(parent)?.next().is_none() {
+            fs::remove_dir(parent)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn object_exists(repo: &Repository, oid: &str) -> bool {
+    get_object_path(repo, oid).is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ugit::testing::init_repo;
+
+    fn entry(old: Option<&str>, new: &str, message: &str) -> ReflogEntry {
+        ReflogEntry {
+            old: old.map(str::to_owned),
+            new: new.to_owned(),
+            identity: "A U Thor <author@example.com> 1700000000 +0100".to_owned(),
+            message: message.to_owned(),
+        }
+    }
+
+    #[test]
+    fn reflog_round_trip() {
+        let (_dir, repo) = init_repo();
+        assert!(get_reflog(&repo, "refs/heads/master").unwrap().is_empty());
+
+        let first = "1".repeat(40);
+        let second = "2".repeat(40);
+        append_reflog(
+            &repo,
+            "refs/heads/master",
+            &entry(None, &first, "commit (initial): a"),
+        )
+        .unwrap();
+        append_reflog(
+            &repo,
+            "refs/heads/master",
+            &entry(Some(&first), &second, "commit: b\n\nbody"),
+        )
+        .unwrap();
+
+        let entries = get_reflog(&repo, "refs/heads/master").unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].old, None);
+        assert_eq!(entries[0].new, first);
+        assert_eq!(entries[0].message, "commit (initial): a");
+        assert_eq!(entries[1].old.as_deref(), Some(first.as_str()));
+        assert_eq!(entries[1].new, second);
+        assert_eq!(
+            entries[1].identity,
+            "A U Thor <author@example.com> 1700000000 +0100"
+        );
+        // Messages are flattened onto one line.
+        assert_eq!(entries[1].message, "commit: b body");
+
+        assert_eq!(get_reflog_names(&repo).unwrap(), ["refs/heads/master"]);
+    }
+
+    #[test]
+    fn malformed_reflog() {
+        let (_dir, repo) = init_repo();
+        fs::create_dir_all(repo.git_path("logs")).unwrap();
+        fs::write(repo.git_path("logs/HEAD"), "not a reflog entry\n").unwrap();
+        assert!(matches!(
+            get_reflog(&repo, "HEAD"),
+            Err(Error::CorruptReflog(reference)) if reference == "HEAD"
+        ));
+    }
 }
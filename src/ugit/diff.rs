@@ -1,57 +1,168 @@
-use std::process::Command;
-use std::{collections::HashMap, ffi::OsString};
-use std::{io::Write, path::PathBuf};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use tempfile::NamedTempFile;
+use super::merge::{self, MergeFavor, MergeOptions};
+use super::{base, data, relative_to_prefix, Error, Repository, Result, Tree, MODE_SYMLINK};
 
-use super::{data, Tree, DEFAULT_REPO};
+/// Which algorithm to use to find the lines that differ between two files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    /// The classic Myers algorithm, which finds a minimal diff.
+    Myers,
+    /// Anchors the diff on lines that appear exactly once in each file.
+    Patience,
+    /// Anchors the diff on the least frequent lines, like patience but tolerating repeats.
+    Histogram,
+}
+
+/// How to show the changes within lines when diffing word by word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordDiff {
+    /// Mark removed and added words with [-...-] and {+...+}.
+    Plain,
+    /// Show removed and added words by their colour alone.
+    Color,
+    /// A format for scripts, with a line for each run of words prefixed by ' ', '-' or '+', and a
+    /// line of '~' for each line break.
+    Porcelain,
+}
+
+/// Options controlling how the differences between files are found and shown.
+#[derive(Debug, Clone, Copy)]
+pub struct DiffOptions {
+    pub algorithm: DiffAlgorithm,
+    /// The number of unchanged lines to show around each change.
+    pub context: usize,
+    /// How similar, as a percentage, a deleted and an added file must be to be shown as a rename,
+    /// or None to not look for renames.
+    pub renames: Option<usize>,
+    /// Whether to also look for files copied from files modified in the same change.
+    pub copies: bool,
+    /// Whether to highlight the output with ANSI colours.
+    pub color: bool,
+    /// Whether to colour blocks of lines moved from one place to another differently from other
+    /// removed and added lines.
+    pub color_moved: bool,
+    /// Show the changes within lines word by word rather than as whole lines.
+    pub word_diff: Option<WordDiff>,
+}
+
+/// The similarity a deleted and an added file need to be treated as a rename when no other
+/// threshold is given.
+pub const DEFAULT_RENAME_THRESHOLD: usize = 50;
 
-fn diff_blobs(o_from: Option<&str>, o_to: Option<&str>, path: &str) -> String {
-    let mut f_from = NamedTempFile::new().expect("Failed to create temp file");
-    let mut f_to = NamedTempFile::new().expect("Failed to create temp file");
+const RESET: &str = "\x1b[m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+/// The colours of lines moved away from and to another place in the diff.
+const MOVED_FROM: &str = "\x1b[1;35m";
+const MOVED_TO: &str = "\x1b[1;36m";
 
-    if let Some(o_from) = o_from {
-        let data = data::get_object(&PathBuf::from(DEFAULT_REPO), o_from, Some("blob"));
-        let s = std::str::from_utf8(&data).expect("Failed to convert data to string");
-        write!(f_from, "{}", s).expect("Failed to write to temp file");
+/// A file found to have been renamed or copied: the path it came from, the path it was found at,
+/// how similar the two versions are as a percentage, and whether it was copied, leaving the
+/// original in place.
+pub type Rename = (OsString, OsString, usize, bool);
+
+impl Default for DiffOptions {
+    fn default() -> DiffOptions {
+        DiffOptions {
+            algorithm: DiffAlgorithm::Myers,
+            context: 3,
+            renames: None,
+            copies: false,
+            color: false,
+            color_moved: false,
+            word_diff: None,
+        }
     }
+}
+
+/// The difference between two versions of a file.
+#[derive(Debug)]
+pub struct FileDiff {
+    /// The path of the file, relative to the root of the working tree.
+    pub path: OsString,
+    /// The OID and mode of the file before the change, unless it was added.
+    pub old: Option<(String, u32)>,
+    /// The OID and mode of the file after the change, unless it was deleted.
+    pub new: Option<(String, u32)>,
+    /// For a renamed or copied file, the path it came from, how similar the two versions are as a
+    /// percentage, and whether it was copied.
+    pub origin: Option<(OsString, usize, bool)>,
+    pub content: ContentDiff,
+}
 
-    if let Some(o_to) = o_to {
-        let data = data::get_object(&PathBuf::from(DEFAULT_REPO), o_to, Some("blob"));
-        let s = std::str::from_utf8(&data).expect("Failed to convert data to string");
-        write!(f_to, "{}", s).expect("Failed to write to temp file");
+impl FileDiff {
+    /// The number of lines added and removed, or None for a binary file.
+    pub fn line_counts(&self) -> Option<(usize, usize)> {
+        let hunks = match &self.content {
+            ContentDiff::Unchanged => return Some((0, 0)),
+            ContentDiff::Binary => return None,
+            ContentDiff::Text(hunks) => hunks,
+        };
+        let lines = hunks.iter().flat_map(|hunk| &hunk.lines);
+        Some(lines.fold((0, 0), |(added, removed), line| match line {
+            DiffLine::Context(_) => (added, removed),
+            DiffLine::Removed(_) => (added, removed + 1),
+            DiffLine::Added(_) => (added + 1, removed),
+        }))
     }
+}
 
-    let mut diff_command = Command::new("diff");
-    diff_command.arg("--unified").arg("--show-c-function");
-    diff_command
-        .arg("--label")
-        .arg(format!("a/{}", path))
-        .arg(f_from.path().to_str().unwrap());
-    diff_command
-        .arg("--label")
-        .arg(format!("b/{}", path))
-        .arg(f_to.path().to_str().unwrap());
+#[derive(Debug)]
+pub enum ContentDiff {
+    /// The contents are the same and only the mode changed.
+    Unchanged,
+    /// One of the versions isn't text, so there are no lines to compare.
+    Binary,
+    Text(Vec<Hunk>),
+}
 
-    let diff_output = diff_command.output().unwrap();
+/// A group of nearby changes along with the unchanged lines surrounding them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    /// The 1-based line number of the first line of the hunk in the old file, or of the line
+    /// before it if the hunk doesn't include any old lines.
+    pub old_start: usize,
+    pub old_lines: usize,
+    /// The 1-based line number of the first line of the hunk in the new file, or of the line
+    /// before it if the hunk doesn't include any new lines.
+    pub new_start: usize,
+    pub new_lines: usize,
+    /// The nearest line before the hunk that looks like the start of a function.
+    pub function: Option<Vec<u8>>,
+    pub lines: Vec<DiffLine>,
+}
 
-    std::str::from_utf8(&diff_output.stdout)
-        .expect("Failed to convert diff output to string")
-        .to_owned()
+/// A line of a hunk, including its line ending if it has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Context(Vec<u8>),
+    Removed(Vec<u8>),
+    Added(Vec<u8>),
 }
 
-/// Returns a map of the paths contained in the specified trees, mapped to a list of the OIDs of
-/// corresponding objects. The list of OIDs is indexed based on which tree the object is contained
+/// Returns a map of the paths contained in the specified trees, mapped to a list of the OIDs and
+/// modes of corresponding objects. The list is indexed based on which tree the object is contained
 /// in.
-fn compare_trees(trees: &[&Tree]) -> HashMap<OsString, Vec<Option<String>>> {
-    let mut entries: HashMap<OsString, Vec<Option<String>>> = HashMap::new();
+pub(crate) fn compare_trees(trees: &[&Tree]) -> BTreeMap<OsString, Vec<Option<(String, u32)>>> {
+    let mut entries: BTreeMap<OsString, Vec<Option<(String, u32)>>> = BTreeMap::new();
 
     for (i, tree) in trees.iter().enumerate() {
-        for (oid, path) in *tree {
-            let oids = entries
+        for (oid, path, mode) in *tree {
+            let objects = entries
                 .entry(path.to_owned())
                 .or_insert_with(|| vec![None; trees.len()]);
-            oids[i] = Some(oid.clone());
+            objects[i] = Some((oid.clone(), *mode));
         }
     }
 
@@ -59,109 +170,1953 @@ fn compare_trees(trees: &[&Tree]) -> HashMap<OsString, Vec<Option<String>>> {
 }
 
 /// Returns a list of tuples containing all of the paths that differ between the specified trees
-/// along with a status message indicating how they differ.
-pub fn get_changed_files(t_from: &Tree, t_to: &Tree) -> Vec<(OsString, &'static str)> {
+/// along with a status message indicating how they differ, and for renamed or copied files the path
+/// they came from. `renames` lists the files found to have been renamed or copied between the trees.
+pub fn get_changed_files(
+    t_from: &Tree,
+    t_to: &Tree,
+    renames: &[Rename],
+) -> Vec<(OsString, &'static str, Option<OsString>)> {
     let mut result = vec![];
 
-    for (path, oids) in compare_trees(&[t_from, t_to]) {
-        let o_from = &oids[0];
-        let o_to = &oids[1];
-        if o_from != o_to {
-            let action = if o_from.is_none() {
-                "new file"
-            } else if o_to.is_none() {
-                "deleted"
+    for (path, objects) in compare_trees(&[t_from, t_to]) {
+        if let Some((from, _, _, copied)) = renames.iter().find(|(_, to, _, _)| *to == path) {
+            let action = if *copied { "copied" } else { "renamed" };
+            result.push((path, action, Some(from.clone())));
+            continue;
+        }
+        let action = match (&objects[0], &objects[1]) {
+            (None, None) => continue,
+            // A renamed file is listed under its new path instead.
+            (Some(_), None) if is_renamed(renames, &path) => continue,
+            (None, Some(_)) => "new file",
+            (Some(_), None) => "deleted",
+            (Some((_, m_from)), Some((_, m_to)))
+                if (*m_from == MODE_SYMLINK) != (*m_to == MODE_SYMLINK) =>
+            {
+                "typechange"
+            }
+            (Some(from), Some(to)) if from != to => "modified",
+            _ => continue,
+        };
+        result.push((path, action, None));
+    }
+
+    result
+}
+
+/// Whether the specified path was renamed, rather than copied, to another.
+fn is_renamed(renames: &[Rename], path: &OsString) -> bool {
+    renames
+        .iter()
+        .any(|(from, _, _, copied)| from == path && !copied)
+}
+
+/// Find the files added between the specified trees that were renamed from files deleted between
+/// them, or with `copies` set, copied from files that were modified. Files must be at least
+/// `threshold` percent similar to match, and the most similar pairs are matched first.
+pub fn find_renames(
+    repo: &Repository,
+    t_from: &Tree,
+    t_to: &Tree,
+    threshold: usize,
+    copies: bool,
+) -> Result<Vec<Rename>> {
+    let mut added = vec![];
+    // The files that might have been renamed or copied, and whether each was deleted.
+    let mut sources = vec![];
+    for (path, objects) in compare_trees(&[t_from, t_to]) {
+        match (&objects[0], &objects[1]) {
+            (None, Some((oid, _))) => added.push((path, oid.clone())),
+            (Some((oid, _)), None) => sources.push((path, oid.clone(), true)),
+            (Some(from), Some(to)) if copies && from != to => {
+                sources.push((path, from.0.clone(), false))
+            }
+            _ => {}
+        }
+    }
+    if added.is_empty() || sources.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut contents: HashMap<&str, Vec<u8>> = HashMap::new();
+    let added_paths = added.iter().map(|(path, oid)| (path, oid));
+    let source_paths = sources.iter().map(|(path, oid, _)| (path, oid));
+    for (path, oid) in added_paths.chain(source_paths) {
+        if !contents.contains_key(oid.as_str()) {
+            contents.insert(oid, get_blob(repo, Some(oid), Path::new(path))?);
+        }
+    }
+
+    // Score every pairing, then match the most similar first, each deleted file being renamed at
+    // most once.
+    let mut candidates = vec![];
+    for (i, (_, oid)) in added.iter().enumerate() {
+        for (j, (_, source_oid, _)) in sources.iter().enumerate() {
+            let (new, old) = (&contents[oid.as_str()], &contents[source_oid.as_str()]);
+            // Empty files are all alike, so say nothing about where one came from.
+            if new.is_empty() || old.is_empty() {
+                continue;
+            }
+            let score = if oid == source_oid {
+                100
             } else {
-                "modified"
+                similarity(old, new)
             };
-            result.push((path, action));
+            if score >= threshold {
+                candidates.push((Reverse(score), i, j));
+            }
+        }
+    }
+    candidates.sort();
+
+    let mut found: Vec<Option<Rename>> = vec![None; added.len()];
+    let mut renamed = vec![false; sources.len()];
+    for (Reverse(score), i, j) in candidates {
+        let (from, _, deleted) = &sources[j];
+        if found[i].is_some() {
+            continue;
+        }
+        let copied = !*deleted || renamed[j];
+        if copied && !copies {
+            continue;
         }
+        renamed[j] |= !copied;
+        found[i] = Some((from.clone(), added[i].0.clone(), score, copied));
     }
+    Ok(found.into_iter().flatten().collect())
+}
+
+/// Estimate how similar two files are, as the percentage of the larger one made up of lines that
+/// the two have in common.
+fn similarity(old: &[u8], new: &[u8]) -> usize {
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for line in split_lines(old) {
+        *counts.entry(line).or_default() += 1;
+    }
+    let mut common = 0;
+    for line in split_lines(new) {
+        if let Some(count) = counts.get_mut(line).filter(|count| **count > 0) {
+            *count -= 1;
+            common += line.len();
+        }
+    }
+    common * 100 / old.len().max(new.len())
+}
 
+/// Find the differences between the files contained in the specified trees, looking for renamed
+/// and copied files if the options ask for it.
+pub fn diff_tree_files(
+    repo: &Repository,
+    t_from: &Tree,
+    t_to: &Tree,
+    options: &DiffOptions,
+) -> Result<Vec<FileDiff>> {
+    let renames = match options.renames {
+        Some(threshold) => find_renames(repo, t_from, t_to, threshold, options.copies)?,
+        None => vec![],
+    };
+    let entries = compare_trees(&[t_from, t_to]);
+
+    let mut result = vec![];
+    for (path, objects) in &entries {
+        let rename = renames.iter().find(|(_, to, _, _)| to == path);
+        let (old_path, old) = match rename {
+            Some((from, _, _, _)) => (from, entries[from][0].clone()),
+            None => (path, objects[0].clone()),
+        };
+        let new = objects[1].clone();
+        if (old == new && rename.is_none()) || (new.is_none() && is_renamed(&renames, path)) {
+            continue;
+        }
+
+        let old_oid = old.as_ref().map(|(oid, _)| oid.as_str());
+        let new_oid = new.as_ref().map(|(oid, _)| oid.as_str());
+        let content = if old_oid == new_oid {
+            ContentDiff::Unchanged
+        } else {
+            let old_data = get_blob(repo, old_oid, Path::new(old_path))?;
+            let new_data = get_blob(repo, new_oid, Path::new(path))?;
+            if is_binary(&old_data) || is_binary(&new_data) {
+                ContentDiff::Binary
+            } else {
+                ContentDiff::Text(diff_lines(&old_data, &new_data, options))
+            }
+        };
+
+        result.push(FileDiff {
+            path: path.clone(),
+            old,
+            new,
+            origin: rename.map(|(from, _, score, copied)| (from.clone(), *score, *copied)),
+            content,
+        });
+    }
+    Ok(result)
+}
+
+/// Generates a diff of the files contained in the specified trees. Paths are shown relative to
+/// `prefix`, the current directory's location within the working tree.
+pub fn diff_trees(
+    repo: &Repository,
+    t_from: &Tree,
+    t_to: &Tree,
+    prefix: &Path,
+    options: &DiffOptions,
+) -> Result<String> {
+    let file_diffs = diff_tree_files(repo, t_from, t_to, options)?;
+    Ok(format_file_diffs(&file_diffs, prefix, options))
+}
+
+/// Format the differences between two versions of each file as a git-style unified diff, coloured
+/// or word by word if the options ask for it.
+pub fn format_file_diffs(file_diffs: &[FileDiff], prefix: &Path, options: &DiffOptions) -> String {
+    let moved = if options.color && options.color_moved && options.word_diff.is_none() {
+        find_moved_lines(file_diffs)
+    } else {
+        HashSet::new()
+    };
+    file_diffs
+        .iter()
+        .map(|file_diff| format_file_diff(file_diff, prefix, options, &moved))
+        .collect()
+}
+
+/// Format the difference between two versions of a file as a git-style unified diff. `moved`
+/// holds the lines that were moved from one place to another in the diff as a whole.
+fn format_file_diff(
+    file_diff: &FileDiff,
+    prefix: &Path,
+    options: &DiffOptions,
+    moved: &HashSet<&[u8]>,
+) -> String {
+    let display_path = relative_to_prefix(prefix, Path::new(&file_diff.path));
+    let path = display_path.to_string_lossy();
+    let old_display_path = match &file_diff.origin {
+        Some((from, _, _)) => relative_to_prefix(prefix, Path::new(from)),
+        None => display_path.clone(),
+    };
+    let old_path = old_display_path.to_string_lossy();
+    let mut output = format!("diff --git a/{} b/{}\n", old_path, path);
+
+    if let Some((_, similarity, copied)) = &file_diff.origin {
+        let kind = if *copied { "copy" } else { "rename" };
+        output.push_str(&format!(
+            "similarity index {}%\n{} from {}\n{} to {}\n",
+            similarity, kind, old_path, kind, path
+        ));
+    }
+    match (&file_diff.old, &file_diff.new) {
+        (None, Some((_, mode))) => output.push_str(&format!("new file mode {:o}\n", mode)),
+        (Some((_, mode)), None) => output.push_str(&format!("deleted file mode {:o}\n", mode)),
+        (Some((_, old_mode)), Some((_, new_mode))) if old_mode != new_mode => output.push_str(
+            &format!("old mode {:o}\nnew mode {:o}\n", old_mode, new_mode),
+        ),
+        _ => {}
+    }
+
+    let old_label = match file_diff.old {
+        Some(_) => format!("a/{}", old_path),
+        None => "/dev/null".to_owned(),
+    };
+    let new_label = match file_diff.new {
+        Some(_) => format!("b/{}", path),
+        None => "/dev/null".to_owned(),
+    };
+    if let ContentDiff::Text(_) = &file_diff.content {
+        output.push_str(&format!("--- {}\n+++ {}\n", old_label, new_label));
+    }
+    // Like git, the header lines are shown in bold.
+    let mut output: String = output
+        .lines()
+        .map(|line| format!("{}\n", paint(line, BOLD, options.color)))
+        .collect();
+
+    match &file_diff.content {
+        ContentDiff::Unchanged => {}
+        ContentDiff::Binary => {
+            output.push_str(&format!(
+                "Binary files {} and {} differ\n",
+                old_label, new_label
+            ));
+        }
+        ContentDiff::Text(hunks) => {
+            for hunk in hunks {
+                output.push_str(&match options.word_diff {
+                    Some(mode) => format_word_diff_hunk(hunk, mode, options.color),
+                    None => format_hunk(hunk, options.color, moved),
+                });
+            }
+        }
+    }
+    output
+}
+
+/// Wrap text in the specified ANSI colour, if colouring is enabled.
+fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled && !text.is_empty() {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text.to_owned()
+    }
+}
+
+/// Find the lines that were removed from one place and added in another, anywhere in a diff.
+fn find_moved_lines(file_diffs: &[FileDiff]) -> HashSet<&[u8]> {
+    let mut removed = HashSet::new();
+    let mut added = HashSet::new();
+    for file_diff in file_diffs {
+        if let ContentDiff::Text(hunks) = &file_diff.content {
+            for line in hunks.iter().flat_map(|hunk| &hunk.lines) {
+                match line {
+                    DiffLine::Context(_) => {}
+                    DiffLine::Removed(text) => {
+                        removed.insert(text.as_slice());
+                    }
+                    DiffLine::Added(text) => {
+                        added.insert(text.as_slice());
+                    }
+                }
+            }
+        }
+    }
+    removed.intersection(&added).copied().collect()
+}
+
+/// Mark the lines of a hunk that belong to blocks of removed or added lines that were all moved,
+/// given the moved lines of the whole diff. Like git, blocks with fewer than 20 alphanumeric
+/// characters don't count, since short lines are often repeated by chance.
+fn find_moved_blocks(lines: &[DiffLine], moved: &HashSet<&[u8]>) -> Vec<bool> {
+    let kind = |line: &DiffLine| match line {
+        DiffLine::Removed(text) if moved.contains(text.as_slice()) => 1,
+        DiffLine::Added(text) if moved.contains(text.as_slice()) => 2,
+        _ => 0,
+    };
+    let mut result = vec![false; lines.len()];
+    let mut start = 0;
+    while start < lines.len() {
+        let block_kind = kind(&lines[start]);
+        let end = start
+            + lines[start..]
+                .iter()
+                .take_while(|line| kind(line) == block_kind)
+                .count();
+        let size: usize = lines[start..end]
+            .iter()
+            .map(|line| match line {
+                DiffLine::Context(text) | DiffLine::Removed(text) | DiffLine::Added(text) => {
+                    text.iter().filter(|b| b.is_ascii_alphanumeric()).count()
+                }
+            })
+            .sum();
+        if block_kind != 0 && size >= 20 {
+            result[start..end].fill(true);
+        }
+        start = end;
+    }
     result
 }
 
-/// Generates a diff of the files contained in the specified trees.
-pub fn diff_trees(t_from: &Tree, t_to: &Tree) -> String {
+/// Summarise the changes to each file as a git-style diffstat, with a bar of +s and -s scaled to
+/// fit in 80 columns, followed by the totals. Paths are shown relative to `prefix`.
+pub fn format_stat(file_diffs: &[FileDiff], prefix: &Path) -> String {
+    let rows: Vec<(String, Option<(usize, usize)>)> = file_diffs
+        .iter()
+        .map(|file_diff| (format_path(file_diff, prefix), file_diff.line_counts()))
+        .collect();
+    let changes =
+        |counts: &Option<(usize, usize)>| counts.map_or(0, |(added, removed)| added + removed);
+    let name_width = rows
+        .iter()
+        .map(|(path, _)| path.chars().count())
+        .max()
+        .unwrap_or(0);
+    let max_changes = rows
+        .iter()
+        .map(|(_, counts)| changes(counts))
+        .max()
+        .unwrap_or(0);
+    let count_width = max_changes.to_string().len();
+    let bar_width = 80usize
+        .saturating_sub(name_width + count_width + 6)
+        .clamp(10, 40);
+
+    let mut output = String::new();
+    let (mut total_added, mut total_removed) = (0, 0);
+    for (path, counts) in &rows {
+        let (added, removed) = match counts {
+            Some(counts) => *counts,
+            None => {
+                output.push_str(&format!(" {:<2$} | {}\n", path, "Bin", name_width));
+                continue;
+            }
+        };
+        total_added += added;
+        total_removed += removed;
+        // Like git, scale the bars down when they don't fit, keeping at least one character for
+        // any change. The total is scaled first and then split, so the bar never overflows.
+        let scale = |n: usize| match n {
+            0 => 0,
+            n => 1 + n * (bar_width - 1) / max_changes,
+        };
+        let (plus, minus) = if max_changes <= bar_width {
+            (added, removed)
+        } else {
+            let mut total = scale(added + removed);
+            if total < 2 && added > 0 && removed > 0 {
+                total = 2;
+            }
+            if added < removed {
+                (scale(added), total - scale(added))
+            } else {
+                (total - scale(removed), scale(removed))
+            }
+        };
+        let row = format!(
+            " {:<4$} | {:>5$} {}{}",
+            path,
+            added + removed,
+            "+".repeat(plus),
+            "-".repeat(minus),
+            name_width,
+            count_width
+        );
+        // A file with only its mode changed has no bar to separate from the count.
+        output.push_str(row.trim_end());
+        output.push('\n');
+    }
+
+    let plural =
+        |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+    output.push_str(&format!(
+        " {}",
+        plural(rows.len(), "file changed", "files changed")
+    ));
+    if total_added > 0 || total_removed == 0 {
+        output.push_str(&format!(
+            ", {}",
+            plural(total_added, "insertion(+)", "insertions(+)")
+        ));
+    }
+    if total_removed > 0 || total_added == 0 {
+        output.push_str(&format!(
+            ", {}",
+            plural(total_removed, "deletion(-)", "deletions(-)")
+        ));
+    }
+    output.push('\n');
+    output
+}
+
+/// List the number of lines added and removed in each file, tab separated, as git's --numstat
+/// does. Binary files show "-" for both.
+pub fn format_numstat(file_diffs: &[FileDiff], prefix: &Path) -> String {
+    let mut output = String::new();
+    for file_diff in file_diffs {
+        let (added, removed) = match file_diff.line_counts() {
+            Some((added, removed)) => (added.to_string(), removed.to_string()),
+            None => ("-".to_owned(), "-".to_owned()),
+        };
+        let path = format_path(file_diff, prefix);
+        output.push_str(&format!("{}\t{}\t{}\n", added, removed, path));
+    }
+    output
+}
+
+/// List each changed file with a letter showing how it changed, as git's --name-status does:
+/// A(dded), D(eleted), M(odified), T(ype changed), or R(enamed) and C(opied) followed by the
+/// similarity and the path it came from.
+pub fn format_name_status(file_diffs: &[FileDiff], prefix: &Path) -> String {
     let mut output = String::new();
-    for (path, oids) in compare_trees(&[t_from, t_to]) {
-        let o_from = &oids[0];
-        let o_to = &oids[1];
-        if o_from != o_to {
-            let path_string = path.to_str().expect("Failed to convert path to string");
-            let diff = diff_blobs(o_from.as_deref(), o_to.as_deref(), path_string);
-            output.push_str(&diff);
+    for file_diff in file_diffs {
+        let path = relative_to_prefix(prefix, Path::new(&file_diff.path));
+        let status = match (&file_diff.origin, &file_diff.old, &file_diff.new) {
+            (Some((from, similarity, copied)), _, _) => {
+                let from = relative_to_prefix(prefix, Path::new(from));
+                let letter = if *copied { 'C' } else { 'R' };
+                format!("{}{:03}\t{}", letter, similarity, from.display())
+            }
+            (None, None, _) => "A".to_owned(),
+            (None, _, None) => "D".to_owned(),
+            (None, Some((_, old_mode)), Some((_, new_mode)))
+                if (*old_mode == MODE_SYMLINK) != (*new_mode == MODE_SYMLINK) =>
+            {
+                "T".to_owned()
+            }
+            _ => "M".to_owned(),
+        };
+        output.push_str(&format!("{}\t{}\n", status, path.display()));
+    }
+    output
+}
+
+/// The path of a changed file as shown in summaries, relative to `prefix`, including where it was
+/// renamed or copied from.
+fn format_path(file_diff: &FileDiff, prefix: &Path) -> String {
+    let path = relative_to_prefix(prefix, Path::new(&file_diff.path));
+    let path = path.to_string_lossy();
+    match &file_diff.origin {
+        Some((from, _, _)) => {
+            let from = relative_to_prefix(prefix, Path::new(from));
+            format_rename(&from.to_string_lossy(), &path)
+        }
+        None => path.into_owned(),
+    }
+}
+
+/// Format a renamed path as "old => new", or as "dir/{old => new}/rest" when the paths share
+/// leading or trailing directories, like git.
+fn format_rename(from: &str, to: &str) -> String {
+    let common = from
+        .bytes()
+        .zip(to.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    // Slice the bytes, as the common prefix may end part way through a character.
+    let prefix = from.as_bytes()[..common]
+        .iter()
+        .rposition(|b| *b == b'/')
+        .map_or(0, |i| i + 1);
+    let common = from[prefix..]
+        .bytes()
+        .rev()
+        .zip(to[prefix..].bytes().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    // Likewise the common suffix may begin part way through a character.
+    let suffix = from.as_bytes()[from.len() - common..]
+        .iter()
+        .position(|b| *b == b'/')
+        .map_or(0, |i| common - i);
+    if prefix == 0 && suffix == 0 {
+        return format!("{} => {}", from, to);
+    }
+    format!(
+        "{}{{{} => {}}}{}",
+        &from[..prefix],
+        &from[prefix..from.len() - suffix],
+        &to[prefix..to.len() - suffix],
+        &from[from.len() - suffix..]
+    )
+}
+
+/// Keep only the entries of a tree that lie beneath one of the specified paths. No paths means the
+/// whole tree.
+pub fn filter_tree(tree: Tree, paths: &[PathBuf]) -> Tree {
+    if paths.is_empty() {
+        return tree;
+    }
+    tree.into_iter()
+        .filter(|(_, path, _)| {
+            paths
+                .iter()
+                .any(|prefix| Path::new(path).starts_with(prefix))
+        })
+        .collect()
+}
+
+/// Format a hunk as it appears in a unified diff, highlighting the lines in `moved` that belong to
+/// moved blocks in their own colours.
+fn format_hunk(hunk: &Hunk, color: bool, moved: &HashSet<&[u8]>) -> String {
+    let mut output = format_hunk_header(hunk, color);
+    output.push('\n');
+    let moved_blocks = find_moved_blocks(&hunk.lines, moved);
+    for (line, is_moved) in hunk.lines.iter().zip(moved_blocks) {
+        let (marker, text, line_color) = match line {
+            DiffLine::Context(text) => (' ', text, None),
+            DiffLine::Removed(text) if is_moved => ('-', text, Some(MOVED_FROM)),
+            DiffLine::Removed(text) => ('-', text, Some(RED)),
+            DiffLine::Added(text) if is_moved => ('+', text, Some(MOVED_TO)),
+            DiffLine::Added(text) => ('+', text, Some(GREEN)),
+        };
+        let content = text.strip_suffix(b"\n").unwrap_or(text);
+        let line = format!("{}{}", marker, String::from_utf8_lossy(content));
+        match line_color {
+            Some(line_color) => output.push_str(&paint(&line, line_color, color)),
+            None => output.push_str(&line),
+        }
+        output.push('\n');
+        if !text.ends_with(b"\n") {
+            output.push_str("\\ No newline at end of file\n");
         }
     }
     output
 }
 
-/// Merges the files contained in the specified trees.
-pub fn merge_trees(t_base: &Tree, t_head: &Tree, t_other: &Tree) -> HashMap<OsString, String> {
-    let mut tree = HashMap::new();
-    for (path, oids) in compare_trees(&[t_base, t_head, t_other]) {
-        let o_base = &oids[0];
-        let o_head = &oids[1];
-        let o_other = &oids[2];
-        tree.insert(
-            path,
-            merge_blobs(o_base.as_deref(), o_head.as_deref(), o_other.as_deref()),
+/// Format a hunk showing the changes within its lines word by word, in the specified mode.
+fn format_word_diff_hunk(hunk: &Hunk, mode: WordDiff, color: bool) -> String {
+    let mut output = format_hunk_header(hunk, color);
+    output.push('\n');
+
+    // Compare the whole of each side of the hunk, with the context lines common to both.
+    let (mut old, mut new) = (vec![], vec![]);
+    for line in &hunk.lines {
+        match line {
+            DiffLine::Context(text) => {
+                old.extend_from_slice(text);
+                new.extend_from_slice(text);
+            }
+            DiffLine::Removed(text) => old.extend_from_slice(text),
+            DiffLine::Added(text) => new.extend_from_slice(text),
+        }
+    }
+
+    for (kind, text) in diff_words(&old, &new) {
+        let text = String::from_utf8_lossy(&text);
+        // Each line of a run is marked separately, so that markers and colours don't span lines.
+        for (i, piece) in text.split('\n').enumerate() {
+            if i > 0 {
+                output.push_str(if mode == WordDiff::Porcelain {
+                    "~\n"
+                } else {
+                    "\n"
+                });
+            }
+            if piece.is_empty() {
+                continue;
+            }
+            let (open, close, piece_color) = match kind {
+                ' ' => ("", "", None),
+                '-' => ("[-", "-]", Some(RED)),
+                _ => ("{+", "+}", Some(GREEN)),
+            };
+            match mode {
+                WordDiff::Porcelain => output.push_str(&format!("{}{}\n", kind, piece)),
+                _ if piece_color.is_none() => output.push_str(piece),
+                WordDiff::Plain => {
+                    let marked = format!("{}{}{}", open, piece, close);
+                    output.push_str(&paint(&marked, piece_color.unwrap(), color));
+                }
+                WordDiff::Color => output.push_str(&paint(piece, piece_color.unwrap(), color)),
+            }
+        }
+    }
+    output
+}
+
+/// Compare two texts word by word, where a word is a run of non-whitespace, returning runs of
+/// text that are unchanged (' '), removed ('-') or added ('+'). Whitespace between words is taken
+/// from the new text, except within runs of removed words.
+fn diff_words(old: &[u8], new: &[u8]) -> Vec<(char, Vec<u8>)> {
+    let (old_words, _) = split_words(old);
+    let (new_words, new_end) = split_words(new);
+    let old_tokens: Vec<&[u8]> = old_words.iter().map(|(_, word)| *word).collect();
+    let new_tokens: Vec<&[u8]> = new_words.iter().map(|(_, word)| *word).collect();
+    let ids = intern_lines(&[&old_tokens, &new_tokens]);
+    let (removed, added) = find_changed_lines(&ids[0], &ids[1], DiffAlgorithm::Myers);
+
+    let mut runs: Vec<(char, Vec<u8>)> = vec![];
+    let mut push = |kind: char, text: &[u8]| match runs.last_mut() {
+        Some((last_kind, last_text)) if *last_kind == kind => last_text.extend_from_slice(text),
+        _ => runs.push((kind, text.to_vec())),
+    };
+    let (mut i, mut j) = (0, 0);
+    let mut last_kind = ' ';
+    while i < old_words.len() || j < new_words.len() {
+        if i < old_words.len() && removed[i] {
+            let (space, word) = old_words[i];
+            push(if last_kind == '-' { '-' } else { ' ' }, space);
+            push('-', word);
+            last_kind = '-';
+            i += 1;
+        } else if j < new_words.len() && added[j] {
+            let (space, word) = new_words[j];
+            // Added words directly replacing removed ones follow them without a gap.
+            match last_kind {
+                '+' => push('+', space),
+                '-' => {}
+                _ => push(' ', space),
+            }
+            push('+', word);
+            last_kind = '+';
+            j += 1;
+        } else {
+            let (space, word) = new_words[j];
+            push(' ', space);
+            push(' ', word);
+            last_kind = ' ';
+            i += 1;
+            j += 1;
+        }
+    }
+    push(' ', new_end);
+    runs
+}
+
+/// A word, as the whitespace before it and the word itself.
+type Word<'a> = (&'a [u8], &'a [u8]);
+
+/// Split text into words, followed by any whitespace at the end.
+fn split_words(text: &[u8]) -> (Vec<Word<'_>>, &[u8]) {
+    let mut words = vec![];
+    let mut start = 0;
+    loop {
+        let space_end = start
+            + text[start..]
+                .iter()
+                .take_while(|b| b.is_ascii_whitespace())
+                .count();
+        if space_end == text.len() {
+            return (words, &text[start..]);
+        }
+        let word_end = space_end
+            + text[space_end..]
+                .iter()
+                .take_while(|b| !b.is_ascii_whitespace())
+                .count();
+        words.push((&text[start..space_end], &text[space_end..word_end]));
+        start = word_end;
+    }
+}
+
+/// Format the "@@ -a,b +c,d @@ function" line that introduces a hunk.
+fn format_hunk_header(hunk: &Hunk, color: bool) -> String {
+    let range = format!(
+        "@@ -{} +{} @@",
+        format_range(hunk.old_start, hunk.old_lines),
+        format_range(hunk.new_start, hunk.new_lines)
+    );
+    let mut header = paint(&range, CYAN, color);
+    if let Some(function) = &hunk.function {
+        header.push(' ');
+        header.push_str(&String::from_utf8_lossy(function));
+    }
+    header
+}
+
+fn format_range(start: usize, lines: usize) -> String {
+    if lines == 1 {
+        start.to_string()
+    } else {
+        format!("{},{}", start, lines)
+    }
+}
+
+/// Retrieve the contents of the blob with the specified OID, or nothing if there's no OID. Blobs
+/// describing the working tree aren't stored, so if it's missing the file at the specified path in
+/// the working tree is used instead, provided it still has that OID.
+fn get_blob(repo: &Repository, oid: Option<&str>, path: &Path) -> Result<Vec<u8>> {
+    let oid = match oid {
+        Some(oid) => oid,
+        None => return Ok(vec![]),
+    };
+    match data::get_object(repo, oid, Some("blob")) {
+        Err(Error::ObjectNotFound(_)) => {
+            let file_path = repo.work_tree_path(path);
+            if fs::symlink_metadata(&file_path).is_ok() {
+                let (contents, _) = base::read_work_tree_file(&file_path)?;
+                if data::compute_oid(&contents, "blob") == oid {
+                    return Ok(contents);
+                }
+            }
+            Err(Error::ObjectNotFound(oid.to_owned()))
+        }
+        result => result,
+    }
+}
+
+/// Whether the specified data looks like binary rather than text, which like git is decided by
+/// whether there's a null byte near the start.
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|b| *b == 0)
+}
+
+/// Why a path couldn't be merged automatically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the file in ways that couldn't be combined.
+    BothModified,
+    /// Both sides added the file with different contents.
+    BothAdded,
+    /// We deleted the file while they modified it.
+    DeletedByUs,
+    /// They deleted the file while we modified it.
+    DeletedByThem,
+}
+
+impl ConflictKind {
+    /// The two-letter code that git's short status format uses for the conflict.
+    pub fn code(self) -> &'static str {
+        match self {
+            ConflictKind::BothModified => "UU",
+            ConflictKind::BothAdded => "AA",
+            ConflictKind::DeletedByUs => "DU",
+            ConflictKind::DeletedByThem => "UD",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<ConflictKind> {
+        [
+            ConflictKind::BothModified,
+            ConflictKind::BothAdded,
+            ConflictKind::DeletedByUs,
+            ConflictKind::DeletedByThem,
+        ]
+        .iter()
+        .copied()
+        .find(|kind| kind.code() == code)
+    }
+
+    /// How status describes the conflict.
+    pub fn description(self) -> &'static str {
+        match self {
+            ConflictKind::BothModified => "both modified",
+            ConflictKind::BothAdded => "both added",
+            ConflictKind::DeletedByUs => "deleted by us",
+            ConflictKind::DeletedByThem => "deleted by them",
+        }
+    }
+}
+
+/// The result of merging a single path: the contents and mode it should have, or None if it
+/// should be deleted, along with the conflict that needs resolving, if any.
+pub type MergedFile = (Option<(Vec<u8>, u32)>, Option<ConflictKind>);
+
+/// Merges the files contained in the specified trees. A file changed on only one side takes that
+/// side's version, including deletions, and only files changed on both sides are merged line by
+/// line.
+pub fn merge_trees(
+    repo: &Repository,
+    t_base: &Tree,
+    t_head: &Tree,
+    t_other: &Tree,
+    options: &MergeOptions,
+) -> Result<BTreeMap<OsString, MergedFile>> {
+    let read = |object: &Option<(String, u32)>| -> Result<Option<(Vec<u8>, u32)>> {
+        match object {
+            Some((oid, mode)) => Ok(Some((data::get_object(repo, oid, Some("blob"))?, *mode))),
+            None => Ok(None),
+        }
+    };
+
+    let mut tree = BTreeMap::new();
+    for (path, objects) in compare_trees(&[t_base, t_head, t_other]) {
+        let (base, head, other) = (&objects[0], &objects[1], &objects[2]);
+        let merged = if head == other || other == base {
+            (read(head)?, None)
+        } else if head == base {
+            (read(other)?, None)
+        } else {
+            match (head, other) {
+                // Leave the modified version in place so that it can be inspected.
+                (None, _) => (read(other)?, Some(ConflictKind::DeletedByUs)),
+                (_, None) => (read(head)?, Some(ConflictKind::DeletedByThem)),
+                (Some((head_oid, head_mode)), Some((other_oid, other_mode))) => {
+                    // Take their mode only if we left ours unchanged.
+                    let mode = match base {
+                        Some((_, base_mode)) if base_mode == head_mode => *other_mode,
+                        _ => *head_mode,
+                    };
+                    let base_oid = base.as_ref().map(|(oid, _)| oid.as_str());
+                    let symlink = *head_mode == MODE_SYMLINK || *other_mode == MODE_SYMLINK;
+                    let (contents, conflicted) =
+                        merge_blobs(repo, base_oid, head_oid, other_oid, symlink, options)?;
+                    let kind = match base {
+                        Some(_) => ConflictKind::BothModified,
+                        None => ConflictKind::BothAdded,
+                    };
+                    (Some((contents, mode)), conflicted.then_some(kind))
+                }
+            }
+        };
+        tree.insert(path, merged);
+    }
+    Ok(tree)
+}
+
+/// Use a three-way merge to merge the blobs with the specified OIDs using a common base, if there
+/// is one, returning the merged contents and whether they contain conflicts. Symlink targets
+/// aren't merged line by line.
+fn merge_blobs(
+    repo: &Repository,
+    o_base: Option<&str>,
+    o_head: &str,
+    o_other: &str,
+    symlink: bool,
+    options: &MergeOptions,
+) -> Result<(Vec<u8>, bool)> {
+    let base = match o_base {
+        Some(oid) => data::get_object(repo, oid, Some("blob"))?,
+        None => vec![],
+    };
+    let head = data::get_object(repo, o_head, Some("blob"))?;
+    let other = data::get_object(repo, o_other, Some("blob"))?;
+
+    if o_head == o_other || Some(o_other) == o_base {
+        return Ok((head, false));
+    }
+    if Some(o_head) == o_base {
+        return Ok((other, false));
+    }
+    // Binary files can't be merged line by line, so keep our version unless told otherwise.
+    if symlink || is_binary(&base) || is_binary(&head) || is_binary(&other) {
+        return Ok(match options.favor {
+            Some(MergeFavor::Theirs) => (other, false),
+            Some(_) => (head, false),
+            None => (head, true),
+        });
+    }
+
+    let result = merge::merge_lines(&base, &head, &other, options);
+    let contents = merge::format_merge(&result, options.style, ["HEAD", "BASE", "MERGE_HEAD"]);
+    Ok((contents, !result.is_clean()))
+}
+
+/// Find the differences between the lines of two files and group them into hunks.
+pub fn diff_lines(old: &[u8], new: &[u8], options: &DiffOptions) -> Vec<Hunk> {
+    let old_lines = split_lines(old);
+    let new_lines = split_lines(new);
+    let ids = intern_lines(&[&old_lines, &new_lines]);
+    let (removed, added) = find_changed_lines(&ids[0], &ids[1], options.algorithm);
+
+    build_hunks(&old_lines, &new_lines, &removed, &added, options.context)
+}
+
+/// Compare two files, given as lists of line IDs, returning which lines of the old file were
+/// removed and which lines of the new file were added.
+pub(crate) fn find_changed_lines(
+    old_ids: &[usize],
+    new_ids: &[usize],
+    algorithm: DiffAlgorithm,
+) -> (Vec<bool>, Vec<bool>) {
+    let mut removed = vec![false; old_ids.len()];
+    let mut added = vec![false; new_ids.len()];
+    let mut changes = Changes {
+        removed: &mut removed,
+        added: &mut added,
+    };
+    match algorithm {
+        DiffAlgorithm::Myers => myers(old_ids, new_ids, 0, 0, &mut changes),
+        DiffAlgorithm::Patience => patience(old_ids, new_ids, 0, 0, &mut changes),
+        DiffAlgorithm::Histogram => histogram(old_ids, new_ids, 0, 0, &mut changes),
+    }
+    (removed, added)
+}
+
+/// Split data into lines, keeping each line's ending.
+pub(crate) fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|b| *b == b'\n').collect()
+}
+
+/// Replace each line of each file with a number identifying its contents, so that lines can be
+/// compared cheaply.
+pub(crate) fn intern_lines<'a>(files: &[&[&'a [u8]]]) -> Vec<Vec<usize>> {
+    let mut ids: HashMap<&'a [u8], usize> = HashMap::new();
+    files
+        .iter()
+        .map(|lines| {
+            lines
+                .iter()
+                .map(|line| {
+                    let next_id = ids.len();
+                    *ids.entry(*line).or_insert(next_id)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Records which lines of the old file were removed and which lines of the new file were added.
+struct Changes<'a> {
+    removed: &'a mut [bool],
+    added: &'a mut [bool],
+}
+
+impl Changes<'_> {
+    fn replace(&mut self, old_offset: usize, old_len: usize, new_offset: usize, new_len: usize) {
+        self.removed[old_offset..old_offset + old_len].fill(true);
+        self.added[new_offset..new_offset + new_len].fill(true);
+    }
+}
+
+/// Return the lengths of the common prefix and common suffix of two sequences, which don't overlap.
+pub(crate) fn common_affixes<T: PartialEq>(old: &[T], new: &[T]) -> (usize, usize) {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    (prefix, suffix)
+}
+
+/// Diff two sequences using Myers' linear space divide-and-conquer algorithm, marking the changed
+/// lines. The offsets give the position of the sequences within the whole files.
+fn myers(
+    old: &[usize],
+    new: &[usize],
+    old_offset: usize,
+    new_offset: usize,
+    changes: &mut Changes,
+) {
+    let (prefix, suffix) = common_affixes(old, new);
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+    let old_offset = old_offset + prefix;
+    let new_offset = new_offset + prefix;
+
+    if old.is_empty() || new.is_empty() {
+        changes.replace(old_offset, old.len(), new_offset, new.len());
+        return;
+    }
+
+    match find_middle_snake(old, new) {
+        Some((x, y)) if (x, y) != (0, 0) && (x, y) != (old.len(), new.len()) => {
+            myers(&old[..x], &new[..y], old_offset, new_offset, changes);
+            myers(
+                &old[x..],
+                &new[y..],
+                old_offset + x,
+                new_offset + y,
+                changes,
+            );
+        }
+        _ => changes.replace(old_offset, old.len(), new_offset, new.len()),
+    }
+}
+
+/// Find the point at which the forward and backward searches for the shortest edit script meet,
+/// which splits the problem into two smaller ones.
+fn find_middle_snake(old: &[usize], new: &[usize]) -> Option<(usize, usize)> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let delta = n - m;
+    let odd = delta & 1 == 1;
+    let d_max = (n + m + 1) / 2 + 1;
+    // Furthest reaching x on each diagonal k, indexed by k + offset.
+    let offset = d_max + 1;
+    let mut forward = vec![0isize; (2 * offset + 1) as usize];
+    let mut backward = vec![0isize; (2 * offset + 1) as usize];
+    let index = |k: isize| (k + offset) as usize;
+    let max_cost = 256.max(((n + m + 3) as f64).sqrt() as isize);
+
+    for d in 0..d_max {
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && forward[index(k - 1)] < forward[index(k + 1)]) {
+                forward[index(k + 1)]
+            } else {
+                forward[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x >= 0 && y >= 0 && x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[index(k)] = x;
+            if odd
+                && (k - delta).abs() < d
+                && forward[index(k)] + backward[index(delta - k)] >= n
+                && x0 >= 0
+                && y0 >= 0
+            {
+                return Some((x0 as usize, y0 as usize));
+            }
+        }
+
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && backward[index(k - 1)] < backward[index(k + 1)]) {
+                backward[index(k + 1)]
+            } else {
+                backward[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x >= 0
+                && y >= 0
+                && x < n
+                && y < m
+                && old[(n - x - 1) as usize] == new[(m - y - 1) as usize]
+            {
+                x += 1;
+                y += 1;
+            }
+            backward[index(k)] = x;
+            if !odd
+                && (k - delta).abs() <= d
+                && backward[index(k)] + forward[index(delta - k)] >= n
+                && n - x >= 0
+                && m - y >= 0
+            {
+                return Some(((n - x) as usize, (m - y) as usize));
+            }
+        }
+
+        // Like git, give up on finding a minimal diff once it's clear the files are very
+        // different, splitting at the furthest point the forward search has reached instead.
+        if d >= max_cost {
+            let furthest = (-d..=d)
+                .step_by(2)
+                .map(|k| {
+                    let x = forward[index(k)].min(n);
+                    (x, x - k)
+                })
+                .filter(|(x, y)| *y >= 0 && *y <= m && (*x, *y) != (0, 0) && (*x, *y) != (n, m))
+                .max_by_key(|(x, y)| x + y);
+            if let Some((x, y)) = furthest {
+                return Some((x as usize, y as usize));
+            }
+        }
+    }
+
+    None
+}
+
+/// Diff two sequences by anchoring on lines that appear exactly once in each, falling back to
+/// Myers when there are none.
+fn patience(
+    old: &[usize],
+    new: &[usize],
+    old_offset: usize,
+    new_offset: usize,
+    changes: &mut Changes,
+) {
+    let (prefix, suffix) = common_affixes(old, new);
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+    let old_offset = old_offset + prefix;
+    let new_offset = new_offset + prefix;
+
+    if old.is_empty() || new.is_empty() {
+        changes.replace(old_offset, old.len(), new_offset, new.len());
+        return;
+    }
+
+    // Map each line to its position and number of occurrences in each file.
+    let mut occurrences: HashMap<usize, (usize, usize, usize, usize)> = HashMap::new();
+    for (i, line) in old.iter().enumerate() {
+        let entry = occurrences.entry(*line).or_insert((0, 0, 0, 0));
+        entry.0 += 1;
+        entry.1 = i;
+    }
+    for (j, line) in new.iter().enumerate() {
+        if let Some(entry) = occurrences.get_mut(line) {
+            entry.2 += 1;
+            entry.3 = j;
+        }
+    }
+    let mut unique: Vec<(usize, usize)> = occurrences
+        .values()
+        .filter(|(old_count, _, new_count, _)| *old_count == 1 && *new_count == 1)
+        .map(|(_, i, _, j)| (*i, *j))
+        .collect();
+    if unique.is_empty() {
+        myers(old, new, old_offset, new_offset, changes);
+        return;
+    }
+    unique.sort_unstable();
+
+    let anchors = longest_increasing_subsequence(&unique);
+    let (mut i, mut j) = (0, 0);
+    for (anchor_i, anchor_j) in anchors {
+        patience(
+            &old[i..anchor_i],
+            &new[j..anchor_j],
+            old_offset + i,
+            new_offset + j,
+            changes,
         );
+        i = anchor_i + 1;
+        j = anchor_j + 1;
     }
-    tree
+    patience(
+        &old[i..],
+        &new[j..],
+        old_offset + i,
+        new_offset + j,
+        changes,
+    );
 }
 
-/// Use a three-way merge to merge the objects with the specified OIDs using a common base.
-fn merge_blobs(o_base: Option<&str>, o_head: Option<&str>, o_other: Option<&str>) -> String {
-    let f_base = NamedTempFile::new().expect("Failed to create temp file");
-    let f_head = NamedTempFile::new().expect("Failed to create temp file");
-    let f_other = NamedTempFile::new().expect("Failed to create temp file");
+/// Given pairs sorted by their first element, return the longest subsequence in which the second
+/// elements are also increasing, using patience sorting.
+fn longest_increasing_subsequence(pairs: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    // The index of the pair at the top of each pile, and each pair's predecessor.
+    let mut piles: Vec<usize> = vec![];
+    let mut predecessors: Vec<Option<usize>> = vec![None; pairs.len()];
+    for (index, (_, j)) in pairs.iter().enumerate() {
+        let pile = piles.partition_point(|top| pairs[*top].1 < *j);
+        predecessors[index] = pile.checked_sub(1).map(|p| piles[p]);
+        if pile == piles.len() {
+            piles.push(index);
+        } else {
+            piles[pile] = index;
+        }
+    }
+
+    let mut result = vec![];
+    let mut current = piles.last().copied();
+    while let Some(index) = current {
+        result.push(pairs[index]);
+        current = predecessors[index];
+    }
+    result.reverse();
+    result
+}
+
+/// Diff two sequences by anchoring on the longest run of common lines containing the least
+/// frequent line, as git's histogram algorithm does, falling back to Myers when no line is rare
+/// enough.
+fn histogram(
+    old: &[usize],
+    new: &[usize],
+    old_offset: usize,
+    new_offset: usize,
+    changes: &mut Changes,
+) {
+    // Lines occurring more often than this aren't considered as anchors.
+    const MAX_OCCURRENCES: usize = 64;
+
+    let (prefix, suffix) = common_affixes(old, new);
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+    let old_offset = old_offset + prefix;
+    let new_offset = new_offset + prefix;
+
+    if old.is_empty() || new.is_empty() {
+        changes.replace(old_offset, old.len(), new_offset, new.len());
+        return;
+    }
+
+    let mut positions: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, line) in old.iter().enumerate() {
+        positions.entry(*line).or_default().push(i);
+    }
+
+    // The best region found so far, as (occurrences, old start, new start, length).
+    let mut best: Option<(usize, usize, usize, usize)> = None;
+    for (j, line) in new.iter().enumerate() {
+        let line_positions = match positions.get(line) {
+            Some(line_positions) if line_positions.len() <= MAX_OCCURRENCES => line_positions,
+            _ => continue,
+        };
+        let occurrences = line_positions.len();
+        if best.is_some_and(|(best_occurrences, ..)| occurrences > best_occurrences) {
+            continue;
+        }
+        for i in line_positions {
+            let before = old[..*i]
+                .iter()
+                .rev()
+                .zip(new[..j].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count();
+            let after = old[*i..]
+                .iter()
+                .zip(&new[j..])
+                .take_while(|(a, b)| a == b)
+                .count();
+            let length = before + after;
+            let better = match best {
+                None => true,
+                Some((best_occurrences, _, _, best_length)) => {
+                    occurrences < best_occurrences || length > best_length
+                }
+            };
+            if better {
+                best = Some((occurrences, i - before, j - before, length));
+            }
+        }
+    }
+
+    match best {
+        Some((_, i, j, length)) => {
+            histogram(&old[..i], &new[..j], old_offset, new_offset, changes);
+            histogram(
+                &old[i + length..],
+                &new[j + length..],
+                old_offset + i + length,
+                new_offset + j + length,
+                changes,
+            );
+        }
+        None => myers(old, new, old_offset, new_offset, changes),
+    }
+}
+
+/// Group the changed lines into hunks, each with up to `context` unchanged lines either side.
+/// Changes separated by no more than twice that many unchanged lines share a hunk.
+fn build_hunks(
+    old: &[&[u8]],
+    new: &[&[u8]],
+    removed: &[bool],
+    added: &[bool],
+    context: usize,
+) -> Vec<Hunk> {
+    // Walk both files together to produce a single sequence of lines, each with its position in
+    // the old and new files.
+    let mut lines: Vec<(usize, usize, DiffLine)> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && removed[i] {
+            lines.push((i, j, DiffLine::Removed(old[i].to_vec())));
+            i += 1;
+        } else if j < new.len() && added[j] {
+            lines.push((i, j, DiffLine::Added(new[j].to_vec())));
+            j += 1;
+        } else {
+            lines.push((i, j, DiffLine::Context(old[i].to_vec())));
+            i += 1;
+            j += 1;
+        }
+    }
+
+    let changed: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, _, line))| !matches!(line, DiffLine::Context(_)))
+        .map(|(index, _)| index)
+        .collect();
+
+    let mut hunks = vec![];
+    let mut index = 0;
+    while index < changed.len() {
+        let first = changed[index];
+        let mut last = first;
+        index += 1;
+        while index < changed.len() && changed[index] - last <= 2 * context + 1 {
+            last = changed[index];
+            index += 1;
+        }
+
+        let start = first.saturating_sub(context);
+        let end = (last + context + 1).min(lines.len());
+        let hunk_lines: Vec<DiffLine> = lines[start..end]
+            .iter()
+            .map(|(_, _, line)| line.clone())
+            .collect();
+        let old_lines = hunk_lines
+            .iter()
+            .filter(|line| !matches!(line, DiffLine::Added(_)))
+            .count();
+        let new_lines = hunk_lines
+            .iter()
+            .filter(|line| !matches!(line, DiffLine::Removed(_)))
+            .count();
+        let (old_index, new_index, _) = lines[start];
+        hunks.push(Hunk {
+            old_start: if old_lines == 0 {
+                old_index
+            } else {
+                old_index + 1
+            },
+            old_lines,
+            new_start: if new_lines == 0 {
+                new_index
+            } else {
+                new_index + 1
+            },
+            new_lines,
+            function: find_function(&old[..old_index]),
+            lines: hunk_lines,
+        });
+    }
+
+    hunks
+}
+
+/// Find the last of the specified lines that looks like the start of a function, meaning it begins
+/// with a letter, "_" or "$", as git does by default.
+fn find_function(lines: &[&[u8]]) -> Option<Vec<u8>> {
+    const MAX_LENGTH: usize = 80;
+
+    let line = lines
+        .iter()
+        .rev()
+        .find(|line| matches!(line.first(), Some(c) if c.is_ascii_alphabetic() || *c == b'_' || *c == b'$'))?;
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Some(line[..line.len().min(MAX_LENGTH)].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ugit::{testing::init_repo, MODE_FILE};
+
+    const ALGORITHMS: [DiffAlgorithm; 3] = [
+        DiffAlgorithm::Myers,
+        DiffAlgorithm::Patience,
+        DiffAlgorithm::Histogram,
+    ];
+
+    fn options(algorithm: DiffAlgorithm, context: usize) -> DiffOptions {
+        DiffOptions {
+            algorithm,
+            context,
+            ..DiffOptions::default()
+        }
+    }
+
+    /// Apply hunks to the old file like patch does, checking that every context and removed line
+    /// matches the old file and that the hunk headers agree with the lines they contain.
+    fn apply(old: &[u8], hunks: &[Hunk]) -> Vec<u8> {
+        let old_lines = split_lines(old);
+        let mut result = vec![];
+        let mut next = 0;
+        for hunk in hunks {
+            let start = if hunk.old_lines == 0 {
+                hunk.old_start
+            } else {
+                hunk.old_start - 1
+            };
+            assert!(start >= next, "hunks overlap or are out of order");
+            for line in &old_lines[next..start] {
+                result.extend_from_slice(line);
+            }
+            next = start;
+
+            let (mut old_count, mut new_count) = (0, 0);
+            for line in &hunk.lines {
+                match line {
+                    DiffLine::Context(text) => {
+                        assert_eq!(old_lines[next], text.as_slice());
+                        result.extend_from_slice(text);
+                        next += 1;
+                        old_count += 1;
+                        new_count += 1;
+                    }
+                    DiffLine::Removed(text) => {
+                        assert_eq!(old_lines[next], text.as_slice());
+                        next += 1;
+                        old_count += 1;
+                    }
+                    DiffLine::Added(text) => {
+                        result.extend_from_slice(text);
+                        new_count += 1;
+                    }
+                }
+            }
+            assert_eq!((old_count, new_count), (hunk.old_lines, hunk.new_lines));
+        }
+        for line in &old_lines[next..] {
+            result.extend_from_slice(line);
+        }
+        result
+    }
+
+    /// Check that diffing two files with every algorithm and a few amounts of context produces
+    /// hunks that turn the old file into the new one.
+    fn assert_round_trip(old: &[u8], new: &[u8]) {
+        for algorithm in ALGORITHMS {
+            for context in [0, 1, 3] {
+                let hunks = diff_lines(old, new, &options(algorithm, context));
+                assert_eq!(
+                    apply(old, &hunks),
+                    new,
+                    "{:?} with {} lines of context",
+                    algorithm,
+                    context
+                );
+            }
+        }
+    }
+
+    /// Generate pseudo-random files made of a few repeated lines, so that they share many lines.
+    fn generate_file(seed: &mut u64, lines: usize) -> Vec<u8> {
+        let mut file = vec![];
+        for _ in 0..lines {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let line = (*seed >> 33) % 6;
+            file.extend_from_slice(format!("line {}\n", line).as_bytes());
+        }
+        file
+    }
+
+    #[test]
+    fn empty_inputs_have_no_hunks() {
+        for algorithm in ALGORITHMS {
+            assert!(diff_lines(b"", b"", &options(algorithm, 3)).is_empty());
+        }
+    }
+
+    #[test]
+    fn adding_to_or_removing_from_empty_file() {
+        for algorithm in ALGORITHMS {
+            let hunks = diff_lines(b"", b"a\nb\n", &options(algorithm, 3));
+            assert_eq!(hunks.len(), 1);
+            assert_eq!((hunks[0].old_start, hunks[0].old_lines), (0, 0));
+            assert_eq!((hunks[0].new_start, hunks[0].new_lines), (1, 2));
+
+            let hunks = diff_lines(b"a\nb\n", b"", &options(algorithm, 3));
+            assert_eq!(hunks.len(), 1);
+            assert_eq!((hunks[0].old_start, hunks[0].old_lines), (1, 2));
+            assert_eq!((hunks[0].new_start, hunks[0].new_lines), (0, 0));
+        }
+        assert_round_trip(b"", b"a\nb\n");
+        assert_round_trip(b"a\nb\n", b"");
+    }
+
+    #[test]
+    fn identical_inputs_have_no_hunks() {
+        let file = b"one\ntwo\nthree\n";
+        for algorithm in ALGORITHMS {
+            assert!(diff_lines(file, file, &options(algorithm, 3)).is_empty());
+        }
+    }
+
+    #[test]
+    fn missing_trailing_newline() {
+        assert_round_trip(b"a\nb", b"a\nc");
+        assert_round_trip(b"a\nb", b"a\nb\n");
+        assert_round_trip(b"a\nb\n", b"a\nb");
+
+        // Adding the newline changes the last line, which is marked as having none before.
+        let hunks = diff_lines(b"a\nb", b"a\nb\n", &DiffOptions::default());
+        let text = format_hunk(&hunks[0], false, &HashSet::new());
+        assert_eq!(
+            text,
+            "@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+b\n"
+        );
+    }
+
+    #[test]
+    fn hunks_are_separated_by_more_than_twice_the_context() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let new = b"1\nx\n3\n4\n5\n6\n7\n8\ny\n10\n";
+        assert_eq!(
+            diff_lines(old, new, &options(DiffAlgorithm::Myers, 3)).len(),
+            1
+        );
+        assert_eq!(
+            diff_lines(old, new, &options(DiffAlgorithm::Myers, 2)).len(),
+            2
+        );
+        assert_round_trip(old, new);
+    }
+
+    #[test]
+    fn round_trip_generated_files() {
+        let mut seed = 1;
+        for _ in 0..200 {
+            let old_len = (seed % 20) as usize;
+            let old = generate_file(&mut seed, old_len);
+            let new_len = (seed % 20) as usize;
+            let new = generate_file(&mut seed, new_len);
+            assert_round_trip(&old, &new);
+        }
+    }
+
+    #[test]
+    fn myers_diff_is_minimal() {
+        let old = b"a\nb\nc\na\nb\nb\na\n";
+        let new = b"c\nb\na\nb\na\nc\n";
+        let hunks = diff_lines(old, new, &options(DiffAlgorithm::Myers, 0));
+        let changes: usize = hunks.iter().map(|hunk| hunk.lines.len()).sum();
+        // The classic example from Myers' paper has an edit distance of 5.
+        assert_eq!(changes, 5);
+    }
 
-    if let Some(oid) = o_base {
-        std::fs::write(
-            &f_base,
-            data::get_object(&PathBuf::from(DEFAULT_REPO), oid, Some("blob")),
-        )
-        .expect("Failed to write blob");
+    /// Store the specified files as blobs and return a tree listing them.
+    fn make_tree(repo: &Repository, files: &[(&str, &str)]) -> Tree {
+        files
+            .iter()
+            .map(|(path, contents)| {
+                let oid = data::hash_object(repo, contents.as_bytes(), "blob").unwrap();
+                (oid, OsString::from(path), MODE_FILE)
+            })
+            .collect()
     }
 
-    if let Some(oid) = o_head {
-        std::fs::write(
-            &f_head,
-            data::get_object(&PathBuf::from(DEFAULT_REPO), oid, Some("blob")),
-        )
-        .expect("Failed to write blob");
+    fn rename(from: &str, to: &str, score: usize, copied: bool) -> Rename {
+        (from.into(), to.into(), score, copied)
+    }
+
+    #[test]
+    fn similarity_counts_bytes_in_common_lines() {
+        assert_eq!(similarity(b"aaaa\nbbbb\n", b"aaaa\nbbbb\n"), 100);
+        assert_eq!(
+            similarity(b"aaaa\nbbbb\ncccc\ndddd\n", b"aaaa\nbbbb\ncccc\neeee\n"),
+            75
+        );
+        // Measured against the larger file, so adding lines lowers it too.
+        assert_eq!(similarity(b"aaaa\n", b"aaaa\nbbbb\n"), 50);
+        // Each line of the old file can only be matched once.
+        assert_eq!(similarity(b"aaaa\n", b"aaaa\naaaa\n"), 50);
+        assert_eq!(similarity(b"aaaa\n", b"bbbb\n"), 0);
+    }
+
+    #[test]
+    fn exact_and_inexact_renames() {
+        let (_dir, repo) = init_repo();
+        let old = "aaaa\nbbbb\ncccc\ndddd\n";
+        let from = make_tree(&repo, &[("exact.txt", old), ("inexact.txt", old)]);
+        let to = make_tree(
+            &repo,
+            &[
+                ("moved.txt", old),
+                ("edited.txt", "aaaa\nbbbb\ncccc\neeee\n"),
+            ],
+        );
+
+        assert_eq!(
+            find_renames(&repo, &from, &to, 50, false).unwrap(),
+            [
+                rename("inexact.txt", "edited.txt", 75, false),
+                rename("exact.txt", "moved.txt", 100, false),
+            ]
+        );
+        // The threshold is inclusive, and the exact rename is matched first whatever the order.
+        assert_eq!(
+            find_renames(&repo, &from, &to, 75, false).unwrap(),
+            [
+                rename("inexact.txt", "edited.txt", 75, false),
+                rename("exact.txt", "moved.txt", 100, false),
+            ]
+        );
+        assert_eq!(
+            find_renames(&repo, &from, &to, 76, false).unwrap(),
+            [rename("exact.txt", "moved.txt", 100, false)]
+        );
     }
 
-    if let Some(oid) = o_other {
-        std::fs::write(
-            &f_other,
-            data::get_object(&PathBuf::from(DEFAULT_REPO), oid, Some("blob")),
-        )
-        .expect("Failed to write blob");
+    #[test]
+    fn empty_files_are_not_renamed() {
+        let (_dir, repo) = init_repo();
+        let from = make_tree(&repo, &[("a.txt", "")]);
+        let to = make_tree(&repo, &[("b.txt", "")]);
+        assert_eq!(find_renames(&repo, &from, &to, 0, true).unwrap(), []);
     }
 
-    let mut diff_command = Command::new("diff3");
-    diff_command
-        .arg("-m")
-        .arg("-L")
-        .arg("HEAD")
-        .arg(f_head.path().to_str().unwrap())
-        .arg("-L")
-        .arg("BASE")
-        .arg(f_base.path().to_str().unwrap())
-        .arg("-L")
-        .arg("MERGE_HEAD")
-        .arg(f_other.path().to_str().unwrap());
+    #[test]
+    fn copies_are_only_found_when_asked_for() {
+        let (_dir, repo) = init_repo();
+        let original = "aaaa\nbbbb\ncccc\ndddd\n";
+        let from = make_tree(&repo, &[("kept.txt", original), ("gone.txt", original)]);
+        let to = make_tree(
+            &repo,
+            &[
+                ("kept.txt", "aaaa\nbbbb\ncccc\nchanged\n"),
+                ("one.txt", original),
+                ("two.txt", original),
+            ],
+        );
+
+        // The deleted file is renamed once, to whichever path comes first.
+        assert_eq!(
+            find_renames(&repo, &from, &to, 50, false).unwrap(),
+            [rename("gone.txt", "one.txt", 100, false)]
+        );
+        // A file that was modified, or already renamed, can still be copied.
+        assert_eq!(
+            find_renames(&repo, &from, &to, 50, true).unwrap(),
+            [
+                rename("gone.txt", "one.txt", 100, false),
+                rename("gone.txt", "two.txt", 100, true),
+            ]
+        );
 
-    let diff_output = diff_command.output().unwrap();
-    if let Some(return_code) = diff_output.status.code() {
-        // 0: success
-        // 1: conflicts
-        assert!(return_code == 0 || return_code == 1);
+        // Unmodified files aren't considered as sources, as git does without --find-copies-harder.
+        let from = make_tree(&repo, &[("kept.txt", original)]);
+        let to = make_tree(&repo, &[("kept.txt", original), ("copy.txt", original)]);
+        assert_eq!(find_renames(&repo, &from, &to, 50, true).unwrap(), []);
     }
-    let diff_string =
-        std::str::from_utf8(&diff_output.stdout).expect("Failed to convert diff output to string");
 
-    diff_string.to_owned()
+    #[test]
+    fn renamed_paths_share_common_directories() {
+        let cases = [
+            ("a.txt", "b.txt", "a.txt => b.txt"),
+            ("src/a.rs", "src/b.rs", "src/{a.rs => b.rs}"),
+            ("a/x/f", "b/x/f", "{a => b}/x/f"),
+            (
+                "src/old/mod.rs",
+                "src/new/mod.rs",
+                "src/{old => new}/mod.rs",
+            ),
+            ("dir/f", "f", "dir/f => f"),
+            // Only whole directories are shared, not parts of names.
+            ("src/ab.rs", "src/cb.rs", "src/{ab.rs => cb.rs}"),
+            ("é/f", "è/f", "{é => è}/f"),
+        ];
+        for (from, to, expected) in cases {
+            assert_eq!(format_rename(from, to), expected, "{} => {}", from, to);
+        }
+    }
+
+    /// A modified text file with the specified numbers of lines added and removed.
+    fn changed_file(path: &str, added: usize, removed: usize) -> FileDiff {
+        let mut lines = vec![DiffLine::Removed(b"old\n".to_vec()); removed];
+        lines.extend(vec![DiffLine::Added(b"new\n".to_vec()); added]);
+        let hunk = Hunk {
+            old_start: 1,
+            old_lines: removed,
+            new_start: 1,
+            new_lines: added,
+            function: None,
+            lines,
+        };
+        FileDiff {
+            path: path.into(),
+            old: Some(("1".repeat(40), MODE_FILE)),
+            new: Some(("2".repeat(40), MODE_FILE)),
+            origin: None,
+            content: ContentDiff::Text(vec![hunk]),
+        }
+    }
+
+    fn binary_file(path: &str) -> FileDiff {
+        FileDiff {
+            content: ContentDiff::Binary,
+            ..changed_file(path, 0, 0)
+        }
+    }
+
+    #[test]
+    fn stat_shows_small_changes_unscaled() {
+        let file_diffs = [changed_file("a.txt", 2, 1), binary_file("bin.dat")];
+        assert_eq!(
+            format_stat(&file_diffs, Path::new("")),
+            " a.txt   | 3 ++-\n \
+             bin.dat | Bin\n \
+             2 files changed, 2 insertions(+), 1 deletion(-)\n"
+        );
+    }
+
+    #[test]
+    fn stat_scales_changes_wider_than_the_bar() {
+        let file_diffs = [
+            changed_file("big.txt", 100, 50),
+            changed_file("small.txt", 1, 0),
+            changed_file("tiny.txt", 1, 1),
+        ];
+        let stat = format_stat(&file_diffs, Path::new(""));
+        assert_eq!(
+            stat,
+            format!(
+                " big.txt   | 150 {}{}\n \
+                 small.txt |   1 +\n \
+                 tiny.txt  |   2 +-\n \
+                 3 files changed, 102 insertions(+), 51 deletions(-)\n",
+                "+".repeat(26),
+                "-".repeat(14)
+            )
+        );
+        assert!(stat.lines().all(|line| line.len() <= 80));
+
+        // Long paths squeeze the bar, but never below 10 columns.
+        let path = "x".repeat(100);
+        let stat = format_stat(&[changed_file(&path, 1000, 0)], Path::new(""));
+        assert!(stat.starts_with(&format!(" {} | 1000 {}\n", path, "+".repeat(10))));
+    }
+
+    #[test]
+    fn stat_counts_deletions_and_mode_changes() {
+        let file_diffs = [changed_file("gone.txt", 0, 2)];
+        assert_eq!(
+            format_stat(&file_diffs, Path::new("")),
+            " gone.txt | 2 --\n 1 file changed, 2 deletions(-)\n"
+        );
+        let mode_change = FileDiff {
+            content: ContentDiff::Unchanged,
+            ..changed_file("run.sh", 0, 0)
+        };
+        assert_eq!(
+            format_stat(&[mode_change], Path::new("")),
+            " run.sh | 0\n 1 file changed, 0 insertions(+), 0 deletions(-)\n"
+        );
+    }
+
+    #[test]
+    fn numstat_lists_counts_and_paths() {
+        let renamed = FileDiff {
+            origin: Some(("old/a.txt".into(), 90, false)),
+            ..changed_file("new/a.txt", 1, 1)
+        };
+        let file_diffs = [
+            changed_file("sub/a.txt", 3, 2),
+            binary_file("bin.dat"),
+            renamed,
+        ];
+        assert_eq!(
+            format_numstat(&file_diffs, Path::new("")),
+            "3\t2\tsub/a.txt\n-\t-\tbin.dat\n1\t1\t{old => new}/a.txt\n"
+        );
+        // Paths are relative to the current directory.
+        assert_eq!(
+            format_numstat(&file_diffs[..2], Path::new("sub")),
+            "3\t2\ta.txt\n-\t-\t../bin.dat\n"
+        );
+    }
+
+    #[test]
+    fn name_status_shows_how_each_file_changed() {
+        let file_diffs = [
+            FileDiff {
+                old: None,
+                ..changed_file("added.txt", 1, 0)
+            },
+            FileDiff {
+                new: None,
+                ..changed_file("deleted.txt", 0, 1)
+            },
+            changed_file("modified.txt", 1, 1),
+            FileDiff {
+                new: Some(("2".repeat(40), MODE_SYMLINK)),
+                ..changed_file("link", 1, 1)
+            },
+            FileDiff {
+                origin: Some(("from.txt".into(), 75, false)),
+                ..changed_file("renamed.txt", 1, 1)
+            },
+            FileDiff {
+                origin: Some(("from.txt".into(), 100, true)),
+                ..changed_file("copied.txt", 0, 0)
+            },
+        ];
+        assert_eq!(
+            format_name_status(&file_diffs, Path::new("")),
+            "A\tadded.txt\n\
+             D\tdeleted.txt\n\
+             M\tmodified.txt\n\
+             T\tlink\n\
+             R075\tfrom.txt\trenamed.txt\n\
+             C100\tfrom.txt\tcopied.txt\n"
+        );
+    }
+
+    #[test]
+    fn words_are_runs_of_non_whitespace() {
+        assert_eq!(split_words(b""), (vec![], &b""[..]));
+        assert_eq!(split_words(b" \n"), (vec![], &b" \n"[..]));
+        let (words, end) = split_words(b"  foo(bar);\tbaz\n");
+        assert_eq!(
+            words,
+            [(&b"  "[..], &b"foo(bar);"[..]), (&b"\t"[..], &b"baz"[..])]
+        );
+        assert_eq!(end, b"\n");
+    }
+
+    #[test]
+    fn word_diff_runs() {
+        let runs = |old: &str, new: &str| -> Vec<(char, String)> {
+            diff_words(old.as_bytes(), new.as_bytes())
+                .into_iter()
+                .map(|(kind, text)| (kind, String::from_utf8(text).unwrap()))
+                .collect()
+        };
+        let run = |kind, text: &str| (kind, text.to_owned());
+
+        assert_eq!(
+            runs("the quick fox\n", "the slow fox\n"),
+            [
+                run(' ', "the "),
+                run('-', "quick"),
+                run('+', "slow"),
+                run(' ', " fox\n")
+            ]
+        );
+        // Removed words keep the whitespace between them, and the new text's spacing is used
+        // elsewhere.
+        assert_eq!(
+            runs("a b c d\n", "a  d\n"),
+            [run(' ', "a "), run('-', "b c"), run(' ', "  d\n")]
+        );
+        assert_eq!(
+            runs("a d\n", "a b c d\n"),
+            [run(' ', "a "), run('+', "b c"), run(' ', " d\n")]
+        );
+        assert_eq!(runs("same\n", "same\n"), [run(' ', "same\n")]);
+    }
+
+    #[test]
+    fn word_diff_hunks() {
+        let old = b"fn main() {\n    let x = 1;\n}\n";
+        let new = b"fn main() {\n    let y = 1;\n}\n";
+        let hunks = diff_lines(old, new, &DiffOptions::default());
+        assert_eq!(
+            format_word_diff_hunk(&hunks[0], WordDiff::Plain, false),
+            "@@ -1,3 +1,3 @@\nfn main() {\n    let [-x-]{+y+} = 1;\n}\n"
+        );
+        assert_eq!(
+            format_word_diff_hunk(&hunks[0], WordDiff::Porcelain, false),
+            "@@ -1,3 +1,3 @@\n fn main() {\n~\n     let \n-x\n+y\n  = 1;\n~\n }\n~\n"
+        );
+        assert_eq!(
+            format_word_diff_hunk(&hunks[0], WordDiff::Color, true),
+            format!(
+                "{}@@ -1,3 +1,3 @@{}\nfn main() {{\n    let {}x{}{}y{} = 1;\n}}\n",
+                CYAN, RESET, RED, RESET, GREEN, RESET
+            )
+        );
+
+        // Changes spanning lines are marked on each line separately.
+        let hunks = diff_lines(b"one\ntwo\n", b"three\n", &DiffOptions::default());
+        assert_eq!(
+            format_word_diff_hunk(&hunks[0], WordDiff::Plain, false),
+            "@@ -1,2 +1 @@\n[-one-]\n[-two-]{+three+}\n"
+        );
+    }
+
+    #[test]
+    fn moved_lines_are_found_across_files() {
+        let line = |text: &str| text.as_bytes().to_vec();
+        let hunk = |lines| Hunk {
+            old_start: 1,
+            old_lines: 0,
+            new_start: 1,
+            new_lines: 0,
+            function: None,
+            lines,
+        };
+        let file_diffs = [
+            FileDiff {
+                content: ContentDiff::Text(vec![hunk(vec![
+                    DiffLine::Removed(line("moved\n")),
+                    DiffLine::Removed(line("only removed\n")),
+                    DiffLine::Context(line("context\n")),
+                ])]),
+                ..changed_file("a.txt", 0, 0)
+            },
+            FileDiff {
+                content: ContentDiff::Text(vec![hunk(vec![
+                    DiffLine::Added(line("moved\n")),
+                    DiffLine::Added(line("context\n")),
+                ])]),
+                ..changed_file("b.txt", 0, 0)
+            },
+        ];
+        let moved = find_moved_lines(&file_diffs);
+        assert_eq!(moved, HashSet::from([&b"moved\n"[..]]));
+    }
+
+    #[test]
+    fn moved_blocks_need_enough_alphanumeric_characters() {
+        let long = b"let total = compute_total();\n".to_vec();
+        let short = b"}\n".to_vec();
+        let moved = HashSet::from([long.as_slice(), short.as_slice()]);
+        let lines = [
+            DiffLine::Removed(long.clone()),
+            DiffLine::Removed(short.clone()),
+            DiffLine::Context(b"x\n".to_vec()),
+            DiffLine::Removed(short.clone()),
+            DiffLine::Added(long.clone()),
+            DiffLine::Added(b"not moved\n".to_vec()),
+        ];
+        // The first block has exactly 20 alphanumeric characters, while the lone brace has none.
+        assert_eq!(
+            find_moved_blocks(&lines, &moved),
+            [true, true, false, false, true, false]
+        );
+
+        let hunk = Hunk {
+            old_start: 1,
+            old_lines: 4,
+            new_start: 1,
+            new_lines: 3,
+            function: None,
+            lines: lines.to_vec(),
+        };
+        let text = format_hunk(&hunk, true, &moved);
+        let expected_lines = [
+            format!("{}-let total = compute_total();{}", MOVED_FROM, RESET),
+            format!("{}-}}{}", MOVED_FROM, RESET),
+            " x".to_owned(),
+            format!("{}-}}{}", RED, RESET),
+            format!("{}+let total = compute_total();{}", MOVED_TO, RESET),
+            format!("{}+not moved{}", GREEN, RESET),
+        ];
+        assert_eq!(text.lines().skip(1).collect::<Vec<_>>(), expected_lines);
+    }
 }
@@ -0,0 +1,184 @@
+use std::{fmt, io, path::PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong when operating on a ugit repository.
+#[derive(Debug)]
+pub enum Error {
+    /// The specified directory doesn't contain a ugit repository.
+    NotARepository(PathBuf),
+    /// There is no object with the specified OID in the object store.
+    ObjectNotFound(String),
+    /// The object with the specified OID isn't of the expected type.
+    TypeMismatch {
+        oid: String,
+        expected: String,
+        actual: String,
+    },
+    /// The object with the specified OID exists but couldn't be parsed.
+    CorruptObject {
+        oid: String,
+        reason: String,
+    },
+    /// The specified name doesn't resolve to a ref or an object.
+    BadRef(String),
+    /// A range of lines was requested that the specified file, with the specified number of lines,
+    /// doesn't contain.
+    InvalidLineRange {
+        path: String,
+        lines: usize,
+    },
+    /// Exactly one revision was needed, but none or several were given.
+    NeedSingleRevision,
+    /// Exactly one path was needed, but none or several were given.
+    NeedSinglePath,
+    /// The specified abbreviated OID is the prefix of more than one object.
+    AmbiguousOid(String),
+    /// The specified path doesn't exist in the tree of the specified revision.
+    PathNotInRevision {
+        path: String,
+        revision: String,
+    },
+    /// The index couldn't be parsed.
+    CorruptIndex(String),
+    /// The reflog of the specified ref couldn't be parsed.
+    CorruptReflog(String),
+    /// The record of the merge in progress couldn't be parsed, for the specified reason.
+    CorruptMergeState(String),
+    /// The reflog of the specified ref has fewer entries than were asked for.
+    ReflogTooShort {
+        reference: String,
+        entries: usize,
+    },
+    /// The specified pathspec didn't match any files.
+    PathspecNoMatch(PathBuf),
+    /// The specified path can't be stored in or restored from a tree or the index.
+    InvalidPath(PathBuf),
+    /// The specified commit shares no history with HEAD.
+    NoCommonAncestor(String),
+    /// A merge left conflicts in the specified files that need resolving before committing.
+    MergeConflict(Vec<PathBuf>),
+    /// Updating the working tree would overwrite uncommitted changes to the specified files.
+    WouldOverwrite(Vec<PathBuf>),
+    /// A merge was started before the one in progress was committed or aborted.
+    MergeInProgress,
+    /// A merge can't be continued or aborted because none is in progress.
+    NoMergeInProgress,
+    /// A commit was requested without a message, and there's no default one.
+    MissingCommitMessage,
+    /// No name or email is configured for the specified role, e.g. "author".
+    IdentityUnknown(String),
+    /// The specified date isn't of the form "<seconds since the epoch> <+/-hhmm>".
+    InvalidDate(String),
+    /// The remote refused to update the specified ref because it would lose commits.
+    PushRejected(String),
+    Io(io::Error),
+}
+
+impl Error {
+    pub(crate) fn corrupt_object(oid: &str, reason: &str) -> Error {
+        Error::CorruptObject {
+            oid: oid.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotARepository(path) => {
+                write!(f, "not a ugit repository: {}", path.display())
+            }
+            Error::ObjectNotFound(oid) => write!(f, "object {} not found", oid),
+            Error::TypeMismatch {
+                oid,
+                expected,
+                actual,
+            } => write!(f, "object {} is a {}, not a {}", oid, actual, expected),
+            Error::CorruptObject { oid, reason } => {
+                write!(f, "object {} is corrupt: {}", oid, reason)
+            }
+            Error::BadRef(name) => write!(f, "unknown revision or ref '{}'", name),
+            Error::InvalidLineRange { path, lines } => {
+                write!(f, "invalid line range: file {} has {} lines", path, lines)
+            }
+            Error::NeedSingleRevision => write!(f, "needed a single revision"),
+            Error::NeedSinglePath => write!(f, "needed a single path"),
+            Error::AmbiguousOid(prefix) => write!(f, "short object ID {} is ambiguous", prefix),
+            Error::PathNotInRevision { path, revision } => {
+                write!(f, "path '{}' does not exist in '{}'", path, revision)
+            }
+            Error::CorruptIndex(reason) => write!(f, "index is corrupt: {}", reason),
+            Error::CorruptReflog(reference) => write!(f, "reflog of '{}' is corrupt", reference),
+            Error::CorruptMergeState(reason) => write!(f, "merge state is corrupt: {}", reason),
+            Error::ReflogTooShort { reference, entries } => {
+                write!(f, "log for '{}' only has {} entries", reference, entries)
+            }
+            Error::PathspecNoMatch(path) => {
+                write!(f, "pathspec '{}' did not match any files", path.display())
+            }
+            Error::InvalidPath(path) => write!(f, "invalid path '{}'", path.display()),
+            Error::NoCommonAncestor(oid) => {
+                write!(f, "{} has no common ancestor with HEAD", oid)
+            }
+            Error::MergeConflict(paths) => {
+                writeln!(f, "merge conflict in:")?;
+                for path in paths {
+                    writeln!(f, "    {}", path.display())?;
+                }
+                write!(f, "fix conflicts and then commit the result")
+            }
+            Error::WouldOverwrite(paths) => {
+                writeln!(
+                    f,
+                    "your local changes to the following files would be overwritten:"
+                )?;
+                for path in paths {
+                    writeln!(f, "    {}", path.display())?;
+                }
+                write!(f, "commit your changes or use --force to discard them")
+            }
+            Error::MergeInProgress => {
+                write!(f, "you have not concluded your merge (MERGE_HEAD exists)")
+            }
+            Error::NoMergeInProgress => {
+                write!(f, "there is no merge in progress (MERGE_HEAD missing)")
+            }
+            Error::MissingCommitMessage => write!(f, "no commit message given; use -m <message>"),
+            Error::IdentityUnknown(role) => write!(
+                f,
+                "unable to determine {0} identity; set user.name and user.email in the config or \
+                 UGIT_{1}_NAME and UGIT_{1}_EMAIL",
+                role,
+                role.to_uppercase()
+            ),
+            Error::InvalidDate(date) => write!(f, "invalid date format: {}", date),
+            Error::PushRejected(ref_name) => {
+                write!(f, "push of {} would overwrite remote commits", ref_name)
+            }
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<walkdir::Error> for Error {
+    fn from(err: walkdir::Error) -> Error {
+        Error::Io(err.into())
+    }
+}
@@ -0,0 +1,422 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use super::{index, os_str_to_bytes, Repository, Result, DEFAULT_REPO};
+
+/// The name of the per-directory files listing paths to ignore.
+const IGNORE_FILE: &str = ".ugitignore";
+/// The repository-wide file listing paths to ignore, which isn't committed.
+const EXCLUDE_FILE: &str = "info/exclude";
+
+/// A single pattern from an ignore file.
+#[derive(Debug, Clone)]
+pub struct IgnoreRule {
+    /// The file the rule was read from, relative to the working tree.
+    pub source: PathBuf,
+    pub line_number: usize,
+    /// The rule as it was written in the file.
+    pub pattern: String,
+    /// Whether the rule re-includes paths excluded by an earlier rule.
+    pub negated: bool,
+    dir_only: bool,
+    anchored: bool,
+    glob: Vec<u8>,
+    /// The directory that the rule's paths are relative to.
+    base: PathBuf,
+}
+
+impl IgnoreRule {
+    /// Parse a line of an ignore file, returning None for blank lines and comments.
+    fn parse(line: &str, base: &Path, source: &Path, line_number: usize) -> Option<IgnoreRule> {
+        // Trailing spaces are ignored unless they're escaped.
+        let mut glob = line.trim_end_matches(['\r', '\n']);
+        while glob.ends_with(' ') && !glob.ends_with("\\ ") {
+            glob = &glob[..glob.len() - 1];
+        }
+        if glob.is_empty() || glob.starts_with('#') {
+            return None;
+        }
+
+        // A leading backslash escapes a "!" or "#" that's part of the pattern.
+        let negated = glob.starts_with('!');
+        if negated || glob.starts_with("\\!") || glob.starts_with("\\#") {
+            glob = &glob[1..];
+        }
+
+        let dir_only = glob.ends_with('/');
+        glob = glob.trim_end_matches('/');
+        // A slash anywhere but the end ties the pattern to the directory containing the ignore
+        // file, otherwise it can match at any depth.
+        let anchored = glob.contains('/');
+        glob = glob.trim_start_matches('/');
+        if glob.is_empty() {
+            return None;
+        }
+
+        Some(IgnoreRule {
+            source: source.to_owned(),
+            line_number,
+            pattern: line.trim_end().to_owned(),
+            negated,
+            dir_only,
+            anchored,
+            glob: glob.as_bytes().to_vec(),
+            base: base.to_owned(),
+        })
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let relative_path = match path.strip_prefix(&self.base) {
+            Ok(relative_path) => relative_path,
+            Err(_) => return false,
+        };
+        let subject = if self.anchored {
+            relative_path.as_os_str()
+        } else {
+            match relative_path.file_name() {
+                Some(name) => name,
+                None => return false,
+            }
+        };
+        os_str_to_bytes(subject).is_some_and(|subject| glob_match(&self.glob, subject))
+    }
+}
+
+/// Decides which paths in the working tree should be left out of the object store, according to
+/// the .ugitignore files in the working tree and the repository's info/exclude file. Paths that are
+/// already in the index are never ignored.
+pub struct Ignore<'a> {
+    repo: &'a Repository,
+    /// The paths in the index, along with the directories containing them.
+    tracked: HashSet<PathBuf>,
+    exclude_rules: Vec<IgnoreRule>,
+    /// The rules read from the .ugitignore file in each directory, loaded as they're needed.
+    directory_rules: HashMap<PathBuf, Vec<IgnoreRule>>,
+}
+
+impl<'a> Ignore<'a> {
+    pub fn new(repo: &'a Repository) -> Result<Ignore<'a>> {
+        let mut tracked = HashSet::new();
+        for (path, _) in index::read(repo)?.entries() {
+            for ancestor in Path::new(path).ancestors() {
+                tracked.insert(ancestor.to_owned());
+            }
+        }
+
+        let exclude_source = Path::new(DEFAULT_REPO).join(EXCLUDE_FILE);
+        let exclude_rules =
+            read_rules(&repo.git_path(EXCLUDE_FILE), Path::new(""), &exclude_source)?;
+
+        Ok(Ignore {
+            repo,
+            tracked,
+            exclude_rules,
+            directory_rules: HashMap::new(),
+        })
+    }
+
+    /// Whether the specified path, relative to the working tree, should be left out of the object
+    /// store.
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> Result<bool> {
+        if is_repository_path(self.repo, path) {
+            return Ok(true);
+        }
+        if self.tracked.contains(path) {
+            return Ok(false);
+        }
+        Ok(self
+            .matching_rule(path, is_dir)?
+            .is_some_and(|rule| !rule.negated))
+    }
+
+    /// Return the rule that decides whether the specified path, relative to the working tree, is
+    /// ignored. This is the last rule matching the path, unless one of its parent directories is
+    /// ignored, as nothing within an ignored directory can be re-included.
+    pub fn matching_rule(&mut self, path: &Path, is_dir: bool) -> Result<Option<IgnoreRule>> {
+        let ancestors: Vec<&Path> = path.ancestors().skip(1).collect();
+        for ancestor in ancestors.into_iter().rev().skip(1) {
+            if let Some(rule) = self.last_matching_rule(ancestor, true)? {
+                if !rule.negated {
+                    return Ok(Some(rule));
+                }
+            }
+        }
+        self.last_matching_rule(path, is_dir)
+    }
+
+    fn last_matching_rule(&mut self, path: &Path, is_dir: bool) -> Result<Option<IgnoreRule>> {
+        // Rules in deeper directories take precedence over shallower ones, and all of them over
+        // info/exclude.
+        let directories: Vec<PathBuf> = path.ancestors().skip(1).map(Path::to_path_buf).collect();
+        let mut result = self
+            .exclude_rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(path, is_dir))
+            .cloned();
+        for directory in directories.into_iter().rev() {
+            let rules = self.directory_rules(&directory)?;
+            if let Some(rule) = rules.iter().rev().find(|rule| rule.matches(path, is_dir)) {
+                result = Some(rule.clone());
+            }
+        }
+        Ok(result)
+    }
+
+    fn directory_rules(&mut self, directory: &Path) -> Result<&[IgnoreRule]> {
+        if !self.directory_rules.contains_key(directory) {
+            let source = directory.join(IGNORE_FILE);
+            let rules = read_rules(&self.repo.work_tree_path(&source), directory, &source)?;
+            self.directory_rules.insert(directory.to_owned(), rules);
+        }
+        Ok(&self.directory_rules[directory])
+    }
+}
+
+/// Whether the specified path, relative to the working tree, is part of the repository's own data
+/// rather than its contents.
+pub fn is_repository_path(repo: &Repository, path: &Path) -> bool {
+    path.components()
+        .any(|c| c == Component::Normal(DEFAULT_REPO.as_ref()))
+        || repo.work_tree_path(path).starts_with(&repo.git_dir)
+}
+
+fn read_rules(path: &Path, base: &Path, source: &Path) -> Result<Vec<IgnoreRule>> {
+    if !path.is_file() {
+        return Ok(vec![]);
+    }
+    let contents = fs::read(path)?;
+    Ok(String::from_utf8_lossy(&contents)
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| IgnoreRule::parse(line, base, source, i + 1))
+        .collect())
+}
+
+/// Match a path against a gitignore-style glob, in which "*" and "?" don't match slashes and "**"
+/// between slashes matches any number of directories.
+fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => {
+            let rest = &rest[1..];
+            match rest.split_first() {
+                // A trailing "**" matches everything.
+                None => true,
+                // "**/" matches zero or more leading directories.
+                Some((b'/', after)) => {
+                    glob_match(after, text)
+                        || text
+                            .iter()
+                            .enumerate()
+                            .any(|(i, c)| *c == b'/' && glob_match(after, &text[i + 1..]))
+                }
+                // Otherwise it's no different to a single "*".
+                Some(_) => glob_match(&glob[1..], text),
+            }
+        }
+        Some((b'*', rest)) => {
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some((b'?', rest)) => match text.split_first() {
+            Some((c, text)) if *c != b'/' => glob_match(rest, text),
+            _ => false,
+        },
+        Some((b'[', rest)) => match (text.split_first(), match_class(rest)) {
+            (Some((c, text)), Some((matches, rest))) if *c != b'/' => {
+                matches(*c) && glob_match(rest, text)
+            }
+            // An unterminated class is matched literally.
+            (Some((b'[', text)), None) => glob_match(rest, text),
+            _ => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Parse a character class such as "[a-z]" or "[!0-9]", starting just after the "[". Returns a
+/// predicate for the class and the rest of the glob.
+fn match_class(glob: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, &[u8])> {
+    let (negated, body_start) = match glob.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    // A "]" straight after the opening bracket is part of the class.
+    let end = glob
+        .iter()
+        .skip(body_start + 1)
+        .position(|c| *c == b']')
+        .map(|i| i + body_start + 1)?;
+    let body = &glob[body_start..end];
+
+    let matches = move |c: u8| {
+        let mut i = 0;
+        let mut found = false;
+        while i < body.len() {
+            if i + 2 < body.len() && body[i + 1] == b'-' {
+                found |= body[i] <= c && c <= body[i + 2];
+                i += 3;
+            } else {
+                found |= body[i] == c;
+                i += 1;
+            }
+        }
+        found != negated
+    };
+    Some((matches, &glob[end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check whether each path would be ignored by rules read from the specified directory's
+    /// .ugitignore, given as (patterns, directory, path, is_dir, expected).
+    fn check(cases: &[(&[&str], &str, &str, bool, bool)]) {
+        let repo = Repository::new(Path::new("/nonexistent/.ugit"), Path::new("/nonexistent"));
+        for (patterns, directory, path, is_dir, expected) in cases {
+            let directory = Path::new(directory);
+            let source = directory.join(IGNORE_FILE);
+            let rules = patterns
+                .iter()
+                .enumerate()
+                .filter_map(|(i, line)| IgnoreRule::parse(line, directory, &source, i + 1))
+                .collect();
+            let mut ignore = Ignore {
+                repo: &repo,
+                tracked: HashSet::new(),
+                exclude_rules: vec![],
+                directory_rules: HashMap::from([(directory.to_owned(), rules)]),
+            };
+            let ignored = ignore.is_ignored(Path::new(path), *is_dir).unwrap();
+            assert_eq!(
+                ignored, *expected,
+                "{:?} in {:?} for {:?}",
+                patterns, directory, path
+            );
+        }
+    }
+
+    #[test]
+    fn plain_patterns() {
+        check(&[
+            (&["*.o"], "", "foo.o", false, true),
+            (&["*.o"], "", "src/foo.o", false, true),
+            (&["*.o"], "", "foo.c", false, false),
+            (&["frotz"], "", "a/b/frotz", false, true),
+            (&["?.txt"], "", "a.txt", false, true),
+            (&["?.txt"], "", "ab.txt", false, false),
+            (&["# comment"], "", "# comment", false, false),
+            (&["\\#notes"], "", "#notes", false, true),
+            (&["foo  "], "", "foo", false, true),
+            (&["foo\\ "], "", "foo ", false, true),
+            (&["foo\\ "], "", "foo", false, false),
+        ]);
+    }
+
+    #[test]
+    fn negation() {
+        check(&[
+            (&["*.log", "!important.log"], "", "debug.log", false, true),
+            (
+                &["*.log", "!important.log"],
+                "",
+                "important.log",
+                false,
+                false,
+            ),
+            (
+                &["!important.log", "*.log"],
+                "",
+                "important.log",
+                false,
+                true,
+            ),
+            (&["\\!important"], "", "!important", false, true),
+            // Nothing inside an ignored directory can be re-included.
+            (&["build/", "!build/keep"], "", "build/keep", false, true),
+            (&["/*", "!/src"], "", "src/main.rs", false, false),
+            (&["/*", "!/src"], "", "README", false, true),
+        ]);
+    }
+
+    #[test]
+    fn directory_only_patterns() {
+        check(&[
+            (&["build/"], "", "build", true, true),
+            (&["build/"], "", "build", false, false),
+            (&["build/"], "", "src/build", true, true),
+            (&["build/"], "", "build/out.o", false, true),
+            (&["foo/*"], "", "foo/bar", true, true),
+            (&["foo/*"], "", "foo/test.json", false, true),
+            (&["foo/*"], "", "foo", true, false),
+        ]);
+    }
+
+    #[test]
+    fn anchoring() {
+        check(&[
+            (&["/TODO"], "", "TODO", false, true),
+            (&["/TODO"], "", "src/TODO", false, false),
+            (&["doc/frotz"], "", "doc/frotz", false, true),
+            (&["doc/frotz"], "", "a/doc/frotz", false, false),
+            (&["/*.html"], "docs", "docs/index.html", false, true),
+            (&["/*.html"], "docs", "docs/api/index.html", false, false),
+            (&["/*.html"], "docs", "index.html", false, false),
+            (&["*.html"], "docs", "docs/api/index.html", false, true),
+        ]);
+    }
+
+    #[test]
+    fn double_asterisks() {
+        check(&[
+            (&["**/foo"], "", "foo", false, true),
+            (&["**/foo"], "", "a/b/foo", false, true),
+            (&["**/foo/bar"], "", "foo/bar", false, true),
+            (&["**/foo/bar"], "", "x/foo/bar", false, true),
+            (&["abc/**"], "", "abc/x", false, true),
+            (&["abc/**"], "", "abc/x/y", false, true),
+            (&["abc/**"], "", "abc", true, false),
+            (&["a/**/b"], "", "a/b", false, true),
+            (&["a/**/b"], "", "a/x/b", false, true),
+            (&["a/**/b"], "", "a/x/y/b", false, true),
+            (&["a/**/b"], "", "a/xb", false, false),
+            (&["a/*/b"], "", "a/x/y/b", false, false),
+        ]);
+    }
+
+    #[test]
+    fn character_classes() {
+        check(&[
+            (&["*.[oa]"], "", "lib.a", false, true),
+            (&["*.[oa]"], "", "main.o", false, true),
+            (&["*.[oa]"], "", "main.c", false, false),
+            (&["file[0-9].txt"], "", "file5.txt", false, true),
+            (&["file[0-9].txt"], "", "filex.txt", false, false),
+            (&["[!a-c]*"], "", "d", false, true),
+            (&["[!a-c]*"], "", "b", false, false),
+            (&["[^a-c]*"], "", "b", false, false),
+            (&["[]]x"], "", "]x", false, true),
+            (&["a[/]b"], "", "a/b", false, false),
+            // An unterminated class is matched literally.
+            (&["[abc"], "", "[abc", false, true),
+        ]);
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+    ffi::OsString,
+    fs,
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use sha1::{Digest, Sha1};
+use tempfile::NamedTempFile;
+
+use super::{
+    bytes_to_os_string, from_hex, os_str_to_bytes, to_hex, Error, Repository, Result, Tree,
+    MODE_EXECUTABLE, MODE_FILE, MODE_SYMLINK,
+};
+
+/// Identifies the file as a ugit index.
+const INDEX_SIGNATURE: &[u8; 4] = b"UIDX";
+/// Version 2 added inode numbers to entries. Version 1 indexes are still read.
+const INDEX_VERSION: u32 = 2;
+const INDEX_FILE: &str = "index";
+
+/// The staged state of a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub oid: String,
+    pub size: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    pub inode: u64,
+    pub mode: u32,
+}
+
+impl IndexEntry {
+    /// Create an entry for the blob with the specified OID, recording the size, modification time,
+    /// inode and mode of the file it was read from.
+    pub fn new(oid: &str, metadata: &fs::Metadata) -> IndexEntry {
+        let (mtime_secs, mtime_nanos) = get_mtime(metadata);
+        IndexEntry {
+            oid: oid.to_owned(),
+            size: metadata.len(),
+            mtime_secs,
+            mtime_nanos,
+            inode: get_inode(metadata),
+            mode: get_mode(metadata),
+        }
+    }
+
+    /// Create an entry for a blob taken from a tree rather than a file, which doesn't match any
+    /// file's metadata, so the file will be hashed the next time it's checked for changes.
+    pub fn from_tree(oid: &str, mode: u32) -> IndexEntry {
+        IndexEntry {
+            oid: oid.to_owned(),
+            size: 0,
+            mtime_secs: 0,
+            mtime_nanos: 0,
+            inode: 0,
+            mode,
+        }
+    }
+
+    /// Whether the file the entry was created from appears to be unchanged, judging by its
+    /// metadata.
+    fn matches(&self, metadata: &fs::Metadata) -> bool {
+        (self.mtime_secs, self.mtime_nanos) == get_mtime(metadata)
+            && self.size == metadata.len()
+            && self.inode == get_inode(metadata)
+            && self.mode == get_mode(metadata)
+    }
+}
+
+fn get_mtime(metadata: &fs::Metadata) -> (u64, u32) {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    (mtime.as_secs(), mtime.subsec_nanos())
+}
+
+#[cfg(unix)]
+fn get_inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn get_inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+/// Return the tree entry mode of a file with the specified metadata, which must have been read
+/// without following symlinks.
+pub fn get_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.file_type().is_symlink() {
+        MODE_SYMLINK
+    } else if is_executable(metadata) {
+        MODE_EXECUTABLE
+    } else {
+        MODE_FILE
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// The staging area, mapping each path that will be included in the next commit to the blob that
+/// will be stored for it.
+#[derive(Debug, Default)]
+pub struct Index {
+    entries: BTreeMap<OsString, IndexEntry>,
+    /// When the index was last written, if it was read from disk.
+    mtime: Option<(u64, u32)>,
+}
+
+impl Index {
+    pub fn insert(&mut self, path: &Path, entry: IndexEntry) {
+        self.entries.insert(path.as_os_str().to_owned(), entry);
+    }
+
+    pub fn remove(&mut self, path: &Path) -> Option<IndexEntry> {
+        self.entries.remove(path.as_os_str())
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&IndexEntry> {
+        self.entries.get(path.as_os_str())
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&OsString, &IndexEntry)> {
+        self.entries.iter()
+    }
+
+    /// Return the paths staged beneath the specified path, including the path itself if it's a
+    /// file.
+    pub fn paths_under(&self, path: &Path) -> Vec<PathBuf> {
+        self.entries
+            .keys()
+            .map(PathBuf::from)
+            .filter(|p| path == Path::new("") || p.starts_with(path))
+            .collect()
+    }
+
+    /// Return the staged OID of the specified file if its metadata shows that it hasn't changed
+    /// since it was staged, so that it needn't be read and hashed again.
+    pub fn get_unchanged_oid(&self, path: &Path, metadata: &fs::Metadata) -> Option<&str> {
+        let entry = self.entries.get(path.as_os_str())?;
+        // A file modified in the same instant that the index was written may have changed without
+        // its metadata showing it, so its contents can't be trusted.
+        let racy = self
+            .mtime
+            .is_none_or(|mtime| (entry.mtime_secs, entry.mtime_nanos) >= mtime);
+        if racy || !entry.matches(metadata) {
+            return None;
+        }
+        Some(&entry.oid)
+    }
+
+    /// Return the flattened list of OIDs, paths and modes that the index contains.
+    pub fn to_tree(&self) -> Tree {
+        self.entries
+            .iter()
+            .map(|(path, entry)| (entry.oid.clone(), path.clone(), entry.mode))
+            .collect()
+    }
+}
+
+/// Read the index from the specified repository, returning an empty index if none has been
+/// written yet.
+pub fn read(repo: &Repository) -> Result<Index> {
+    let mut index = Index::default();
+
+    let path = repo.git_path(INDEX_FILE);
+    if !path.is_file() {
+        return Ok(index);
+    }
+
+    let contents = fs::read(&path)?;
+    index.mtime = Some(get_mtime(&fs::metadata(&path)?));
+    if contents.len() < 32 {
+        return Err(Error::CorruptIndex("file is truncated".to_owned()));
+    }
+    let (body, checksum) = contents.split_at(contents.len() - 20);
+    if Sha1::digest(body).as_slice() != checksum {
+        return Err(Error::CorruptIndex("checksum mismatch".to_owned()));
+    }
+    if &body[0..4] != INDEX_SIGNATURE {
+        return Err(Error::CorruptIndex("invalid signature".to_owned()));
+    }
+    let version = read_u32(body, 4)?;
+    if version != 1 && version != INDEX_VERSION {
+        return Err(Error::CorruptIndex("unsupported version".to_owned()));
+    }
+
+    let entry_count = read_u32(body, 8)?;
+    let mut offset = 12;
+    for _ in 0..entry_count {
+        let mtime_secs = read_u64(body, offset)?;
+        let mtime_nanos = read_u32(body, offset + 8)?;
+        let size = read_u64(body, offset + 12)?;
+        offset += 20;
+        let inode = if version >= 2 {
+            offset += 8;
+            read_u64(body, offset - 8)?
+        } else {
+            0
+        };
+        let mode = read_u32(body, offset)?;
+        let oid = to_hex(read_bytes(body, offset + 4, 20)?);
+        let path_len = u16::from_be_bytes(read_bytes(body, offset + 24, 2)?.try_into().unwrap());
+        offset += 26;
+        let path = bytes_to_os_string(read_bytes(body, offset, path_len as usize)?)
+            .ok_or_else(|| Error::CorruptIndex("path can't be represented".to_owned()))?;
+        offset += path_len as usize;
+
+        index.entries.insert(
+            path,
+            IndexEntry {
+                oid,
+                size,
+                mtime_secs,
+                mtime_nanos,
+                inode,
+                mode,
+            },
+        );
+    }
+
+    Ok(index)
+}
+
+/// Write the index to the specified repository, replacing the existing one.
+pub fn write(repo: &Repository, index: &Index) -> Result<()> {
+    let mut body: Vec<u8> = vec![];
+    body.extend_from_slice(INDEX_SIGNATURE);
+    body.extend_from_slice(&INDEX_VERSION.to_be_bytes());
+    body.extend_from_slice(&(index.entries.len() as u32).to_be_bytes());
+
+    for (path_name, entry) in &index.entries {
+        let path = os_str_to_bytes(path_name)
+            .ok_or_else(|| Error::InvalidPath(PathBuf::from(path_name)))?;
+        body.extend_from_slice(&entry.mtime_secs.to_be_bytes());
+        body.extend_from_slice(&entry.mtime_nanos.to_be_bytes());
+        body.extend_from_slice(&entry.size.to_be_bytes());
+        body.extend_from_slice(&entry.inode.to_be_bytes());
+        body.extend_from_slice(&entry.mode.to_be_bytes());
+        body.extend_from_slice(&from_hex(&entry.oid)?);
+        // The length is stored in 16 bits, so longer paths can't be written without corrupting it.
+        let path_len =
+            u16::try_from(path.len()).map_err(|_| Error::InvalidPath(PathBuf::from(path_name)))?;
+        body.extend_from_slice(&path_len.to_be_bytes());
+        body.extend_from_slice(path);
+    }
+
+    let checksum = Sha1::digest(&body);
+    body.extend_from_slice(checksum.as_slice());
+
+    // Write to a temporary file first so that a failed write can't leave a corrupt index behind.
+    let mut file = NamedTempFile::new_in(&repo.git_dir)?;
+    file.write_all(&body)?;
+    file.persist(repo.git_path(INDEX_FILE))
+        .map_err(|err| Error::Io(err.error))?;
+
+    Ok(())
+}
+
+fn read_bytes(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    bytes
+        .get(offset..offset + len)
+        .ok_or_else(|| Error::CorruptIndex("entry is truncated".to_owned()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_be_bytes(
+        read_bytes(bytes, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_be_bytes(
+        read_bytes(bytes, offset, 8)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ugit::testing::init_repo;
+    use std::time::{Duration, SystemTime};
+
+    fn entry(oid: &str, size: u64, inode: u64, mode: u32) -> IndexEntry {
+        IndexEntry {
+            oid: oid.to_owned(),
+            size,
+            mtime_secs: 1700000000,
+            mtime_nanos: 123456789,
+            inode,
+            mode,
+        }
+    }
+
+    /// Write a file to the working tree with the specified modification time and return its
+    /// metadata.
+    fn write_file(
+        repo: &Repository,
+        path: &str,
+        contents: &str,
+        mtime: SystemTime,
+    ) -> fs::Metadata {
+        let path = repo.work_tree_path(path);
+        fs::write(&path, contents).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        fs::symlink_metadata(path).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_dir, repo) = init_repo();
+        let mut index = Index::default();
+        index.insert(Path::new("a.txt"), entry(&"a".repeat(40), 6, 42, MODE_FILE));
+        index.insert(
+            Path::new("bin/run"),
+            entry(&"b".repeat(40), 0, 7, MODE_EXECUTABLE),
+        );
+        index.insert(
+            Path::new("link"),
+            entry(&"c".repeat(40), 3, 9, MODE_SYMLINK),
+        );
+        write(&repo, &index).unwrap();
+
+        let read_back = read(&repo).unwrap();
+        assert_eq!(read_back.entries, index.entries);
+        assert!(read_back.mtime.is_some());
+        // The temporary file the index was written to is gone.
+        let leftovers = fs::read_dir(&repo.git_dir)
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_string_lossy().starts_with(".tmp")
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let (_dir, repo) = init_repo();
+        let mut body = vec![];
+        body.extend_from_slice(INDEX_SIGNATURE);
+        body.extend_from_slice(&1u32.to_be_bytes());
+        body.extend_from_slice(&1u32.to_be_bytes());
+        body.extend_from_slice(&1700000000u64.to_be_bytes());
+        body.extend_from_slice(&123456789u32.to_be_bytes());
+        body.extend_from_slice(&6u64.to_be_bytes());
+        body.extend_from_slice(&MODE_FILE.to_be_bytes());
+        body.extend_from_slice(&[0xaa; 20]);
+        body.extend_from_slice(&5u16.to_be_bytes());
+        body.extend_from_slice(b"a.txt");
+        let checksum = Sha1::digest(&body);
+        body.extend_from_slice(checksum.as_slice());
+        fs::write(repo.git_path(INDEX_FILE), &body).unwrap();
+
+        let index = read(&repo).unwrap();
+        let entries: Vec<_> = index.entries().collect();
+        assert_eq!(
+            entries,
+            [(
+                &OsString::from("a.txt"),
+                &entry(&"a".repeat(40), 6, 0, MODE_FILE)
+            )]
+        );
+
+        // Corruption anywhere is caught by the checksum.
+        body[20] ^= 1;
+        fs::write(repo.git_path(INDEX_FILE), &body).unwrap();
+        assert!(matches!(read(&repo), Err(Error::CorruptIndex(_))));
+        fs::write(repo.git_path(INDEX_FILE), &body[..20]).unwrap();
+        assert!(matches!(read(&repo), Err(Error::CorruptIndex(_))));
+    }
+
+    #[test]
+    fn refuses_paths_too_long_to_store() {
+        let (_dir, repo) = init_repo();
+        let mut index = Index::default();
+        let path = "a".repeat(usize::from(u16::MAX) + 1);
+        index.insert(Path::new(&path), entry(&"a".repeat(40), 0, 0, MODE_FILE));
+        assert!(matches!(write(&repo, &index), Err(Error::InvalidPath(_))));
+    }
+
+    #[test]
+    fn unchanged_files_are_trusted_unless_racy() {
+        let (_dir, repo) = init_repo();
+        let oid = "a".repeat(40);
+        let past = SystemTime::now() - Duration::from_secs(3600);
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let old_file = write_file(&repo, "old.txt", "old\n", past);
+        let racy_file = write_file(&repo, "racy.txt", "racy\n", future);
+
+        let mut index = Index::default();
+        index.insert(Path::new("old.txt"), IndexEntry::new(&oid, &old_file));
+        index.insert(Path::new("racy.txt"), IndexEntry::new(&oid, &racy_file));
+        // Until the index has been written, nothing can be trusted.
+        assert_eq!(
+            index.get_unchanged_oid(Path::new("old.txt"), &old_file),
+            None
+        );
+        write(&repo, &index).unwrap();
+
+        // A file modified no earlier than the index was written may have changed unnoticed.
+        let index = read(&repo).unwrap();
+        assert_eq!(
+            index.get_unchanged_oid(Path::new("old.txt"), &old_file),
+            Some(oid.as_str())
+        );
+        assert_eq!(
+            index.get_unchanged_oid(Path::new("racy.txt"), &racy_file),
+            None
+        );
+
+        let changed_file = write_file(&repo, "old.txt", "changed\n", past);
+        assert_eq!(
+            index.get_unchanged_oid(Path::new("old.txt"), &changed_file),
+            None
+        );
+        assert_eq!(
+            index.get_unchanged_oid(Path::new("missing"), &old_file),
+            None
+        );
+    }
+}
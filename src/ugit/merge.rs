@@ -0,0 +1,349 @@
+use super::diff::{common_affixes, find_changed_lines, intern_lines, split_lines, DiffAlgorithm};
+
+/// How conflicts are written out when a merge can't be resolved automatically.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictStyle {
+    /// Show our and their versions of the conflicting lines.
+    Merge,
+    /// Also show the base version of the conflicting lines.
+    Diff3,
+    /// Like diff3, but lines that both sides agree on are moved out of the conflict.
+    Zdiff3,
+}
+
+/// Which side to take when both sides changed the same lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeFavor {
+    Ours,
+    Theirs,
+    /// Keep both sides' lines, ours first.
+    Union,
+}
+
+/// Options controlling how files are merged.
+#[derive(Debug, Clone, Copy)]
+pub struct MergeOptions {
+    pub style: ConflictStyle,
+    /// How to resolve conflicting changes, or None to leave them as conflicts.
+    pub favor: Option<MergeFavor>,
+}
+
+impl Default for MergeOptions {
+    fn default() -> MergeOptions {
+        MergeOptions {
+            style: ConflictStyle::Merge,
+            favor: None,
+        }
+    }
+}
+
+/// A region of conflicting changes, holding each version's lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub base: Vec<u8>,
+    pub ours: Vec<u8>,
+    pub theirs: Vec<u8>,
+}
+
+/// A section of the result of a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeChunk {
+    /// Lines that were unchanged, or changed in a way that could be merged automatically.
+    Resolved(Vec<u8>),
+    Conflict(Conflict),
+}
+
+/// The result of merging a file, as a sequence of resolved lines and conflicts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeResult {
+    pub chunks: Vec<MergeChunk>,
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts().next().is_none()
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &Conflict> {
+        self.chunks.iter().filter_map(|chunk| match chunk {
+            MergeChunk::Conflict(conflict) => Some(conflict),
+            MergeChunk::Resolved(_) => None,
+        })
+    }
+
+    fn push_resolved(&mut self, lines: &[&[u8]]) {
+        if lines.is_empty() {
+            return;
+        }
+        if let Some(MergeChunk::Resolved(data)) = self.chunks.last_mut() {
+            data.extend(lines.concat());
+        } else {
+            self.chunks.push(MergeChunk::Resolved(lines.concat()));
+        }
+    }
+}
+
+/// Merge the changes made to `base` in `ours` and in `theirs`, line by line. Changes to
+/// different lines are combined, while changes to the same lines are conflicts unless they're
+/// identical or `options.favor` says which to keep.
+pub fn merge_lines(base: &[u8], ours: &[u8], theirs: &[u8], options: &MergeOptions) -> MergeResult {
+    let base_lines = split_lines(base);
+    let our_lines = split_lines(ours);
+    let their_lines = split_lines(theirs);
+    let ids = intern_lines(&[&base_lines, &our_lines, &their_lines]);
+    let our_matches = match_lines(&ids[0], &ids[1]);
+    let their_matches = match_lines(&ids[0], &ids[2]);
+
+    let mut result = MergeResult { chunks: vec![] };
+    // The start of the current unstable region in the base, ours and theirs.
+    let (mut b, mut o, mut t) = (0, 0, 0);
+    loop {
+        // Find the next base line that both sides left untouched.
+        let stable = (b..base_lines.len())
+            .find_map(|i| Some((i, our_matches[i]?, their_matches[i]?)))
+            .unwrap_or((base_lines.len(), our_lines.len(), their_lines.len()));
+
+        let base_region = &base_lines[b..stable.0];
+        let our_region = &our_lines[o..stable.1];
+        let their_region = &their_lines[t..stable.2];
+        if our_region == base_region || our_region == their_region {
+            result.push_resolved(their_region);
+        } else if their_region == base_region {
+            result.push_resolved(our_region);
+        } else {
+            match options.favor {
+                Some(MergeFavor::Ours) => result.push_resolved(our_region),
+                Some(MergeFavor::Theirs) => result.push_resolved(their_region),
+                Some(MergeFavor::Union) => {
+                    let (prefix, suffix) = common_affixes(our_region, their_region);
+                    result.push_resolved(&our_region[..our_region.len() - suffix]);
+                    result.push_resolved(&their_region[prefix..]);
+                }
+                None => result.chunks.push(MergeChunk::Conflict(Conflict {
+                    base: base_region.concat(),
+                    ours: our_region.concat(),
+                    theirs: their_region.concat(),
+                })),
+            }
+        }
+
+        if stable.0 == base_lines.len() {
+            break;
+        }
+        result.push_resolved(&base_lines[stable.0..stable.0 + 1]);
+        b = stable.0 + 1;
+        o = stable.1 + 1;
+        t = stable.2 + 1;
+    }
+
+    result
+}
+
+/// Write out the result of a merge, surrounding each conflict with markers naming the versions.
+/// `labels` names our, the base and their versions.
+pub fn format_merge(result: &MergeResult, style: ConflictStyle, labels: [&str; 3]) -> Vec<u8> {
+    let mut output = vec![];
+    for chunk in &result.chunks {
+        let conflict = match chunk {
+            MergeChunk::Resolved(data) => {
+                output.extend_from_slice(data);
+                continue;
+            }
+            MergeChunk::Conflict(conflict) => conflict,
+        };
+
+        let ours = split_lines(&conflict.ours);
+        let theirs = split_lines(&conflict.theirs);
+        // Only diff3 shows the lines that both sides agree on inside the conflict.
+        let (prefix, suffix) = match style {
+            ConflictStyle::Diff3 => (0, 0),
+            ConflictStyle::Merge | ConflictStyle::Zdiff3 => common_affixes(&ours, &theirs),
+        };
+        output.extend(ours[..prefix].concat());
+
+        let mut section = |marker: &str, label: &str, lines: &[&[u8]]| {
+            output.extend_from_slice(marker.as_bytes());
+            if !label.is_empty() {
+                output.push(b' ');
+                output.extend_from_slice(label.as_bytes());
+            }
+            output.push(b'\n');
+            output.extend(lines.concat());
+            // A conflict at the end of a file that lacks a final newline still needs its markers
+            // on separate lines.
+            if lines.last().is_some_and(|line| !line.ends_with(b"\n")) {
+                output.push(b'\n');
+            }
+        };
+        section("<<<<<<<", labels[0], &ours[prefix..ours.len() - suffix]);
+        if style != ConflictStyle::Merge {
+            section("|||||||", labels[1], &split_lines(&conflict.base));
+        }
+        section("=======", "", &theirs[prefix..theirs.len() - suffix]);
+        output.extend_from_slice(format!(">>>>>>> {}\n", labels[2]).as_bytes());
+
+        output.extend(ours[ours.len() - suffix..].concat());
+    }
+    output
+}
+
+/// Whether the specified data contains the markers that surround an unresolved conflict.
+pub fn has_conflict_markers(data: &[u8]) -> bool {
+    let has_marker = |marker: &[u8]| {
+        split_lines(data).iter().any(|line| {
+            line.strip_prefix(marker)
+                .is_some_and(|rest| matches!(rest.first(), None | Some(b' ' | b'\n' | b'\r')))
+        })
+    };
+    has_marker(b"<<<<<<<") && has_marker(b"=======") && has_marker(b">>>>>>>")
+}
+
+/// For each line of `base`, find the line of `other` it was kept as, if it wasn't changed.
+fn match_lines(base: &[usize], other: &[usize]) -> Vec<Option<usize>> {
+    let (removed, added) = find_changed_lines(base, other, DiffAlgorithm::Myers);
+    let mut unchanged = (0..other.len()).filter(|i| !added[*i]);
+    removed
+        .iter()
+        .map(|removed| if *removed { None } else { unchanged.next() })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LABELS: [&str; 3] = ["HEAD", "BASE", "MERGE_HEAD"];
+
+    const BASE: &[u8] = b"a\nb\nc\n";
+    const OURS: &[u8] = b"a\nx\ny\nc\n";
+    const THEIRS: &[u8] = b"a\nx\nz\nc\n";
+
+    fn merge(base: &[u8], ours: &[u8], theirs: &[u8], options: MergeOptions) -> Vec<u8> {
+        let result = merge_lines(base, ours, theirs, &options);
+        format_merge(&result, options.style, LABELS)
+    }
+
+    fn merge_with_style(style: ConflictStyle) -> Vec<u8> {
+        let options = MergeOptions { style, favor: None };
+        merge(BASE, OURS, THEIRS, options)
+    }
+
+    fn merge_favoring(favor: MergeFavor) -> Vec<u8> {
+        let options = MergeOptions {
+            favor: Some(favor),
+            ..MergeOptions::default()
+        };
+        merge(BASE, OURS, THEIRS, options)
+    }
+
+    #[test]
+    fn changes_to_different_lines_merge_cleanly() {
+        let result = merge_lines(
+            b"a\nb\nc\nd\ne\n",
+            b"A\nb\nc\nd\ne\n",
+            b"a\nb\nc\nd\nE\n",
+            &MergeOptions::default(),
+        );
+        assert!(result.is_clean());
+        let output = format_merge(&result, ConflictStyle::Merge, LABELS);
+        assert_eq!(output, b"A\nb\nc\nd\nE\n");
+    }
+
+    #[test]
+    fn identical_changes_merge_cleanly() {
+        let result = merge_lines(BASE, OURS, OURS, &MergeOptions::default());
+        assert!(result.is_clean());
+        assert_eq!(format_merge(&result, ConflictStyle::Merge, LABELS), OURS);
+    }
+
+    #[test]
+    fn deleting_lines_merges_with_changes_elsewhere() {
+        let output = merge(
+            b"a\nb\nc\nd\n",
+            b"a\nc\nd\n",
+            b"a\nb\nc\nD\n",
+            MergeOptions::default(),
+        );
+        assert_eq!(output, b"a\nc\nD\n");
+    }
+
+    #[test]
+    fn deleting_changed_lines_conflicts() {
+        let result = merge_lines(
+            b"a\nb\nc\n",
+            b"a\nc\n",
+            b"a\nB\nc\n",
+            &MergeOptions::default(),
+        );
+        let conflicts: Vec<_> = result.conflicts().collect();
+        assert_eq!(
+            conflicts,
+            [&Conflict {
+                base: b"b\n".to_vec(),
+                ours: vec![],
+                theirs: b"B\n".to_vec(),
+            }]
+        );
+        let output = format_merge(&result, ConflictStyle::Merge, LABELS);
+        assert_eq!(
+            output,
+            b"a\n<<<<<<< HEAD\n=======\nB\n>>>>>>> MERGE_HEAD\nc\n"
+        );
+    }
+
+    #[test]
+    fn overlapping_changes_conflict() {
+        let result = merge_lines(BASE, OURS, THEIRS, &MergeOptions::default());
+        assert!(!result.is_clean());
+        assert_eq!(result.conflicts().count(), 1);
+    }
+
+    #[test]
+    fn merge_style_leaves_out_the_base() {
+        assert_eq!(
+            merge_with_style(ConflictStyle::Merge),
+            b"a\nx\n<<<<<<< HEAD\ny\n=======\nz\n>>>>>>> MERGE_HEAD\nc\n"
+        );
+    }
+
+    #[test]
+    fn diff3_style_shows_the_whole_conflict() {
+        assert_eq!(
+            merge_with_style(ConflictStyle::Diff3),
+            b"a\n<<<<<<< HEAD\nx\ny\n||||||| BASE\nb\n=======\nx\nz\n>>>>>>> MERGE_HEAD\nc\n"
+        );
+    }
+
+    #[test]
+    fn zdiff3_style_moves_common_lines_out() {
+        assert_eq!(
+            merge_with_style(ConflictStyle::Zdiff3),
+            b"a\nx\n<<<<<<< HEAD\ny\n||||||| BASE\nb\n=======\nz\n>>>>>>> MERGE_HEAD\nc\n"
+        );
+    }
+
+    #[test]
+    fn conflict_without_trailing_newline() {
+        let output = merge(b"a\nb", b"a\nc", b"a\nd", MergeOptions::default());
+        assert_eq!(
+            output,
+            b"a\n<<<<<<< HEAD\nc\n=======\nd\n>>>>>>> MERGE_HEAD\n"
+        );
+    }
+
+    #[test]
+    fn favoring_a_side_resolves_conflicts() {
+        assert_eq!(merge_favoring(MergeFavor::Ours), OURS);
+        assert_eq!(merge_favoring(MergeFavor::Theirs), THEIRS);
+        assert_eq!(merge_favoring(MergeFavor::Union), b"a\nx\ny\nz\nc\n");
+    }
+
+    #[test]
+    fn finds_conflict_markers() {
+        assert!(has_conflict_markers(&merge_with_style(
+            ConflictStyle::Merge
+        )));
+        assert!(!has_conflict_markers(OURS));
+        assert!(!has_conflict_markers(b"<<<<<<<<\n========\n>>>>>>>>\n"));
+    }
+}
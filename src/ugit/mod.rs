@@ -1,7 +1,24 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
 pub mod base;
+pub mod blame;
+pub mod config;
 pub mod data;
 pub mod diff;
+mod error;
+pub mod ignore;
+pub mod index;
+pub mod merge;
 pub mod remote;
+mod repository;
+#[cfg(test)]
+mod testing;
+
+pub use error::{Error, Result};
+pub use repository::Repository;
 
 pub const DEFAULT_REPO: &str = ".ugit";
 
@@ -9,7 +26,91 @@ pub const DEFAULT_REPO: &str = ".ugit";
 pub struct Commit {
     pub tree: String,
     pub parents: Vec<String>,
+    /// Commits made before ugit recorded authorship have no author or committer.
+    pub author: Option<Signature>,
+    pub committer: Option<Signature>,
+    /// Headers this version of ugit doesn't understand, kept so they survive being read and
+    /// written back.
+    pub extra_headers: Vec<(String, String)>,
     pub message: String,
 }
 
-type Tree = Vec<(String, std::ffi::OsString)>;
+/// Who authored or committed a commit, and when.
+#[derive(Debug, Clone)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the Unix epoch.
+    pub time: i64,
+    /// Offset from UTC in minutes.
+    pub offset: i32,
+}
+
+/// A flattened tree, listing the OID, path and mode of each file it contains.
+pub type Tree = Vec<(String, OsString, u32)>;
+
+/// The mode of a subtree entry within a tree.
+pub const MODE_TREE: u32 = 0o040000;
+/// The mode of a regular file within a tree.
+pub const MODE_FILE: u32 = 0o100644;
+/// The mode of an executable file within a tree.
+pub const MODE_EXECUTABLE: u32 = 0o100755;
+/// The mode of a symlink within a tree, whose blob holds the link's target.
+pub const MODE_SYMLINK: u32 = 0o120000;
+
+/// Return the raw bytes of a path, as stored in trees and the index.
+#[cfg(unix)]
+pub(crate) fn os_str_to_bytes(s: &OsStr) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_str_to_bytes(s: &OsStr) -> Option<&[u8]> {
+    s.to_str().map(str::as_bytes)
+}
+
+/// Convert the raw bytes of a path, as stored in trees and the index, back into a path.
+#[cfg(unix)]
+pub(crate) fn bytes_to_os_string(bytes: &[u8]) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes.to_vec()))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn bytes_to_os_string(bytes: &[u8]) -> Option<OsString> {
+    std::str::from_utf8(bytes).ok().map(OsString::from)
+}
+
+/// Convert a path relative to the root of the working tree to one relative to the directory at
+/// `prefix` within the working tree.
+pub fn relative_to_prefix(prefix: &Path, path: &Path) -> PathBuf {
+    let mut prefix_components = prefix.components().peekable();
+    let mut path_components = path.components().peekable();
+    while prefix_components.peek().is_some() && prefix_components.peek() == path_components.peek() {
+        prefix_components.next();
+        path_components.next();
+    }
+
+    let mut result = PathBuf::new();
+    for _ in prefix_components {
+        result.push("..");
+    }
+    result.extend(path_components);
+    result
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| Error::BadRef(hex.to_owned()))
+        })
+        .collect()
+}
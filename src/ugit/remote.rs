@@ -1,94 +1,90 @@
-use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
-};
+use std::collections::HashMap;
 
-use super::{base, data, DEFAULT_REPO};
+use super::{base, data, Error, Repository, Result};
 
 const REMOTE_REFS_BASE: &str = "refs/heads/";
 const LOCAL_REFS_BASE: &str = "refs/remote/";
 
-pub fn fetch(remote_path: &Path) {
+pub fn fetch(repo: &Repository, remote: &Repository) -> Result<()> {
     // Get refs from server.
-    let refs = get_remote_refs(remote_path, Some(REMOTE_REFS_BASE));
-    let commit_oids = refs
-        .values()
-        .into_iter()
-        .filter_map(|v| v.as_deref())
-        .collect();
+    let refs = get_remote_refs(remote, Some(REMOTE_REFS_BASE))?;
+    let commit_oids = refs.values().filter_map(|v| v.as_deref()).collect();
 
     // Fetch missing objects by iterating and fetching on demand.
-    for oid in base::get_objects_in_commits(remote_path, commit_oids) {
-        data::fetch_object_if_missing(remote_path, &oid);
+    for oid in base::get_objects_in_commits(remote, commit_oids)? {
+        data::fetch_object_if_missing(repo, remote, &oid)?;
     }
 
     // Update local refs to match server.
     for (remote_name, value) in refs {
         let mut refname = String::from(LOCAL_REFS_BASE);
         refname.push_str(&remote_name.replacen(REMOTE_REFS_BASE, "", 1));
-        data::update_ref(
-            &PathBuf::from(DEFAULT_REPO),
-            &refname,
-            &data::RefValue {
-                symbolic: false,
-                value,
-            },
-            true,
-        )
+        let value = value.ok_or_else(|| Error::BadRef(remote_name.clone()))?;
+        let old = data::get_ref(repo, &refname, true)?.value;
+        let reason = match &old {
+            Some(old) if *old == value => continue,
+            Some(old) if base::is_ancestor_of(repo, &value, old)? => "fast-forward",
+            Some(_) => "forced-update",
+            None => "storing head",
+        };
+        base::update_ref_logged(repo, &refname, &value, &format!("fetch: {}", reason))?;
     }
-}
 
-pub fn push(remote_path: &Path, ref_name: &str) {
-    let default_repo = &PathBuf::from(DEFAULT_REPO);
+    Ok(())
+}
 
+pub fn push(repo: &Repository, remote: &Repository, ref_name: &str) -> Result<()> {
     // Get refs data.
-    let remote_refs = get_remote_refs(remote_path, None);
+    let remote_refs = get_remote_refs(remote, None)?;
     let remote_ref = remote_refs
         .get(ref_name)
-        .expect("Ref doesn't exist in remote repo");
-    let local_ref = data::get_ref(default_repo, ref_name, true)
+        .ok_or_else(|| Error::BadRef(ref_name.to_owned()))?;
+    let local_ref = data::get_ref(repo, ref_name, true)?
         .value
-        .expect("Ref has no OID");
+        .ok_or_else(|| Error::BadRef(ref_name.to_owned()))?;
 
     // Don't allow force push.
-    assert!(
-        remote_ref.is_none()
-            || base::is_ancestor_of(default_repo, &local_ref, &remote_ref.clone().unwrap()),
-        "Push would overwrite remote commits"
-    );
+    if let Some(remote_ref) = remote_ref {
+        if !base::is_ancestor_of(repo, &local_ref, remote_ref)? {
+            return Err(Error::PushRejected(ref_name.to_owned()));
+        }
+    }
 
     // Compute which objects the server doesn't have.
     let known_remote_refs: Vec<&str> = remote_refs
         .values()
         .flatten()
-        .filter(|oid| data::object_exists(default_repo, oid))
+        .filter(|oid| data::object_exists(repo, oid))
         .map(AsRef::as_ref)
         .collect();
-    let remote_objects = base::get_objects_in_commits(remote_path, known_remote_refs);
-    let local_objects = base::get_objects_in_commits(default_repo, vec![&local_ref]);
+    let remote_objects = base::get_objects_in_commits(remote, known_remote_refs)?;
+    let local_objects = base::get_objects_in_commits(repo, vec![&local_ref])?;
     let objects_to_push = local_objects.difference(&remote_objects);
 
     // Push all objects.
     for oid in objects_to_push {
-        data::push_object(remote_path, &oid);
+        data::push_object(repo, remote, oid)?;
     }
 
     // Update server ref to our value.
     data::update_ref(
-        remote_path,
+        remote,
         ref_name,
         &data::RefValue {
             symbolic: false,
             value: Some(local_ref),
         },
         true,
-    );
+    )
 }
 
-fn get_remote_refs(remote_path: &Path, prefix: Option<&str>) -> HashMap<String, Option<String>> {
+fn get_remote_refs(
+    remote: &Repository,
+    prefix: Option<&str>,
+) -> Result<HashMap<String, Option<String>>> {
     let mut result = HashMap::new();
-    for (refname, reference) in data::get_refs(remote_path, prefix, true) {
+    for (refname, reference) in data::get_refs(remote, prefix, true)? {
         result.insert(refname, reference.value);
     }
-    result
+    Ok(result)
 }
@@ -0,0 +1,100 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use super::{Error, Result, DEFAULT_REPO};
+
+/// A ugit repository, made up of the directory that ugit stores its data in and the working tree
+/// whose contents it tracks.
+#[derive(Debug, Clone)]
+pub struct Repository {
+    pub git_dir: PathBuf,
+    pub work_tree: PathBuf,
+}
+
+impl Repository {
+    /// Return a handle to a repository with the specified data directory and working tree, without
+    /// checking whether either exists.
+    pub fn new(git_dir: &Path, work_tree: &Path) -> Repository {
+        Repository {
+            git_dir: git_dir.to_owned(),
+            work_tree: work_tree.to_owned(),
+        }
+    }
+
+    /// Open the repository whose working tree is the specified directory.
+    pub fn open(work_tree: &Path) -> Result<Repository> {
+        let repo = Repository::new(&work_tree.join(DEFAULT_REPO), work_tree);
+        if !repo.git_dir.is_dir() {
+            return Err(Error::NotARepository(work_tree.to_owned()));
+        }
+        Ok(repo)
+    }
+
+    /// Find the repository containing the specified directory by searching it and each of its
+    /// ancestors for a ugit directory. The search stops rather than cross onto another filesystem.
+    pub fn discover(start: &Path) -> Result<Repository> {
+        let start = fs::canonicalize(start)?;
+        let device = get_device_id(&start)?;
+
+        let mut dir = start.as_path();
+        loop {
+            let git_dir = dir.join(DEFAULT_REPO);
+            if git_dir.is_dir() {
+                return Ok(Repository::new(&git_dir, dir));
+            }
+
+            match dir.parent() {
+                Some(parent) if get_device_id(parent)? == device => dir = parent,
+                _ => return Err(Error::NotARepository(start)),
+            }
+        }
+    }
+
+    /// Return the path of a file within the repository's data directory.
+    pub fn git_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.git_dir.join(path)
+    }
+
+    /// Return the absolute path of a file within the working tree.
+    pub fn work_tree_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.work_tree.join(path)
+    }
+
+    /// Convert an absolute path within the working tree to one relative to the root of the working
+    /// tree, resolving any "." and ".." components along the way.
+    pub fn work_tree_relative_path(&self, path: &Path) -> Result<PathBuf> {
+        resolve_dots(path)
+            .strip_prefix(&self.work_tree)
+            .map(Path::to_path_buf)
+            .map_err(|_| Error::InvalidPath(path.to_owned()))
+    }
+}
+
+/// Lexically remove "." components from a path and resolve ".." components against the preceding
+/// component.
+fn resolve_dots(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
+#[cfg(unix)]
+fn get_device_id(path: &Path) -> Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(path)?.dev())
+}
+
+#[cfg(not(unix))]
+fn get_device_id(_path: &Path) -> Result<u64> {
+    Ok(0)
+}
@@ -0,0 +1,90 @@
+//! Helpers for tests that need a repository on disk.
+
+use std::{fs, path::Path};
+
+use tempfile::TempDir;
+
+use super::{base, data, index, Commit, Repository, Signature, DEFAULT_REPO, MODE_FILE};
+
+/// Create an empty repository in a new temporary directory, with an identity configured so that
+/// commits can be made. The directory is deleted when the returned handle is dropped.
+pub fn init_repo() -> (TempDir, Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let work_tree = fs::canonicalize(dir.path()).unwrap();
+    let repo = base::init(&work_tree.join(DEFAULT_REPO), &work_tree).unwrap();
+    fs::write(
+        repo.git_path("config"),
+        "[user]\n\tname = A U Thor\n\temail = author@example.com\n",
+    )
+    .unwrap();
+    (dir, repo)
+}
+
+/// Write files to the working tree, creating any directories they need.
+pub fn write_files(repo: &Repository, files: &[(&str, &str)]) {
+    for (path, contents) in files {
+        let path = repo.work_tree_path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+}
+
+/// Return the contents of a file in the working tree, or None if it doesn't exist.
+pub fn read_file(repo: &Repository, path: &str) -> Option<String> {
+    fs::read_to_string(repo.work_tree_path(path)).ok()
+}
+
+/// Write files to the working tree, stage them and commit, returning the new commit's OID.
+pub fn commit_files(repo: &Repository, files: &[(&str, &str)], message: &str) -> String {
+    write_files(repo, files);
+    let paths: Vec<_> = files
+        .iter()
+        .map(|(path, _)| Path::new(path).into())
+        .collect();
+    base::add(repo, &paths).unwrap();
+    base::commit(repo, message).unwrap()
+}
+
+/// Store a commit with a tree holding just the specified files, without touching the index or the
+/// working tree. Its author and committer both made it at `time`.
+pub fn make_commit(
+    repo: &Repository,
+    files: &[(&str, &str)],
+    parents: &[&str],
+    time: i64,
+) -> String {
+    make_commit_with_times(repo, files, parents, time, time)
+}
+
+/// Like `make_commit`, but with different times for the author and the committer.
+pub fn make_commit_with_times(
+    repo: &Repository,
+    files: &[(&str, &str)],
+    parents: &[&str],
+    author_time: i64,
+    committer_time: i64,
+) -> String {
+    let mut tree = index::Index::default();
+    for (path, contents) in files {
+        let oid = data::hash_object(repo, contents.as_bytes(), "blob").unwrap();
+        tree.insert(
+            Path::new(path),
+            index::IndexEntry::from_tree(&oid, MODE_FILE),
+        );
+    }
+    let signature = |time| Signature {
+        name: "A U Thor".to_owned(),
+        email: "author@example.com".to_owned(),
+        time,
+        offset: 0,
+    };
+    let commit = Commit {
+        tree: base::write_tree_from_index(repo, &tree).unwrap(),
+        parents: parents.iter().map(|parent| parent.to_string()).collect(),
+        author: Some(signature(author_time)),
+        committer: Some(signature(committer_time)),
+        extra_headers: vec![],
+        message: "message\n".to_owned(),
+    };
+    base::write_commit(repo, &commit).unwrap()
+}